@@ -1,39 +1,339 @@
+use std::path::PathBuf;
+
 use clap::{Parser, Subcommand};
 
 #[derive(Parser, Debug)]
 pub struct Args {
+    #[arg(
+        long,
+        value_name = "path",
+        help = "set the path to the repository, overriding GIT_DIR"
+    )]
+    pub git_dir: Option<PathBuf>,
     #[command(subcommand)]
     pub command: Commands,
 }
 
 #[derive(Subcommand, Debug)]
 pub enum Commands {
-    Init,
+    Init {
+        #[arg(long, help = "create a bare repository")]
+        bare: bool,
+        #[arg(
+            short = 'b',
+            long,
+            default_value = "master",
+            help = "use the specified name for the initial branch"
+        )]
+        initial_branch: String,
+    },
     CatFile {
         #[arg(short, help = "pretty-print <object> content")]
         pretty_print: bool,
-        #[arg()]
-        obj_sha: String,
+        #[arg(
+            short,
+            help = "show object type identified by <object>",
+            conflicts_with = "pretty_print"
+        )]
+        type_only: bool,
+        #[arg(
+            short,
+            help = "show object size identified by <object>",
+            conflicts_with_all = ["pretty_print", "type_only"]
+        )]
+        size_only: bool,
+        #[arg(
+            short,
+            help = "check if <object> exists",
+            conflicts_with_all = ["pretty_print", "type_only", "size_only"]
+        )]
+        exists: bool,
+        #[arg(
+            long,
+            help = "allow -s and -t to work with objects of unknown type",
+            conflicts_with_all = ["pretty_print", "exists"]
+        )]
+        allow_unknown_type: bool,
+        #[arg(long, help = "check <object> still hashes to its name before using it")]
+        strict: bool,
+        #[arg(long, help = "with -p, don't dump binary blobs to a terminal")]
+        safe: bool,
+        #[arg(
+            long,
+            help = "dump blobs as they are, even with --safe",
+            overrides_with = "safe"
+        )]
+        raw: bool,
+        #[arg(
+            long,
+            group = "batch_mode",
+            help = "print type, size and content of each object named on stdin",
+            conflicts_with_all = ["pretty_print", "type_only", "size_only", "exists", "obj_sha"]
+        )]
+        batch: bool,
+        #[arg(
+            long,
+            group = "batch_mode",
+            help = "print type and size of each object named on stdin",
+            conflicts_with_all = ["pretty_print", "type_only", "size_only", "exists", "batch", "obj_sha"]
+        )]
+        batch_check: bool,
+        #[arg(
+            long,
+            requires = "batch_mode",
+            help = "with --batch or --batch-check, go through every object instead of reading stdin"
+        )]
+        batch_all_objects: bool,
+        #[arg(required_unless_present_any = ["batch", "batch_check"])]
+        obj_sha: Option<String>,
     },
     HashObject {
         #[arg(short, help = "write the object into the object database")]
         write: bool,
-        #[arg()]
-        file: String,
+        #[arg(
+            short = 't',
+            long = "type",
+            default_value = "blob",
+            help = "type of object to create"
+        )]
+        obj_type: String,
+        #[arg(long, conflicts_with = "files", help = "read the object from stdin")]
+        stdin: bool,
+        #[arg(
+            long,
+            conflicts_with_all = ["stdin", "files"],
+            help = "read the names of files to hash from stdin, one per line"
+        )]
+        stdin_paths: bool,
+        #[arg(
+            long,
+            help = "store the content as is, even with a type git doesn't know about"
+        )]
+        literally: bool,
+        #[arg(
+            long,
+            requires = "write",
+            help = "write the object even if it's already in the database, to repair a corrupt one"
+        )]
+        force: bool,
+        #[arg(
+            value_name = "file",
+            required_unless_present_any = ["stdin", "stdin_paths"]
+        )]
+        files: Vec<String>,
     },
     LsTree {
-        #[arg(long, help = "list only filenames")]
+        #[arg(long, visible_alias = "name-status", help = "list only filenames")]
         name_only: bool,
+        #[arg(
+            long,
+            conflicts_with_all = ["name_only", "long", "format"],
+            help = "list only object names"
+        )]
+        object_only: bool,
+        #[arg(short, help = "recurse into sub-trees")]
+        recursive: bool,
+        #[arg(short, long, help = "include object size of blob (file) entries")]
+        long: bool,
+        #[arg(short, help = "show only tree entries")]
+        dirs_only: bool,
+        #[arg(short = 'z', help = "terminate entries with NUL instead of newline")]
+        null_terminate: bool,
+        #[arg(
+            long,
+            value_name = "n",
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "7",
+            help = "use <n> digits to display object names"
+        )]
+        abbrev: Option<usize>,
+        #[arg(
+            long,
+            help = "show each entry by filling in %(objectmode), %(objecttype), %(objectname), \
+                    %(objectsize), %(objectsize:padded) and %(path)",
+            conflicts_with_all = ["name_only", "long"]
+        )]
+        format: Option<String>,
         #[arg(value_name = "tree-ish")]
         tree_ish: String,
     },
-    WriteTree,
+    DiffTree {
+        #[arg(short, help = "recurse into sub-trees")]
+        recursive: bool,
+        #[arg(value_name = "tree-ish")]
+        old: String,
+        #[arg(value_name = "tree-ish")]
+        new: String,
+    },
+    FsckObject {
+        #[arg()]
+        obj_sha: String,
+    },
+    CountObjects {
+        #[arg(short, long, help = "also report packs and stray files")]
+        verbose: bool,
+    },
+    UpdateIndex {
+        #[arg(long, help = "add the file to the index if it's not already there")]
+        add: bool,
+        #[arg()]
+        file: String,
+    },
+    LsFiles {
+        #[arg(
+            short,
+            long,
+            help = "show staged contents' mode, object name and stage number"
+        )]
+        stage: bool,
+    },
+    WriteTree {
+        #[arg(
+            long,
+            help = "build the tree from the working directory instead of the index"
+        )]
+        from_worktree: bool,
+        #[arg(long, help = "write the tree for just this subdirectory")]
+        prefix: Option<String>,
+        #[arg(long, help = "only work out the tree's hash, writing no objects")]
+        dry_run: bool,
+        #[arg(
+            long,
+            conflicts_with = "dry_run",
+            help = "read the written tree back and check it hashes to its name"
+        )]
+        verify: bool,
+        #[arg(long, help = "report progress on stderr even when it isn't a terminal")]
+        progress: bool,
+    },
     CommitTree {
         #[arg()]
         tree_sha: String,
-        #[arg(short)]
-        parent_sha: String,
+        #[arg(short, help = "id of a parent commit object, may be repeated")]
+        parent_sha: Vec<String>,
         #[arg(short)]
         message: String,
     },
+    RevParse {
+        #[arg(long, help = "fail unless <rev> names exactly one existing object")]
+        verify: bool,
+        #[arg(
+            long,
+            value_name = "n",
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "7",
+            help = "show the shortest unique abbreviation of at least <n> digits, implies --verify"
+        )]
+        short: Option<usize>,
+        #[arg(
+            long,
+            help = "show the short name of the ref <rev> names, following symbolic refs"
+        )]
+        abbrev_ref: bool,
+        #[arg()]
+        rev: String,
+    },
+    UpdateRef {
+        #[arg(short, help = "delete the ref instead of updating it")]
+        delete: bool,
+        #[arg(
+            short,
+            value_name = "reason",
+            help = "note why the ref moved in its reflog"
+        )]
+        message: Option<String>,
+        #[arg()]
+        ref_name: String,
+        #[arg(required_unless_present = "delete", conflicts_with = "delete")]
+        new_value: Option<String>,
+    },
+    ShowRef {
+        #[arg(long, help = "only show branches")]
+        heads: bool,
+        #[arg(long, help = "only show tags")]
+        tags: bool,
+    },
+    SymbolicRef {
+        #[arg()]
+        name: String,
+        #[arg()]
+        value: Option<String>,
+    },
+    IndexPack {
+        #[arg(
+            long,
+            help = "add delta bases missing from a thin pack from the repository's objects"
+        )]
+        fix_thin: bool,
+        #[arg()]
+        pack: String,
+    },
+    UnpackObjects {
+        #[arg()]
+        pack: String,
+    },
+    Clone {
+        #[arg(
+            long,
+            help = "fetch only the last <depth> commits of the default branch"
+        )]
+        depth: Option<u32>,
+        #[arg(long, help = "report progress on stderr even when it isn't a terminal")]
+        progress: bool,
+        #[arg()]
+        url: String,
+        #[arg()]
+        dir: Option<String>,
+    },
+    Status,
+    Checkout {
+        #[arg(short, long, help = "throw away local changes to tracked files")]
+        force: bool,
+        #[arg(value_name = "branch")]
+        commit: String,
+    },
+    Commit {
+        #[arg(short, help = "use the given message as the commit message")]
+        message: String,
+    },
+    Reflog {
+        #[arg(default_value = "HEAD", value_name = "ref")]
+        refname: String,
+    },
+    Log {
+        #[arg(short = 'n', help = "limit the number of commits to show")]
+        max_count: Option<usize>,
+    },
+    Show {
+        #[arg(default_value = "HEAD")]
+        object: String,
+    },
+    Branch {
+        #[arg(short, help = "delete the named branch")]
+        delete: bool,
+        #[arg(value_name = "branchname")]
+        name: Option<String>,
+    },
+    MergeBase {
+        #[arg(value_name = "commit")]
+        a: String,
+        #[arg(value_name = "commit")]
+        b: String,
+    },
+    Tag {
+        #[arg(short, help = "make an annotated tag object", requires = "tag_name")]
+        annotate: bool,
+        #[arg(
+            short,
+            help = "use the given tag message, implies -a",
+            requires = "tag_name"
+        )]
+        message: Option<String>,
+        #[arg(help = "the tag to create, listing every tag without one")]
+        tag_name: Option<String>,
+        #[arg(default_value = "HEAD")]
+        object: String,
+    },
 }
@@ -0,0 +1,278 @@
+use std::iter::Peekable;
+use std::path::Path;
+use std::str::Chars;
+
+use anyhow::{bail, ensure, Context, Result};
+
+#[derive(Clone)]
+struct Entry {
+    // lowercased since git treats section and key names case-insensitively, unlike subsections
+    section: String,
+    subsection: Option<String>,
+    key: String,
+    // a key on its own, without `= value`, has no value at all and reads as boolean true
+    value: Option<String>,
+}
+
+// settings from a git config file, in the order they appear
+#[derive(Clone, Default)]
+pub(crate) struct Config {
+    entries: Vec<Entry>,
+}
+
+impl Config {
+    // a missing config file is the same as an empty one
+    pub(crate) fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.is_file() {
+            return Ok(Config::default());
+        }
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("reading {}", path.to_string_lossy()))?;
+        let mut parser = Parser {
+            chars: contents.chars().peekable(),
+            line: 1,
+        };
+        let entries = parser.parse().with_context(|| {
+            format!(
+                "bad config line {} in file {}",
+                parser.line,
+                path.to_string_lossy()
+            )
+        })?;
+        Ok(Config { entries })
+    }
+
+    // ~/.gitconfig, for settings shared by all of a user's repos
+    pub(crate) fn global() -> Result<Self> {
+        match std::env::var_os("HOME") {
+            Some(home) => Config::read(Path::new(&home).join(".gitconfig")),
+            None => Ok(Config::default()),
+        }
+    }
+
+    // settings from `other` take precedence, as a repo's own config does over the global one
+    pub(crate) fn extend(&mut self, other: Config) {
+        self.entries.extend(other.entries);
+    }
+
+    // `section` may name a subsection too, as in `remote.origin`; like git, the last value set
+    // wins
+    pub(crate) fn get<T: FromConfig>(&self, section: &str, key: &str) -> Result<Option<T>> {
+        let (section, subsection) = match section.split_once('.') {
+            Some((section, subsection)) => (section, Some(subsection)),
+            None => (section, None),
+        };
+        let entry = self.entries.iter().rev().find(|ent| {
+            ent.section.eq_ignore_ascii_case(section)
+                && ent.subsection.as_deref() == subsection
+                && ent.key.eq_ignore_ascii_case(key)
+        });
+        match entry {
+            Some(ent) => {
+                let name = match subsection {
+                    Some(subsection) => format!("{}.{}.{}", ent.section, subsection, ent.key),
+                    None => format!("{}.{}", ent.section, ent.key),
+                };
+                T::from_config(ent.value.as_deref(), &name).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+// how a config value is interpreted depends on who's asking for it
+pub(crate) trait FromConfig: Sized {
+    fn from_config(value: Option<&str>, name: &str) -> Result<Self>;
+}
+
+impl FromConfig for String {
+    fn from_config(value: Option<&str>, name: &str) -> Result<Self> {
+        value
+            .map(String::from)
+            .with_context(|| format!("missing value for '{}'", name))
+    }
+}
+
+impl FromConfig for bool {
+    fn from_config(value: Option<&str>, name: &str) -> Result<Self> {
+        let value = match value {
+            Some(value) => value,
+            None => return Ok(true),
+        };
+        match value.to_ascii_lowercase().as_str() {
+            "true" | "yes" | "on" => Ok(true),
+            "false" | "no" | "off" | "" => Ok(false),
+            _ => match parse_int(value) {
+                Ok(n) => Ok(n != 0),
+                Err(_) => bail!("bad boolean config value '{}' for '{}'", value, name),
+            },
+        }
+    }
+}
+
+impl FromConfig for i64 {
+    fn from_config(value: Option<&str>, name: &str) -> Result<Self> {
+        let value = value.with_context(|| format!("missing value for '{}'", name))?;
+        parse_int(value).map_err(|e| {
+            anyhow::anyhow!("bad numeric config value '{}' for '{}': {}", value, name, e)
+        })
+    }
+}
+
+// integers may end in k, m or g to scale them by 1024, 1024^2 or 1024^3
+fn parse_int(value: &str) -> std::result::Result<i64, &'static str> {
+    let (digits, scale) = match value.chars().last().map(|c| c.to_ascii_lowercase()) {
+        Some('k') => (&value[..value.len() - 1], 1 << 10),
+        Some('m') => (&value[..value.len() - 1], 1 << 20),
+        Some('g') => (&value[..value.len() - 1], 1 << 30),
+        _ => (value, 1),
+    };
+    let n: i64 = digits
+        .parse()
+        .map_err(|e: std::num::ParseIntError| match e.kind() {
+            std::num::IntErrorKind::PosOverflow | std::num::IntErrorKind::NegOverflow => {
+                "out of range"
+            }
+            _ => "invalid unit",
+        })?;
+    n.checked_mul(scale).ok_or("out of range")
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    // where we're at, for reporting errors
+    line: usize,
+}
+
+impl Parser<'_> {
+    fn parse(&mut self) -> Result<Vec<Entry>> {
+        let mut entries = vec![];
+        let mut section: Option<(String, Option<String>)> = None;
+        while let Some(c) = self.chars.next() {
+            match c {
+                '\n' => self.line += 1,
+                c if c.is_whitespace() => {}
+                '#' | ';' => self.skip_comment(),
+                '[' => section = Some(self.parse_header()?),
+                c if c.is_ascii_alphabetic() => {
+                    let (section, subsection) = section.clone().context("key outside a section")?;
+                    let mut key = c.to_ascii_lowercase().to_string();
+                    while let Some(c) = self
+                        .chars
+                        .next_if(|c| c.is_ascii_alphanumeric() || *c == '-')
+                    {
+                        key.push(c.to_ascii_lowercase());
+                    }
+                    self.skip_blanks();
+                    let value = match self.chars.peek() {
+                        Some('=') => {
+                            self.chars.next();
+                            Some(self.parse_value()?)
+                        }
+                        None | Some('\n') | Some('#') | Some(';') => None,
+                        Some(_) => bail!("bad key"),
+                    };
+                    entries.push(Entry {
+                        section,
+                        subsection,
+                        key,
+                        value,
+                    });
+                }
+                _ => bail!("unexpected character"),
+            }
+        }
+        Ok(entries)
+    }
+
+    // `[section]`, `[section "subsection"]` or the older `[section.subsection]`, whose
+    // subsection is lowercased like the rest
+    fn parse_header(&mut self) -> Result<(String, Option<String>)> {
+        let mut section = String::new();
+        while let Some(c) = self
+            .chars
+            .next_if(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '.')
+        {
+            section.push(c.to_ascii_lowercase());
+        }
+        ensure!(!section.is_empty(), "empty section name");
+
+        match self.chars.next() {
+            Some(']') => match section.split_once('.') {
+                Some((section, subsection)) => {
+                    Ok((section.to_string(), Some(subsection.to_string())))
+                }
+                None => Ok((section, None)),
+            },
+            Some(' ') | Some('\t') => {
+                self.skip_blanks();
+                ensure!(self.chars.next() == Some('"'), "bad section header");
+                let mut subsection = String::new();
+                loop {
+                    match self.chars.next() {
+                        Some('"') => break,
+                        Some('\\') => subsection.push(self.chars.next().context("bad escape")?),
+                        Some('\n') | None => bail!("unterminated subsection"),
+                        Some(c) => subsection.push(c),
+                    }
+                }
+                ensure!(self.chars.next() == Some(']'), "bad section header");
+                Ok((section, Some(subsection)))
+            }
+            _ => bail!("bad section header"),
+        }
+    }
+
+    // everything after the `=` up to the end of the line, with quotes and escapes undone and
+    // unquoted whitespace at either end dropped
+    fn parse_value(&mut self) -> Result<String> {
+        self.skip_blanks();
+        let mut value = String::new();
+        let mut kept_len = 0;
+        let mut quoted = false;
+        loop {
+            match self.chars.peek() {
+                None | Some('\n') => break,
+                Some('#') | Some(';') if !quoted => {
+                    self.skip_comment();
+                    break;
+                }
+                _ => {}
+            }
+            match self.chars.next().unwrap() {
+                '"' => quoted = !quoted,
+                '\\' => match self.chars.next() {
+                    // a backslash at the end of a line continues the value onto the next
+                    Some('\n') => {
+                        self.line += 1;
+                        continue;
+                    }
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some('b') => value.push('\x08'),
+                    Some(c @ '\\') | Some(c @ '"') => value.push(c),
+                    _ => bail!("bad escape in value"),
+                },
+                c if c.is_whitespace() && !quoted => {
+                    value.push(c);
+                    continue;
+                }
+                c => value.push(c),
+            }
+            kept_len = value.len();
+        }
+        ensure!(!quoted, "unterminated quote in value");
+        value.truncate(kept_len);
+        Ok(value)
+    }
+
+    fn skip_blanks(&mut self) {
+        while self.chars.next_if(|c| *c == ' ' || *c == '\t').is_some() {}
+    }
+
+    // leaves the newline ending the comment for the caller
+    fn skip_comment(&mut self) {
+        while self.chars.next_if(|c| *c != '\n').is_some() {}
+    }
+}
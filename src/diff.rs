@@ -0,0 +1,1098 @@
+use std::collections::HashMap;
+use std::ops::{Index, IndexMut, Range};
+
+// unchanged lines shown either side of a change, as git does by default
+const CONTEXT: usize = 3;
+
+// git only looks this far into a line it's showing in a hunk header
+const FUNC_LINE_LEN: usize = 80;
+
+// xdiff's limits on how hard it looks for the shortest diff before settling for a good one: a run
+// of matches this long is a good sign, and from this many edits on it'll take one
+const SNAKE_LEN: isize = 20;
+const HEUR_MIN_COST: isize = 256;
+const MAX_COST_MIN: isize = 256;
+const K_HEUR: isize = 4;
+
+// and on which lines are worth searching at all
+const MAX_EQ_LIMIT: usize = 1024;
+const SIMSCAN_WINDOW: usize = 100;
+const KPDIS_RUN: usize = 4;
+
+// git's indent heuristic, for where to put a group of changes that could go in several places
+const MAX_SLIDING: usize = 100;
+const MAX_INDENT: isize = 200;
+const MAX_BLANKS: isize = 20;
+const START_OF_FILE_PENALTY: isize = 1;
+const END_OF_FILE_PENALTY: isize = 21;
+const TOTAL_BLANK_WEIGHT: isize = -30;
+const POST_BLANK_WEIGHT: isize = 6;
+const RELATIVE_INDENT_PENALTY: isize = -4;
+const RELATIVE_INDENT_WITH_BLANK_PENALTY: isize = 10;
+const RELATIVE_OUTDENT_PENALTY: isize = 24;
+const RELATIVE_OUTDENT_WITH_BLANK_PENALTY: isize = 17;
+const RELATIVE_DEDENT_PENALTY: isize = 23;
+const RELATIVE_DEDENT_WITH_BLANK_PENALTY: isize = 17;
+const INDENT_WEIGHT: isize = 60;
+
+// the `@@` hunks of a unified diff turning `old` into `new`, nothing at all if they're the same
+pub(crate) fn hunks(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let old_lines = split_lines(old);
+    let new_lines = split_lines(new);
+    // lines are compared by number rather than content from here on
+    let mut ids = HashMap::new();
+    let mut id_of = |line: &[u8]| {
+        let next = ids.len();
+        *ids.entry(line.to_vec()).or_insert(next)
+    };
+    let old_ids: Vec<usize> = old_lines.iter().map(|line| id_of(line)).collect();
+    let new_ids: Vec<usize> = new_lines.iter().map(|line| id_of(line)).collect();
+
+    let (mut old_changed, mut new_changed) = changed_lines(&old_ids, &new_ids);
+    compact(&old_ids, &old_lines, &mut old_changed, &new_changed);
+    compact(&new_ids, &new_lines, &mut new_changed, &old_changed);
+
+    let ops = edit_script(&old_changed, &new_changed);
+    let mut out = vec![];
+    let changes: Vec<usize> = (0..ops.len())
+        .filter(|&i| !matches!(ops[i], Op::Same(..)))
+        .collect();
+    let mut next = 0;
+    while next < changes.len() {
+        // changes close enough that their context would touch share a hunk
+        let first = changes[next];
+        let mut last = first;
+        next += 1;
+        while next < changes.len() && changes[next] - last - 1 <= 2 * CONTEXT {
+            last = changes[next];
+            next += 1;
+        }
+        let start = first.saturating_sub(CONTEXT);
+        let end = (last + 1 + CONTEXT).min(ops.len());
+        write_hunk(&mut out, &ops[start..end], &old_lines, &new_lines);
+    }
+    out
+}
+
+// each with where it's at in the old and new content
+#[derive(Clone, Copy)]
+enum Op {
+    Same(usize, usize),
+    Removed(usize, usize),
+    Added(usize, usize),
+}
+
+fn write_hunk(out: &mut Vec<u8>, ops: &[Op], old_lines: &[&[u8]], new_lines: &[&[u8]]) {
+    let (old_start, new_start) = match ops[0] {
+        Op::Same(i, j) | Op::Removed(i, j) | Op::Added(i, j) => (i, j),
+    };
+    let old_count = ops.iter().filter(|op| !matches!(op, Op::Added(..))).count();
+    let new_count = ops
+        .iter()
+        .filter(|op| !matches!(op, Op::Removed(..)))
+        .count();
+    out.extend_from_slice(
+        format!(
+            "@@ -{} +{} @@",
+            hunk_range(old_start, old_count),
+            hunk_range(new_start, new_count)
+        )
+        .as_bytes(),
+    );
+    if let Some(func) = func_line(&old_lines[..old_start]) {
+        out.push(b' ');
+        out.extend_from_slice(func);
+    }
+    out.push(b'\n');
+
+    for op in ops {
+        match *op {
+            Op::Same(i, _) => write_line(out, b' ', old_lines[i]),
+            Op::Removed(i, _) => write_line(out, b'-', old_lines[i]),
+            Op::Added(_, j) => write_line(out, b'+', new_lines[j]),
+        }
+    }
+}
+
+// a hunk with nothing on one side starts at the line before where it would be
+fn hunk_range(start: usize, count: usize) -> String {
+    match count {
+        0 => format!("{},0", start),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{}", start + 1, count),
+    }
+}
+
+fn write_line(out: &mut Vec<u8>, marker: u8, line: &[u8]) {
+    out.push(marker);
+    out.extend_from_slice(line);
+    if !line.ends_with(b"\n") {
+        out.extend_from_slice(b"\n\\ No newline at end of file\n");
+    }
+}
+
+// the nearest line above a hunk that looks like the start of a function, which git's default
+// takes to be any line starting with a letter, `_` or `$`
+fn func_line<'a>(above: &[&'a [u8]]) -> Option<&'a [u8]> {
+    let line = above.iter().rev().find(|line| {
+        line.first().map_or(false, |&c| {
+            c.is_ascii_alphabetic() || c == b'_' || c == b'$'
+        })
+    })?;
+    let line = &line[..line.len().min(FUNC_LINE_LEN)];
+    let len = line.len() - line.iter().rev().take_while(|&&c| is_space(c)).count();
+    Some(&line[..len])
+}
+
+// each line keeps its newline, so a last line without one differs from the same line with it
+fn split_lines(content: &[u8]) -> Vec<&[u8]> {
+    content.split_inclusive(|&b| b == b'\n').collect()
+}
+
+// which lines of each side are changed, worked out the way git's xdiff does it so equally short
+// diffs come out the same: lines that can't be matched are put aside, then what's left is split
+// at the middle of its shortest edit over and over until one side of each piece is empty
+fn changed_lines(old: &[usize], new: &[usize]) -> (Vec<bool>, Vec<bool>) {
+    let mut old_changed = vec![false; old.len()];
+    let mut new_changed = vec![false; new.len()];
+    // the unchanged ends needn't go through the search
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let ids = old.iter().chain(new).max().map_or(0, |&id| id + 1);
+    let mut old_count = vec![0; ids];
+    let mut new_count = vec![0; ids];
+    old.iter().for_each(|&id| old_count[id] += 1);
+    new.iter().for_each(|&id| new_count[id] += 1);
+    let (old_kept, old_index) = kept_lines(
+        old,
+        prefix..old.len() - suffix,
+        &new_count,
+        &mut old_changed,
+    );
+    let (new_kept, new_index) = kept_lines(
+        new,
+        prefix..new.len() - suffix,
+        &old_count,
+        &mut new_changed,
+    );
+
+    let mut search = Search::new(&old_kept, &new_kept);
+    search.compare(
+        0,
+        old_kept.len() as isize,
+        0,
+        new_kept.len() as isize,
+        false,
+    );
+    for (i, &changed) in search.old_changed.iter().enumerate() {
+        old_changed[old_index[i]] |= changed;
+    }
+    for (j, &changed) in search.new_changed.iter().enumerate() {
+        new_changed[new_index[j]] |= changed;
+    }
+    (old_changed, new_changed)
+}
+
+// the lines in `range` worth searching for a match on the other side, along with where each is
+// in `lines`; the rest are marked changed already. a line with no match at all is changed
+// whatever happens, and so is one with a great many matches when it's in among changed lines
+fn kept_lines(
+    lines: &[usize],
+    range: Range<usize>,
+    other_count: &[usize],
+    changed: &mut [bool],
+) -> (Vec<usize>, Vec<usize>) {
+    let limit = bogo_sqrt(lines.len()).min(MAX_EQ_LIMIT);
+    let matches: Vec<Matches> = lines
+        .iter()
+        .map(|&id| match other_count[id] {
+            0 => Matches::None,
+            n if n >= limit => Matches::Many,
+            _ => Matches::Some,
+        })
+        .collect();
+    let (mut kept, mut index) = (vec![], vec![]);
+    for i in range.clone() {
+        let keep = match matches[i] {
+            Matches::None => false,
+            Matches::Some => true,
+            Matches::Many => !among_unmatched(&matches, i, range.start, range.end - 1),
+        };
+        if keep {
+            kept.push(lines[i]);
+            index.push(i);
+        } else {
+            changed[i] = true;
+        }
+    }
+    (kept, index)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Matches {
+    None,
+    Some,
+    Many,
+}
+
+// whether the many-matched line `i` sits in a run of lines that are mostly unmatched, looking no
+// further than a window either side
+fn among_unmatched(matches: &[Matches], i: usize, start: usize, end: usize) -> bool {
+    let start = start.max(i.saturating_sub(SIMSCAN_WINDOW));
+    let end = end.min(i + SIMSCAN_WINDOW);
+    let run = |lines: &mut dyn Iterator<Item = &Matches>| {
+        let (mut unmatched, mut many) = (0, 0);
+        for m in lines {
+            match m {
+                Matches::None => unmatched += 1,
+                Matches::Many => many += 1,
+                Matches::Some => break,
+            }
+        }
+        (unmatched, many)
+    };
+    let (unmatched_before, many_before) = run(&mut matches[start..i].iter().rev());
+    if unmatched_before == 0 {
+        return false;
+    }
+    let (unmatched_after, many_after) = run(&mut matches[i + 1..=end].iter());
+    if unmatched_after == 0 {
+        return false;
+    }
+    // counting line `i` itself on both sides
+    let many = many_before + many_after + 2;
+    let unmatched = unmatched_before + unmatched_after;
+    many * KPDIS_RUN < many + unmatched
+}
+
+// xdiff's stand-in for a square root, a power of two about as big
+fn bogo_sqrt(mut n: usize) -> usize {
+    let mut root = 1;
+    while n > 0 {
+        root <<= 1;
+        n >>= 2;
+    }
+    root
+}
+
+// how far along each diagonal k (an old line index minus a new one, so possibly negative) a search
+// has got
+struct Diagonals {
+    furthest: Vec<isize>,
+    zero: isize,
+}
+
+impl Index<isize> for Diagonals {
+    type Output = isize;
+
+    fn index(&self, k: isize) -> &isize {
+        &self.furthest[(k + self.zero) as usize]
+    }
+}
+
+impl IndexMut<isize> for Diagonals {
+    fn index_mut(&mut self, k: isize) -> &mut isize {
+        &mut self.furthest[(k + self.zero) as usize]
+    }
+}
+
+// where to cut a box in two, and whether each half has to be diffed exactly
+struct Split {
+    old: isize,
+    new: isize,
+    exact_before: bool,
+    exact_after: bool,
+}
+
+struct Search<'a> {
+    old: &'a [usize],
+    new: &'a [usize],
+    old_changed: Vec<bool>,
+    new_changed: Vec<bool>,
+    forward: Diagonals,
+    backward: Diagonals,
+    // how many edits to look through before settling for a diff that's merely good
+    max_cost: isize,
+}
+
+impl<'a> Search<'a> {
+    fn new(old: &'a [usize], new: &'a [usize]) -> Self {
+        let diagonals = old.len() + new.len() + 3;
+        let zero = new.len() as isize + 1;
+        Search {
+            old,
+            new,
+            old_changed: vec![false; old.len()],
+            new_changed: vec![false; new.len()],
+            forward: Diagonals {
+                furthest: vec![0; diagonals],
+                zero,
+            },
+            backward: Diagonals {
+                furthest: vec![0; diagonals],
+                zero,
+            },
+            max_cost: (bogo_sqrt(diagonals) as isize).max(MAX_COST_MIN),
+        }
+    }
+
+    fn same(&self, i: isize, j: isize) -> bool {
+        self.old[i as usize] == self.new[j as usize]
+    }
+
+    // marks the changes within old lines `off1..lim1` and new lines `off2..lim2`
+    fn compare(
+        &mut self,
+        mut off1: isize,
+        mut lim1: isize,
+        mut off2: isize,
+        mut lim2: isize,
+        exact: bool,
+    ) {
+        while off1 < lim1 && off2 < lim2 && self.same(off1, off2) {
+            off1 += 1;
+            off2 += 1;
+        }
+        while off1 < lim1 && off2 < lim2 && self.same(lim1 - 1, lim2 - 1) {
+            lim1 -= 1;
+            lim2 -= 1;
+        }
+        if off1 == lim1 {
+            self.new_changed[off2 as usize..lim2 as usize].fill(true);
+        } else if off2 == lim2 {
+            self.old_changed[off1 as usize..lim1 as usize].fill(true);
+        } else {
+            let split = self.split(off1, lim1, off2, lim2, exact);
+            self.compare(off1, split.old, off2, split.new, split.exact_before);
+            self.compare(split.old, lim1, split.new, lim2, split.exact_after);
+        }
+    }
+
+    // searches forward from the top left of the box and back from the bottom right at once until
+    // the two meet, giving up on an exact answer when that's taking too long
+    fn split(&mut self, off1: isize, lim1: isize, off2: isize, lim2: isize, exact: bool) -> Split {
+        let (dmin, dmax) = (off1 - lim2, lim1 - off2);
+        let (fmid, bmid) = (off1 - off2, lim1 - lim2);
+        let odd = (fmid - bmid) & 1 != 0;
+        let (mut fmin, mut fmax) = (fmid, fmid);
+        let (mut bmin, mut bmax) = (bmid, bmid);
+        self.forward[fmid] = off1;
+        self.backward[bmid] = lim1;
+
+        let mut cost = 1;
+        loop {
+            let mut got_snake = false;
+
+            // widen the diagonals looked at by one each way, or narrow them back where that
+            // would leave the box, with the ones just outside set so they're never taken
+            if fmin > dmin {
+                fmin -= 1;
+                self.forward[fmin - 1] = -1;
+            } else {
+                fmin += 1;
+            }
+            if fmax < dmax {
+                fmax += 1;
+                self.forward[fmax + 1] = -1;
+            } else {
+                fmax -= 1;
+            }
+            for d in (fmin..=fmax).rev().step_by(2) {
+                let mut i1 = if self.forward[d - 1] >= self.forward[d + 1] {
+                    self.forward[d - 1] + 1
+                } else {
+                    self.forward[d + 1]
+                };
+                let start = i1;
+                let mut i2 = i1 - d;
+                while i1 < lim1 && i2 < lim2 && self.same(i1, i2) {
+                    i1 += 1;
+                    i2 += 1;
+                }
+                got_snake |= i1 - start > SNAKE_LEN;
+                self.forward[d] = i1;
+                if odd && bmin <= d && d <= bmax && self.backward[d] <= i1 {
+                    return Split {
+                        old: i1,
+                        new: i2,
+                        exact_before: true,
+                        exact_after: true,
+                    };
+                }
+            }
+
+            if bmin > dmin {
+                bmin -= 1;
+                self.backward[bmin - 1] = isize::MAX;
+            } else {
+                bmin += 1;
+            }
+            if bmax < dmax {
+                bmax += 1;
+                self.backward[bmax + 1] = isize::MAX;
+            } else {
+                bmax -= 1;
+            }
+            for d in (bmin..=bmax).rev().step_by(2) {
+                let mut i1 = if self.backward[d - 1] < self.backward[d + 1] {
+                    self.backward[d - 1]
+                } else {
+                    self.backward[d + 1] - 1
+                };
+                let start = i1;
+                let mut i2 = i1 - d;
+                while i1 > off1 && i2 > off2 && self.same(i1 - 1, i2 - 1) {
+                    i1 -= 1;
+                    i2 -= 1;
+                }
+                got_snake |= start - i1 > SNAKE_LEN;
+                self.backward[d] = i1;
+                if !odd && fmin <= d && d <= fmax && i1 <= self.forward[d] {
+                    return Split {
+                        old: i1,
+                        new: i2,
+                        exact_before: true,
+                        exact_after: true,
+                    };
+                }
+            }
+
+            if !exact {
+                if let Some(split) = self.good_split(
+                    (off1, lim1, off2, lim2),
+                    (fmin, fmax, fmid),
+                    (bmin, bmax, bmid),
+                    got_snake,
+                    cost,
+                ) {
+                    return split;
+                }
+            }
+            cost += 1;
+        }
+    }
+
+    // once the edits run into the hundreds, a path that's come a long way and ends in a long run
+    // of matches will do; failing that, once they pass `max_cost`, whichever path got furthest
+    fn good_split(
+        &self,
+        (off1, lim1, off2, lim2): (isize, isize, isize, isize),
+        (fmin, fmax, fmid): (isize, isize, isize),
+        (bmin, bmax, bmid): (isize, isize, isize),
+        got_snake: bool,
+        cost: isize,
+    ) -> Option<Split> {
+        if got_snake && cost > HEUR_MIN_COST {
+            let mut best = None;
+            let mut best_value = 0;
+            for d in (fmin..=fmax).rev().step_by(2) {
+                let i1 = self.forward[d];
+                let i2 = i1 - d;
+                let value = (i1 - off1) + (i2 - off2) - (d - fmid).abs();
+                if value > K_HEUR * cost
+                    && value > best_value
+                    && off1 + SNAKE_LEN <= i1
+                    && i1 < lim1
+                    && off2 + SNAKE_LEN <= i2
+                    && i2 < lim2
+                    && (1..=SNAKE_LEN).all(|k| self.same(i1 - k, i2 - k))
+                {
+                    best = Some((i1, i2));
+                    best_value = value;
+                }
+            }
+            if let Some((old, new)) = best {
+                return Some(Split {
+                    old,
+                    new,
+                    exact_before: true,
+                    exact_after: false,
+                });
+            }
+
+            for d in (bmin..=bmax).rev().step_by(2) {
+                let i1 = self.backward[d];
+                let i2 = i1 - d;
+                let value = (lim1 - i1) + (lim2 - i2) - (d - bmid).abs();
+                if value > K_HEUR * cost
+                    && value > best_value
+                    && off1 < i1
+                    && i1 <= lim1 - SNAKE_LEN
+                    && off2 < i2
+                    && i2 <= lim2 - SNAKE_LEN
+                    && (0..SNAKE_LEN).all(|k| self.same(i1 + k, i2 + k))
+                {
+                    best = Some((i1, i2));
+                    best_value = value;
+                }
+            }
+            if let Some((old, new)) = best {
+                return Some(Split {
+                    old,
+                    new,
+                    exact_before: false,
+                    exact_after: true,
+                });
+            }
+        }
+
+        if cost < self.max_cost {
+            return None;
+        }
+        let (mut fbest, mut fbest1) = (-1, -1);
+        for d in (fmin..=fmax).rev().step_by(2) {
+            let mut i1 = self.forward[d].min(lim1);
+            let mut i2 = i1 - d;
+            if lim2 < i2 {
+                i1 = lim2 + d;
+                i2 = lim2;
+            }
+            if fbest < i1 + i2 {
+                fbest = i1 + i2;
+                fbest1 = i1;
+            }
+        }
+        let (mut bbest, mut bbest1) = (isize::MAX, isize::MAX);
+        for d in (bmin..=bmax).rev().step_by(2) {
+            let mut i1 = self.backward[d].max(off1);
+            let mut i2 = i1 - d;
+            if i2 < off2 {
+                i1 = off2 + d;
+                i2 = off2;
+            }
+            if i1 + i2 < bbest {
+                bbest = i1 + i2;
+                bbest1 = i1;
+            }
+        }
+        Some(if (lim1 + lim2) - bbest < fbest - (off1 + off2) {
+            Split {
+                old: fbest1,
+                new: fbest - fbest1,
+                exact_before: true,
+                exact_after: false,
+            }
+        } else {
+            Split {
+                old: bbest1,
+                new: bbest - bbest1,
+                exact_before: false,
+                exact_after: true,
+            }
+        })
+    }
+}
+
+// a run of changed lines on one side, `start..end`, which is empty between two unchanged lines
+struct Group {
+    start: usize,
+    end: usize,
+}
+
+impl Group {
+    fn first(changed: &[bool]) -> Self {
+        let mut group = Group { start: 0, end: 0 };
+        group.extend_down(changed);
+        group
+    }
+
+    fn extend_down(&mut self, changed: &[bool]) {
+        while self.end < changed.len() && changed[self.end] {
+            self.end += 1;
+        }
+    }
+
+    fn extend_up(&mut self, changed: &[bool]) {
+        while self.start > 0 && changed[self.start - 1] {
+            self.start -= 1;
+        }
+    }
+
+    // the group after the next unchanged line, which may be empty
+    fn next(&mut self, changed: &[bool]) -> bool {
+        if self.end == changed.len() {
+            return false;
+        }
+        self.start = self.end + 1;
+        self.end = self.start;
+        self.extend_down(changed);
+        true
+    }
+
+    fn previous(&mut self, changed: &[bool]) -> bool {
+        if self.start == 0 {
+            return false;
+        }
+        self.end = self.start - 1;
+        self.start = self.end;
+        self.extend_up(changed);
+        true
+    }
+
+    // a group can move along a line when the line it leaves behind is the same as the one it
+    // takes in, joining any group it runs into
+    fn slide_down(&mut self, lines: &[usize], changed: &mut [bool]) -> bool {
+        if self.end == lines.len() || lines[self.start] != lines[self.end] {
+            return false;
+        }
+        changed[self.start] = false;
+        changed[self.end] = true;
+        self.start += 1;
+        self.end += 1;
+        self.extend_down(changed);
+        true
+    }
+
+    fn slide_up(&mut self, lines: &[usize], changed: &mut [bool]) -> bool {
+        if self.start == 0 || lines[self.start - 1] != lines[self.end - 1] {
+            return false;
+        }
+        self.start -= 1;
+        self.end -= 1;
+        changed[self.start] = true;
+        changed[self.end] = false;
+        self.extend_up(changed);
+        true
+    }
+}
+
+// slides each group of changes as far down as it'll go, unless it can line up with a change on
+// the other side instead or another place reads better going by indentation, so that equivalent
+// diffs come out the same way git's do
+fn compact(ids: &[usize], lines: &[&[u8]], changed: &mut [bool], other_changed: &[bool]) {
+    let mut group = Group::first(changed);
+    let mut other = Group::first(other_changed);
+    loop {
+        if group.end != group.start {
+            let mut can_line_up;
+            let mut earliest_end;
+            loop {
+                let size = group.end - group.start;
+                can_line_up = false;
+                while group.slide_up(ids, changed) {
+                    other.previous(other_changed);
+                }
+                earliest_end = group.end;
+                if other.end > other.start {
+                    can_line_up = true;
+                }
+                while group.slide_down(ids, changed) {
+                    other.next(other_changed);
+                    if other.end > other.start {
+                        can_line_up = true;
+                    }
+                }
+                if group.end - group.start == size {
+                    break;
+                }
+            }
+            if group.end == earliest_end {
+                // it can't move at all
+            } else if can_line_up {
+                // back up to the last place it lined up with a change on the other side
+                while other.end == other.start {
+                    group.slide_up(ids, changed);
+                    other.previous(other_changed);
+                }
+            } else {
+                let size = group.end - group.start;
+                let lowest = earliest_end
+                    .max(group.end.saturating_sub(size + 1))
+                    .max(group.end.saturating_sub(MAX_SLIDING));
+                let mut best: Option<(usize, Score)> = None;
+                for end in lowest..=group.end {
+                    let mut score = Score::default();
+                    score.add(&Gap::at(lines, end));
+                    score.add(&Gap::at(lines, end - size));
+                    if best.map_or(true, |(_, best)| score.cmp(&best) <= 0) {
+                        best = Some((end, score));
+                    }
+                }
+                if let Some((end, _)) = best {
+                    while group.end > end {
+                        group.slide_up(ids, changed);
+                        other.previous(other_changed);
+                    }
+                }
+            }
+        }
+        if !group.next(changed) {
+            break;
+        }
+        other.next(other_changed);
+    }
+}
+
+// the surroundings of the gap just above line `at`, where a group of changes starts or ends
+struct Gap {
+    end_of_file: bool,
+    // indents are -1 for a blank line
+    indent: isize,
+    blank_before: isize,
+    indent_before: isize,
+    blank_after: isize,
+    indent_after: isize,
+}
+
+impl Gap {
+    fn at(lines: &[&[u8]], at: usize) -> Self {
+        let mut gap = Gap {
+            end_of_file: at >= lines.len(),
+            indent: lines.get(at).map_or(-1, |line| indent(line)),
+            blank_before: 0,
+            indent_before: -1,
+            blank_after: 0,
+            indent_after: -1,
+        };
+        for line in lines[..at.min(lines.len())].iter().rev() {
+            gap.indent_before = indent(line);
+            if gap.indent_before != -1 {
+                break;
+            }
+            gap.blank_before += 1;
+            if gap.blank_before == MAX_BLANKS {
+                gap.indent_before = 0;
+                break;
+            }
+        }
+        for line in lines.iter().skip(at + 1) {
+            gap.indent_after = indent(line);
+            if gap.indent_after != -1 {
+                break;
+            }
+            gap.blank_after += 1;
+            if gap.blank_after == MAX_BLANKS {
+                gap.indent_after = 0;
+                break;
+            }
+        }
+        gap
+    }
+}
+
+// how bad a place a group of changes is in, lower being better, with git's weights for what tends
+// to read well: starting and ending at blank lines, and at the same indent as what's around it
+#[derive(Clone, Copy, Default)]
+struct Score {
+    effective_indent: isize,
+    penalty: isize,
+}
+
+impl Score {
+    fn add(&mut self, gap: &Gap) {
+        if gap.indent_before == -1 && gap.blank_before == 0 {
+            self.penalty += START_OF_FILE_PENALTY;
+        }
+        if gap.end_of_file {
+            self.penalty += END_OF_FILE_PENALTY;
+        }
+        let blank_after = match gap.indent {
+            -1 => 1 + gap.blank_after,
+            _ => 0,
+        };
+        let total_blank = gap.blank_before + blank_after;
+        self.penalty += TOTAL_BLANK_WEIGHT * total_blank + POST_BLANK_WEIGHT * blank_after;
+
+        let indent = match gap.indent {
+            -1 => gap.indent_after,
+            indent => indent,
+        };
+        let any_blanks = total_blank != 0;
+        self.effective_indent += indent;
+        if indent == -1 || gap.indent_before == -1 || indent == gap.indent_before {
+        } else if indent > gap.indent_before {
+            self.penalty += if any_blanks {
+                RELATIVE_INDENT_WITH_BLANK_PENALTY
+            } else {
+                RELATIVE_INDENT_PENALTY
+            };
+        } else if gap.indent_after != -1 && gap.indent_after > indent {
+            self.penalty += if any_blanks {
+                RELATIVE_OUTDENT_WITH_BLANK_PENALTY
+            } else {
+                RELATIVE_OUTDENT_PENALTY
+            };
+        } else {
+            self.penalty += if any_blanks {
+                RELATIVE_DEDENT_WITH_BLANK_PENALTY
+            } else {
+                RELATIVE_DEDENT_PENALTY
+            };
+        }
+    }
+
+    fn cmp(&self, other: &Score) -> isize {
+        let indents = self.effective_indent.cmp(&other.effective_indent) as isize;
+        INDENT_WEIGHT * indents + self.penalty - other.penalty
+    }
+}
+
+// how far a line is indented, counting tabs to the next multiple of 8, or -1 if it's blank
+fn indent(line: &[u8]) -> isize {
+    let mut indent = 0;
+    for &c in line {
+        if !is_space(c) {
+            return indent;
+        }
+        if c == b' ' {
+            indent += 1;
+        } else if c == b'\t' {
+            indent += 8 - indent % 8;
+        }
+        if indent >= MAX_INDENT {
+            return MAX_INDENT;
+        }
+    }
+    -1
+}
+
+// C's isspace, which unlike `is_ascii_whitespace` takes in vertical tabs
+fn is_space(c: u8) -> bool {
+    matches!(c, b' ' | b'\t' | b'\n' | b'\x0b' | b'\x0c' | b'\r')
+}
+
+fn edit_script(old_changed: &[bool], new_changed: &[bool]) -> Vec<Op> {
+    let mut ops = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < old_changed.len() || j < new_changed.len() {
+        if i < old_changed.len() && old_changed[i] {
+            ops.push(Op::Removed(i, j));
+            i += 1;
+        } else if j < new_changed.len() && new_changed[j] {
+            ops.push(Op::Added(i, j));
+            j += 1;
+        } else {
+            ops.push(Op::Same(i, j));
+            i += 1;
+            j += 1;
+        }
+    }
+    ops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // hunks as git diff prints them for the same change
+    fn diff(old: &str, new: &str) -> String {
+        String::from_utf8(hunks(old.as_bytes(), new.as_bytes())).unwrap()
+    }
+
+    fn lines(range: std::ops::RangeInclusive<usize>) -> String {
+        range.map(|n| format!("line {}\n", n)).collect()
+    }
+
+    #[test]
+    fn same_content_has_no_hunks() {
+        assert_eq!(diff("a\nb\n", "a\nb\n"), "");
+    }
+
+    #[test]
+    fn pure_add() {
+        let old = "one\ntwo\nthree\n";
+        let new = "one\ntwo\nnew\nthree\n";
+        assert_eq!(
+            diff(old, new),
+            concat!(
+                "@@ -1,3 +1,4 @@\n",
+                " one\n",
+                " two\n",
+                "+new\n",
+                " three\n"
+            )
+        );
+    }
+
+    #[test]
+    fn add_to_empty() {
+        let old = "";
+        let new = "a\nb\n";
+        assert_eq!(diff(old, new), concat!("@@ -0,0 +1,2 @@\n", "+a\n", "+b\n"));
+    }
+
+    #[test]
+    fn pure_delete() {
+        let old = lines(1..=5);
+        let new = lines(1..=5).replace("line 3\n", "");
+        assert_eq!(
+            diff(&old, &new),
+            concat!(
+                "@@ -1,5 +1,4 @@\n",
+                " line 1\n",
+                " line 2\n",
+                "-line 3\n",
+                " line 4\n",
+                " line 5\n"
+            )
+        );
+    }
+
+    #[test]
+    fn change_in_middle() {
+        // a line starting with a letter above the hunk names it
+        let old = lines(1..=9);
+        let new = lines(1..=9).replace("line 5\n", "five\n");
+        assert_eq!(
+            diff(&old, &new),
+            concat!(
+                "@@ -2,7 +2,7 @@ line 1\n",
+                " line 2\n",
+                " line 3\n",
+                " line 4\n",
+                "-line 5\n",
+                "+five\n",
+                " line 6\n",
+                " line 7\n",
+                " line 8\n"
+            )
+        );
+    }
+
+    #[test]
+    fn no_newline_at_end() {
+        let old = "a\nb";
+        let new = "a\nc";
+        assert_eq!(
+            diff(old, new),
+            concat!(
+                "@@ -1,2 +1,2 @@\n",
+                " a\n",
+                "-b\n",
+                "\\ No newline at end of file\n",
+                "+c\n",
+                "\\ No newline at end of file\n"
+            )
+        );
+    }
+
+    #[test]
+    fn newline_added_at_end() {
+        let old = "a\nb";
+        let new = "a\nb\n";
+        assert_eq!(
+            diff(old, new),
+            concat!(
+                "@@ -1,2 +1,2 @@\n",
+                " a\n",
+                "-b\n",
+                "\\ No newline at end of file\n",
+                "+b\n"
+            )
+        );
+    }
+
+    #[test]
+    fn context_width() {
+        // three lines either side, no more
+        let old = lines(1..=20);
+        let new = lines(1..=20).replace("line 10\n", "ten\n");
+        assert_eq!(
+            diff(&old, &new),
+            concat!(
+                "@@ -7,7 +7,7 @@ line 6\n",
+                " line 7\n",
+                " line 8\n",
+                " line 9\n",
+                "-line 10\n",
+                "+ten\n",
+                " line 11\n",
+                " line 12\n",
+                " line 13\n"
+            )
+        );
+    }
+
+    #[test]
+    fn close_changes_share_hunk() {
+        // six lines apart, so their context meets
+        let old = lines(1..=20);
+        let new = lines(1..=20)
+            .replace("line 5\n", "five\n")
+            .replace("line 12\n", "x\n");
+        assert_eq!(
+            diff(&old, &new),
+            concat!(
+                "@@ -2,14 +2,14 @@ line 1\n",
+                " line 2\n",
+                " line 3\n",
+                " line 4\n",
+                "-line 5\n",
+                "+five\n",
+                " line 6\n",
+                " line 7\n",
+                " line 8\n",
+                " line 9\n",
+                " line 10\n",
+                " line 11\n",
+                "-line 12\n",
+                "+x\n",
+                " line 13\n",
+                " line 14\n",
+                " line 15\n"
+            )
+        );
+    }
+
+    #[test]
+    fn distant_changes_split_hunks() {
+        // seven lines apart, one too many to share
+        let old = lines(1..=20);
+        let new = lines(1..=20)
+            .replace("line 5\n", "five\n")
+            .replace("line 13\n", "x\n");
+        assert_eq!(
+            diff(&old, &new),
+            concat!(
+                "@@ -2,7 +2,7 @@ line 1\n",
+                " line 2\n",
+                " line 3\n",
+                " line 4\n",
+                "-line 5\n",
+                "+five\n",
+                " line 6\n",
+                " line 7\n",
+                " line 8\n",
+                "@@ -10,7 +10,7 @@ line 9\n",
+                " line 10\n",
+                " line 11\n",
+                " line 12\n",
+                "-line 13\n",
+                "+x\n",
+                " line 14\n",
+                " line 15\n",
+                " line 16\n"
+            )
+        );
+    }
+
+    #[test]
+    fn indent_heuristic_places_added_block() {
+        // the blank line could go before or after the new function; git puts it after
+        let old = "fn a() {\n    one();\n}\n\nfn c() {\n    three();\n}\n";
+        let new =
+            "fn a() {\n    one();\n}\n\nfn b() {\n    two();\n}\n\nfn c() {\n    three();\n}\n";
+        assert_eq!(
+            diff(old, new),
+            concat!(
+                "@@ -2,6 +2,10 @@ fn a() {\n",
+                "     one();\n",
+                " }\n",
+                " \n",
+                "+fn b() {\n",
+                "+    two();\n",
+                "+}\n",
+                "+\n",
+                " fn c() {\n",
+                "     three();\n",
+                " }\n"
+            )
+        );
+    }
+}
@@ -0,0 +1,69 @@
+use std::fmt;
+use std::process::ExitCode;
+
+// how a command failed, which decides both what's reported and the exit status; like git, dying
+// with `fatal:` exits with 128 while `error:` and plain unsuccessful answers exit with 1
+#[derive(Debug)]
+pub(crate) enum GitError {
+    // an object, ref or revision that doesn't exist
+    NotFound(String),
+    // an object of another type than the command needs
+    BadType(String),
+    // something that's there but can't be parsed or doesn't hash to its name
+    Corrupt(String),
+    NotARepo(String),
+    // an object database that can't be written to, which git reports the same way whichever step
+    // ran into it
+    NoPermission(String),
+    // arguments that can't be acted on, like an invalid branch name
+    Invalid(String),
+    NotImplemented(String),
+    // problems git reports as errors rather than dying of them
+    Failed(String),
+    // nothing to report, the command just answers no, like `cat-file -e` on a missing object
+    Quiet,
+    // anything else going wrong along the way
+    Other(anyhow::Error),
+}
+
+impl GitError {
+    pub(crate) fn code(&self) -> u8 {
+        match self {
+            GitError::NotImplemented(_) | GitError::Failed(_) | GitError::Quiet => 1,
+            _ => 128,
+        }
+    }
+
+    pub(crate) fn exit_code(&self) -> ExitCode {
+        ExitCode::from(self.code())
+    }
+}
+
+impl fmt::Display for GitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GitError::NotFound(msg)
+            | GitError::BadType(msg)
+            | GitError::Corrupt(msg)
+            | GitError::NotARepo(msg)
+            | GitError::NoPermission(msg)
+            | GitError::Invalid(msg) => write!(f, "fatal: {}", msg),
+            GitError::NotImplemented(msg) => write!(f, "{}", msg),
+            GitError::Failed(msg) => write!(f, "error: {}", msg),
+            GitError::Quiet => Ok(()),
+            GitError::Other(e) => write!(f, "fatal: {:#}", e),
+        }
+    }
+}
+
+impl std::error::Error for GitError {}
+
+// one that's been passed along as an anyhow error, perhaps with context, keeps its own report
+impl From<anyhow::Error> for GitError {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<GitError>() {
+            Ok(e) => e,
+            Err(e) => GitError::Other(e),
+        }
+    }
+}
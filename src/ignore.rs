@@ -0,0 +1,241 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+#[derive(Clone)]
+struct IgnorePattern {
+    pattern: Vec<u8>,
+    // directory of the .gitignore the pattern came from, relative to the top of the worktree
+    // and ending with a `/` unless it's the top itself
+    base: String,
+    negated: bool,
+    dir_only: bool,
+    anchored: bool,
+}
+
+#[derive(Default)]
+pub(crate) struct IgnoreRules {
+    patterns: Vec<IgnorePattern>,
+}
+
+impl IgnoreRules {
+    // rules for the subtree at `dir`, i.e. these plus any from `dir/.gitignore`
+    pub(crate) fn for_dir(&self, dir: &Path, base: &str) -> Result<IgnoreRules> {
+        let mut patterns = self.patterns.clone();
+
+        let ignore_path = dir.join(".gitignore");
+        if ignore_path.is_file() {
+            let contents = std::fs::read(&ignore_path)
+                .with_context(|| format!("reading {}", ignore_path.to_string_lossy()))?;
+            patterns.extend(
+                contents
+                    .split(|&b| b == b'\n')
+                    .filter_map(|line| parse_line(line, base)),
+            );
+        }
+        Ok(IgnoreRules { patterns })
+    }
+
+    // `path` is relative to the top of the worktree; the last matching pattern wins
+    pub(crate) fn is_ignored(&self, path: &str, is_dir: bool) -> bool {
+        let mut ignored = false;
+        for pat in &self.patterns {
+            if (pat.dir_only && !is_dir) || !path.starts_with(&pat.base) {
+                continue;
+            }
+            let rel_path = &path[pat.base.len()..];
+            let subject = if pat.anchored {
+                rel_path
+            } else {
+                rel_path.rsplit('/').next().unwrap_or(rel_path)
+            };
+            if wildmatch(&pat.pattern, subject.as_bytes()) {
+                ignored = !pat.negated;
+            }
+        }
+        ignored
+    }
+}
+
+fn parse_line(line: &[u8], base: &str) -> Option<IgnorePattern> {
+    let mut line = line.strip_suffix(b"\r").unwrap_or(line);
+    if line.is_empty() || line[0] == b'#' {
+        return None;
+    }
+
+    // trailing spaces are dropped unless escaped with a backslash
+    while line.ends_with(b" ") && !line.ends_with(b"\\ ") {
+        line = &line[..line.len() - 1];
+    }
+    if line.is_empty() {
+        return None;
+    }
+
+    // a backslash lets a pattern start with a literal `!` or `#`
+    let negated = line[0] == b'!';
+    if negated || line.starts_with(b"\\!") || line.starts_with(b"\\#") {
+        line = &line[1..];
+    }
+
+    let dir_only = line.ends_with(b"/");
+    if dir_only {
+        line = &line[..line.len() - 1];
+    }
+    if line.is_empty() {
+        return None;
+    }
+
+    // a slash anywhere but the end ties the pattern to the .gitignore's directory, otherwise
+    // it matches a name at any depth below it
+    let anchored = line.contains(&b'/');
+    let line = line.strip_prefix(b"/").unwrap_or(line);
+
+    Some(IgnorePattern {
+        pattern: line.to_vec(),
+        base: base.to_string(),
+        negated,
+        dir_only,
+        anchored,
+    })
+}
+
+// shell-style glob match where `*`, `?` and `[...]` don't cross a `/` but `**` does
+fn wildmatch(pat: &[u8], text: &[u8]) -> bool {
+    match pat.first() {
+        None => text.is_empty(),
+        Some(b'*') if pat.get(1) == Some(&b'*') => {
+            let rest = &pat[2..];
+            // `**/` also matches no directories at all
+            if rest.first() == Some(&b'/') && wildmatch(&rest[1..], text) {
+                return true;
+            }
+            (0..=text.len()).any(|i| wildmatch(rest, &text[i..]))
+        }
+        Some(b'*') => {
+            let rest = &pat[1..];
+            for i in 0..=text.len() {
+                if wildmatch(rest, &text[i..]) {
+                    return true;
+                }
+                if text.get(i) == Some(&b'/') {
+                    break;
+                }
+            }
+            false
+        }
+        Some(b'?') => match text.first() {
+            Some(&c) if c != b'/' => wildmatch(&pat[1..], &text[1..]),
+            _ => false,
+        },
+        Some(b'[') => match match_class(&pat[1..], text.first()) {
+            Some((matched, rest)) => matched && wildmatch(rest, &text[1..]),
+            // no closing bracket, so it's just a literal `[`
+            None => text.first() == Some(&b'[') && wildmatch(&pat[1..], &text[1..]),
+        },
+        Some(b'\\') if pat.len() > 1 => {
+            text.first() == Some(&pat[1]) && wildmatch(&pat[2..], &text[1..])
+        }
+        Some(&c) => text.first() == Some(&c) && wildmatch(&pat[1..], &text[1..]),
+    }
+}
+
+// matches `c` against the bracket expression starting just after its `[`, returning whether it
+// matched and the rest of the pattern after the closing `]`; the end of the text never matches
+fn match_class<'a>(pat: &'a [u8], c: Option<&u8>) -> Option<(bool, &'a [u8])> {
+    let c = c.copied().unwrap_or(b'/');
+    let (negated, mut i) = match pat.first() {
+        Some(b'!') | Some(b'^') => (true, 1),
+        _ => (false, 0),
+    };
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let lo = *pat.get(i)?;
+        if lo == b']' && !first {
+            break;
+        }
+        first = false;
+        if pat.get(i + 1) == Some(&b'-') && pat.get(i + 2).map_or(false, |&hi| hi != b']') {
+            let hi = pat[i + 2];
+            matched |= lo <= c && c <= hi;
+            i += 3;
+        } else {
+            matched |= lo == c;
+            i += 1;
+        }
+    }
+    Some((matched != negated && c != b'/', &pat[i + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::scratch_dir;
+
+    fn rules(lines: &str) -> IgnoreRules {
+        IgnoreRules {
+            patterns: lines
+                .lines()
+                .filter_map(|line| parse_line(line.as_bytes(), ""))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn wildmatch_globs() {
+        assert!(wildmatch(b"*.rs", b"main.rs"));
+        assert!(!wildmatch(b"*.rs", b"src/main.rs"));
+        assert!(wildmatch(b"?at", b"cat"));
+        assert!(!wildmatch(b"?at", b"/at"));
+        assert!(wildmatch(b"[a-c]at", b"bat"));
+        assert!(!wildmatch(b"[a-c]at", b"dat"));
+        assert!(wildmatch(b"[!a-c]at", b"dat"));
+        assert!(wildmatch(b"\\*", b"*"));
+        assert!(!wildmatch(b"\\*", b"a"));
+        // no closing bracket, so it's taken literally
+        assert!(wildmatch(b"[abc", b"[abc"));
+    }
+
+    #[test]
+    fn wildmatch_double_star_crosses_dirs() {
+        assert!(wildmatch(b"**/bar", b"bar"));
+        assert!(wildmatch(b"**/bar", b"foo/baz/bar"));
+        assert!(wildmatch(b"foo/**/bar", b"foo/bar"));
+        assert!(wildmatch(b"foo/**/bar", b"foo/a/b/bar"));
+        assert!(!wildmatch(b"foo/**/bar", b"foo/a/b/baz"));
+    }
+
+    #[test]
+    fn negation_and_dir_only() {
+        let rules = rules("# build output\n   \n*.log\n!keep.log\nbuild/\n\\!important\n");
+        assert!(rules.is_ignored("debug.log", false));
+        assert!(rules.is_ignored("src/debug.log", false));
+        assert!(!rules.is_ignored("keep.log", false));
+        assert!(!rules.is_ignored("src/keep.log", false));
+        assert!(rules.is_ignored("build", true));
+        assert!(rules.is_ignored("src/build", true));
+        assert!(!rules.is_ignored("build", false));
+        assert!(rules.is_ignored("!important", false));
+        assert!(!rules.is_ignored("# build output", false));
+    }
+
+    #[test]
+    fn nested_gitignore_applies_to_its_subtree() {
+        let top = scratch_dir("nested-gitignore");
+        std::fs::create_dir_all(top.join("sub/build")).unwrap();
+        std::fs::write(top.join(".gitignore"), "*.tmp\nbuild/\n").unwrap();
+        std::fs::write(top.join("sub/.gitignore"), "!keep.tmp\n/local\n").unwrap();
+
+        let top_rules = IgnoreRules::default().for_dir(&top, "").unwrap();
+        let sub_rules = top_rules.for_dir(&top.join("sub"), "sub/").unwrap();
+        assert!(top_rules.is_ignored("a.tmp", false));
+        assert!(top_rules.is_ignored("keep.tmp", false));
+        assert!(!top_rules.is_ignored("local", false));
+        assert!(sub_rules.is_ignored("sub/a.tmp", false));
+        assert!(!sub_rules.is_ignored("sub/keep.tmp", false));
+        assert!(sub_rules.is_ignored("sub/local", false));
+        assert!(sub_rules.is_ignored("sub/build", true));
+        // a leading slash ties the pattern to sub/ itself
+        assert!(!sub_rules.is_ignored("sub/deeper/local", false));
+    }
+}
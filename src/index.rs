@@ -0,0 +1,234 @@
+use std::fs::{Metadata, OpenOptions};
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use anyhow::{ensure, Context, Result};
+use sha1::{Digest, Sha1};
+
+const INDEX_SIGNATURE: &[u8] = b"DIRC";
+const INDEX_VERSION: u32 = 2;
+// ctime, mtime, dev, ino, mode, uid, gid and size, then the sha and flags
+const ENTRY_FIXED_LEN: usize = 10 * 4 + 20 + 2;
+const FLAG_NAME_MASK: u16 = 0x0fff;
+const FLAG_STAGE_SHIFT: u16 = 12;
+
+pub(crate) struct IndexEntry {
+    pub(crate) ctime: (u32, u32),
+    pub(crate) mtime: (u32, u32),
+    pub(crate) dev: u32,
+    pub(crate) ino: u32,
+    pub(crate) mode: u32,
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+    pub(crate) size: u32,
+    pub(crate) hash: [u8; 20],
+    pub(crate) flags: u16,
+    pub(crate) name: String,
+}
+
+impl IndexEntry {
+    pub(crate) fn from_metadata(name: String, meta: &Metadata, hash: [u8; 20]) -> Self {
+        let mode = if crate::is_symlink(meta) {
+            0o120000
+        } else if crate::is_executable(meta) {
+            0o100755
+        } else {
+            0o100644
+        };
+        let flags = name.len().min(FLAG_NAME_MASK as usize) as u16;
+
+        // git stores all of these truncated to 32 bits
+        #[cfg(unix)]
+        let entry = IndexEntry {
+            ctime: (meta.ctime() as u32, meta.ctime_nsec() as u32),
+            mtime: (meta.mtime() as u32, meta.mtime_nsec() as u32),
+            dev: meta.dev() as u32,
+            ino: meta.ino() as u32,
+            mode,
+            uid: meta.uid(),
+            gid: meta.gid(),
+            size: meta.size() as u32,
+            hash,
+            flags,
+            name,
+        };
+        // elsewhere there's no inode, device or owner, so like git for windows only the times and
+        // size are used to tell whether a file has changed
+        #[cfg(not(unix))]
+        let entry = {
+            let since_epoch = |time: std::io::Result<std::time::SystemTime>| {
+                time.ok()
+                    .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                    .map(|dur| (dur.as_secs() as u32, dur.subsec_nanos()))
+                    .unwrap_or_default()
+            };
+            let mtime = since_epoch(meta.modified());
+            IndexEntry {
+                ctime: mtime,
+                mtime,
+                dev: 0,
+                ino: 0,
+                mode,
+                uid: 0,
+                gid: 0,
+                size: meta.len() as u32,
+                hash,
+                flags,
+                name,
+            }
+        };
+        entry
+    }
+
+    // non-zero while a merge conflict is unresolved
+    pub(crate) fn stage(&self) -> u16 {
+        (self.flags >> FLAG_STAGE_SHIFT) & 0b11
+    }
+}
+
+pub(crate) struct Index {
+    pub(crate) entries: Vec<IndexEntry>,
+}
+
+impl Index {
+    // a missing index file is the same as an empty one
+    pub(crate) fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Index { entries: vec![] });
+        }
+        let bytes = std::fs::read(path).context("reading index file")?;
+
+        ensure!(bytes.len() >= 12 + 20, "index file is too short");
+        let (body, checksum) = bytes.split_at(bytes.len() - 20);
+        ensure!(
+            Sha1::digest(body).as_slice() == checksum,
+            "index file checksum mismatch"
+        );
+        ensure!(&body[..4] == INDEX_SIGNATURE, "bad index file signature");
+        let version = be_u32(&body[4..8]);
+        ensure!(
+            version == INDEX_VERSION,
+            "unsupported index file version {}",
+            version
+        );
+        let count = be_u32(&body[8..12]) as usize;
+
+        let mut entries = Vec::with_capacity(count);
+        let mut pos = 12;
+        for _ in 0..count {
+            let fixed = body
+                .get(pos..pos + ENTRY_FIXED_LEN)
+                .context("index entry is truncated")?;
+            let field = |i: usize| be_u32(&fixed[i * 4..]);
+            let mut hash = [0u8; 20];
+            hash.copy_from_slice(&fixed[40..60]);
+            let flags = u16::from_be_bytes([fixed[60], fixed[61]]);
+
+            let name_start = pos + ENTRY_FIXED_LEN;
+            let name_len = body[name_start..]
+                .iter()
+                .position(|&b| b == 0)
+                .context("index entry name is not NUL-terminated")?;
+            let name = String::from_utf8_lossy(&body[name_start..name_start + name_len]).into();
+
+            entries.push(IndexEntry {
+                ctime: (field(0), field(1)),
+                mtime: (field(2), field(3)),
+                dev: field(4),
+                ino: field(5),
+                mode: field(6),
+                uid: field(7),
+                gid: field(8),
+                size: field(9),
+                hash,
+                flags,
+                name,
+            });
+            pos += entry_len(name_len);
+        }
+
+        // any extensions (cached trees etc.) after the entries are dropped, git will recreate
+        // them as needed
+        Ok(Index { entries })
+    }
+
+    pub(crate) fn write<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+
+        let mut buf = Vec::new();
+        buf.extend_from_slice(INDEX_SIGNATURE);
+        buf.extend_from_slice(&INDEX_VERSION.to_be_bytes());
+        buf.extend_from_slice(&(self.entries.len() as u32).to_be_bytes());
+        for ent in &self.entries {
+            let start = buf.len();
+            for field in [
+                ent.ctime.0,
+                ent.ctime.1,
+                ent.mtime.0,
+                ent.mtime.1,
+                ent.dev,
+                ent.ino,
+                ent.mode,
+                ent.uid,
+                ent.gid,
+                ent.size,
+            ] {
+                buf.extend_from_slice(&field.to_be_bytes());
+            }
+            buf.extend_from_slice(&ent.hash);
+            buf.extend_from_slice(&ent.flags.to_be_bytes());
+            buf.extend_from_slice(ent.name.as_bytes());
+            // pad with 1-8 NULs to keep each entry a multiple of 8 bytes long
+            buf.resize(start + entry_len(ent.name.len()), 0);
+        }
+        let checksum = Sha1::digest(&buf);
+        buf.extend_from_slice(&checksum);
+
+        // like git, take `index.lock` while writing and move it into place when done
+        let lock_path = path.with_extension("lock");
+        let mut lockfile = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(&lock_path)
+            .context("Unable to create index.lock, another git process may be running")?;
+        let written = lockfile
+            .write_all(&buf)
+            .and_then(|_| lockfile.sync_all())
+            .context("writing index.lock");
+        if written.is_err() {
+            let _ = std::fs::remove_file(&lock_path);
+            return written;
+        }
+        std::fs::rename(&lock_path, path).context("moving index.lock into place")?;
+        Ok(())
+    }
+
+    pub(crate) fn find(&self, name: &str) -> Option<&IndexEntry> {
+        self.entries
+            .binary_search_by(|ent| ent.name.as_bytes().cmp(name.as_bytes()))
+            .ok()
+            .map(|i| &self.entries[i])
+    }
+
+    // entries are kept sorted by name, as the on-disk format requires
+    pub(crate) fn add(&mut self, entry: IndexEntry) {
+        match self
+            .entries
+            .binary_search_by(|ent| ent.name.as_bytes().cmp(entry.name.as_bytes()))
+        {
+            Ok(i) => self.entries[i] = entry,
+            Err(i) => self.entries.insert(i, entry),
+        }
+    }
+}
+
+fn entry_len(name_len: usize) -> usize {
+    (ENTRY_FIXED_LEN + name_len + 8) / 8 * 8
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes[..4].try_into().unwrap())
+}
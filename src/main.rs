@@ -1,421 +1,3531 @@
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet};
 use std::fs::{File, OpenOptions};
-use std::io::{BufRead, BufReader, Read, Seek, Write};
-use std::path::{Path, PathBuf};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+#[cfg(unix)]
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+#[cfg(unix)]
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
 use std::process::ExitCode;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::SystemTime;
 
-use anyhow::{ensure, Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use clap::Parser;
 use flate2::{read::ZlibDecoder, write::ZlibEncoder};
 
 mod cli;
+mod config;
+mod diff;
+mod error;
+mod ignore;
+mod index;
+mod pack;
+mod progress;
+mod refs;
+mod remote;
+mod repo;
+#[cfg(test)]
+mod scratch;
 
 use cli::{Args, Commands};
+use error::GitError;
+use progress::Progress;
+use repo::Repo;
 
 fn main() -> ExitCode {
-    let ret_not_impl: ExitCode = ExitCode::from(1);
-    let ret_invalid_objsha: ExitCode = ExitCode::from(128);
-    let ret_bad_file = ExitCode::from(128);
+    match run(Args::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(GitError::Quiet) => GitError::Quiet.exit_code(),
+        Err(e) => {
+            println!("{}", e);
+            e.exit_code()
+        }
+    }
+}
+
+fn run(mut cli: Args) -> Result<(), GitError> {
+    let git_dir = cli
+        .git_dir
+        .take()
+        .or_else(|| std::env::var_os("GIT_DIR").map(PathBuf::from));
 
-    let cli = Args::parse();
+    // init and hashing without writing never look at an existing repository
+    let mut repo = Repo::new(".git");
+    // where we were run from, relative to the top of the worktree
+    let mut cwd_prefix = String::new();
+    // hashing without writing doesn't need a repo, but uses one's config when there is one
+    let found = match &cli.command {
+        Commands::HashObject { write: false, .. } => {
+            let cur_dir = std::env::current_dir().expect("read cwd");
+            Repo::find(git_dir.as_deref(), &cur_dir).ok()
+        }
+        command if needs_repo(command) => {
+            let cur_dir = std::env::current_dir().expect("read cwd");
+            Some(
+                Repo::find(git_dir.as_deref(), &cur_dir)
+                    .map_err(|e| GitError::NotARepo(e.to_string()))?,
+            )
+        }
+        _ => None,
+    };
+    if let Some((found, top)) = found {
+        let cur_dir = std::env::current_dir().expect("read cwd");
+        repo = found;
+        if let Ok(rel_path) = cur_dir.strip_prefix(&top) {
+            cwd_prefix = rel_path.to_string_lossy().into_owned();
+        }
+        // files are named relative to where we were run, but everything else works from the top
+        // of the worktree
+        match &mut cli.command {
+            Commands::UpdateIndex { file, .. } => *file = worktree_path(&top, &cur_dir, file)?,
+            Commands::HashObject { files, .. } => {
+                for file in files {
+                    *file = cur_dir.join(&file).to_string_lossy().into_owned();
+                }
+            }
+            Commands::UnpackObjects { pack } | Commands::IndexPack { pack, .. } => {
+                *pack = cur_dir.join(&pack).to_string_lossy().into_owned()
+            }
+            _ => {}
+        }
+        std::env::set_current_dir(&top)
+            .with_context(|| format!("cannot change to '{}'", top.to_string_lossy()))?;
+    }
+    let repo = &repo;
 
     match cli.command {
-        Commands::Init => {
-            std::fs::create_dir(".git").unwrap();
-            std::fs::create_dir(".git/objects").unwrap();
-            std::fs::create_dir(".git/refs").unwrap();
-            std::fs::write(".git/HEAD", "ref: refs/heads/master\n").unwrap();
-            println!("Initialized git directory");
-            ExitCode::SUCCESS
+        Commands::Init {
+            bare,
+            initial_branch,
+        } => {
+            if !is_valid_branch_name(&initial_branch) {
+                return Err(GitError::Invalid(format!(
+                    "invalid initial branch name: '{}'",
+                    initial_branch
+                )));
+            }
+
+            // a bare repository has no working tree, so the git dir is the current dir itself
+            let explicit_git_dir = git_dir.is_some();
+            let git_dir = match git_dir {
+                Some(git_dir) => git_dir,
+                None if bare => PathBuf::from("."),
+                None => PathBuf::from(".git"),
+            };
+            if git_dir.exists() && !git_dir.is_dir() {
+                return Err(GitError::Invalid(format!(
+                    "{} exists but is not a directory",
+                    git_dir.to_string_lossy()
+                )));
+            }
+            let reinit = git_dir.join("HEAD").exists() || (!bare && git_dir.exists());
+            create_git_dir(&git_dir, bare, &initial_branch).context("creating git dir")?;
+
+            let cur_dir = std::env::current_dir().expect("read cwd");
+            let repo_path = cur_dir.join(&git_dir);
+            let repo_path = repo_path.canonicalize().unwrap_or(repo_path);
+            if reinit {
+                println!(
+                    "Reinitialized existing Git repository in {}/",
+                    repo_path.to_string_lossy()
+                );
+            } else if bare || explicit_git_dir {
+                println!(
+                    "Initialized empty Git repository in {}/",
+                    repo_path.to_string_lossy()
+                );
+            } else {
+                println!("Initialized git directory");
+            }
         }
         Commands::CatFile {
             pretty_print,
+            type_only,
+            size_only,
+            exists,
+            allow_unknown_type,
+            strict,
+            safe,
+            raw: _,
+            batch,
+            batch_check,
+            batch_all_objects,
             obj_sha,
         } => {
-            if !pretty_print {
-                println!("cat-file without pretty-print not implemented");
-                return ret_not_impl;
-            }
-            if is_plausibly_obj_sha(&obj_sha) {
-                let p = obj_path_from_sha(&obj_sha);
-                if let Ok(blobfile) = File::open(p) {
-                    let (_objtype, _objsz, mut reader) = object_decoder(blobfile);
-                    if std::io::copy(&mut reader, &mut std::io::stdout()).is_err() {
-                        ExitCode::FAILURE
-                    } else {
-                        ExitCode::SUCCESS
-                    }
+            if batch || batch_check {
+                return Ok(cat_file_batch(repo, batch, batch_all_objects)?);
+            }
+            let obj_sha = obj_sha.expect("clap to require an object unless batching");
+            if !pretty_print && !type_only && !size_only && !exists {
+                return Err(GitError::NotImplemented(
+                    "cat-file without pretty-print, type, size or exists not implemented".into(),
+                ));
+            }
+            let not_found = || GitError::NotFound(format!("Not a valid object name {}", obj_sha));
+            // anything rev-parse takes, like HEAD, a branch or an abbreviated sha
+            let obj_sha = rev_parse(repo, &obj_sha)?.ok_or_else(not_found)?;
+            // missing objects are reported as usual below
+            if strict && verify_object(repo, &obj_sha)? == Some(false) {
+                return Err(GitError::Corrupt(format!("object {} is corrupt", obj_sha)));
+            }
+            if allow_unknown_type && (type_only || size_only) {
+                let (type_name, objsz) = object_header(repo, &obj_sha)?.ok_or_else(not_found)?;
+                if type_only {
+                    println!("{}", type_name);
                 } else {
-                    ret_invalid_objsha
+                    println!("{}", objsz);
+                }
+                return Ok(());
+            }
+            let (objtype, objsz, mut reader) = match open_object(repo, &obj_sha)? {
+                Some(obj) => obj,
+                None if exists => return Err(GitError::Quiet),
+                None => return Err(not_found()),
+            };
+            if exists {
+                // finding it is all that was asked
+            } else if type_only {
+                println!("{}", objtype.type_name());
+            } else if size_only {
+                println!("{}", objsz);
+            } else if let ObjType::Tree | ObjType::Tag = objtype {
+                let obj = GitObject::decode(objtype, reader).map_err(|_| {
+                    GitError::Corrupt(format!(
+                        "corrupt {} object {}",
+                        objtype.type_name(),
+                        obj_sha
+                    ))
+                })?;
+                match obj {
+                    // trees hold binary shas, so render them the way ls-tree does
+                    GitObject::Tree(tree_ents) => {
+                        let quote_high = repo
+                            .config
+                            .get::<bool>("core", "quotepath")?
+                            .unwrap_or(true);
+                        let mut stdout = std::io::stdout();
+                        for ent in tree_ents {
+                            let mut line = ent.ls_prefix(None).into_bytes();
+                            line.extend_from_slice(&quote_path(&ent.name, quote_high));
+                            line.push(b'\n');
+                            stdout.write_all(&line).expect("to write tree to stdout");
+                        }
+                    }
+                    obj => std::io::stdout()
+                        .write_all(&obj.to_bytes())
+                        .expect("to write object to stdout"),
                 }
             } else {
-                println!("fatal: Not a valid object name {}", obj_sha);
-                ret_invalid_objsha
+                // like git's own guess at binary content, a NUL early on
+                let mut start = vec![];
+                (&mut reader)
+                    .take(BINARY_CHECK_LEN.min(objsz) as u64)
+                    .read_to_end(&mut start)
+                    .context("reading object content")?;
+                if safe
+                    && matches!(objtype, ObjType::Blob)
+                    && start.contains(&0)
+                    && is_terminal(STDOUT_FD)
+                {
+                    eprintln!("warning: binary object, use --raw to dump");
+                    return Ok(());
+                }
+                let mut content =
+                    Read::chain(&start[..], (&mut reader).take((objsz - start.len()) as u64));
+                // otherwise it's stdout that failed, e.g. a pager that's quit
+                let copied = std::io::copy(&mut content, &mut std::io::stdout()).map_err(|e| {
+                    match e.kind() {
+                        std::io::ErrorKind::InvalidData => GitError::Corrupt(e.to_string()),
+                        _ => GitError::Quiet,
+                    }
+                })?;
+                let trailing = reader.fill_buf().map_or(true, |b| !b.is_empty());
+                if copied != objsz as u64 || trailing {
+                    return Err(GitError::Corrupt("object corrupt, size mismatch".into()));
+                }
             }
         }
         Commands::HashObject {
             write: do_write,
-            file: infilepath,
-        } => match hash_object(&infilepath, do_write) {
-            Ok(hash) => {
-                let hex_hash = hex::encode(hash);
-                println!("{}", hex_hash);
-                ExitCode::SUCCESS
+            obj_type,
+            stdin,
+            stdin_paths,
+            literally,
+            force,
+            files,
+        } => {
+            // an unknown type can only be hashed literally
+            let otype = ObjType::from(obj_type.as_bytes());
+            if let (None, false) = (otype, literally) {
+                return Err(GitError::Invalid(format!(
+                    "invalid object type \"{}\"",
+                    obj_type
+                )));
+            }
+            let mut repo = repo.clone();
+            repo.rewrite_objects = force;
+            let db = do_write.then(|| &repo);
+            let autocrlf = converts_crlf(&repo)?;
+            // stdin's content when there's no path
+            let hash_one = |path: Option<&Path>| {
+                // nothing checks that trees, commits and tags are well formed yet, but once
+                // something does, --literally is what skips it
+                let res = match (otype, path) {
+                    (None, _) => {
+                        let content = match path {
+                            Some(path) => std::fs::read(path),
+                            None => {
+                                let mut content = vec![];
+                                std::io::stdin().read_to_end(&mut content).map(|_| content)
+                            }
+                        };
+                        content
+                            .context("reading content for hashing")
+                            .and_then(|content| hash_literally(&obj_type, &content, db))
+                    }
+                    (Some(otype), Some(path)) => hash_object(path, otype, autocrlf, db),
+                    (Some(otype), None) => hash_stdin(otype, db),
+                };
+                // only problems with the database itself are fatal, not ones with a single file
+                let hash = res.map_err(|e| match e.downcast::<GitError>() {
+                    Ok(e) => e,
+                    Err(e) => GitError::Failed(e.to_string()),
+                })?;
+                println!("{}", hex::encode(hash));
+                Ok::<(), GitError>(())
+            };
+
+            if stdin {
+                hash_one(None)?;
             }
-            Err(e) => {
-                println!("error: {}", e);
-                ExitCode::FAILURE
+            for file in &files {
+                hash_one(Some(Path::new(file)))?;
             }
-        },
+            // each is hashed as soon as it's read, so a caller can feed names through a pipe and
+            // read back hashes as it goes
+            if stdin_paths {
+                for line in std::io::stdin().lock().lines() {
+                    let line = line.context("reading paths from stdin")?;
+                    hash_one(Some(&Path::new(&cwd_prefix).join(line)))?;
+                }
+            }
+        }
         Commands::LsTree {
             name_only,
+            object_only,
+            recursive,
+            long,
+            dirs_only,
+            null_terminate,
+            abbrev,
+            format,
             tree_ish,
         } => {
-            let obj_path = obj_path_from_sha(&tree_ish);
-            if let Ok(objfile) = File::open(obj_path) {
-                match object_decoder(objfile) {
-                    (ObjType::Tree, _objsz, mut reader) => {
-                        let mut tree_ents: Vec<TreeEntry> = vec![];
-                        let mut pnbuf = vec![];
-                        loop {
-                            let mode: TreeObjMode;
-                            let otype: ObjType;
-
-                            match reader.read_until(b' ', &mut pnbuf) {
-                                Ok(0) => {
-                                    // EOF
-                                    break;
-                                }
-                                Ok(_nbytes) => {
-                                    mode = TreeObjMode::from(&pnbuf);
-                                    otype = match mode {
-                                        TreeObjMode::Directory => ObjType::Tree,
-                                        TreeObjMode::RegularFile
-                                        | TreeObjMode::ExecutableFile
-                                        | TreeObjMode::Link => ObjType::Blob,
-                                    };
-                                }
-                                Err(e) => {
-                                    panic!("failed to read next tree entry up to the NUL separator before its sha: {}", e);
-                                }
-                            };
-                            pnbuf.clear();
-
-                            let name: String = match reader.read_until(b'\0', &mut pnbuf) {
-                                Ok(0) => {
-                                    // EOF
-                                    break;
-                                }
-                                Ok(_nbytes) => {
-                                    pnbuf.pop();
-                                    String::from_utf8_lossy(&pnbuf).into()
-                                }
-                                Err(e) => {
-                                    panic!("failed to read the name after the tree entry's permissions: {}", e);
-                                }
-                            };
-                            pnbuf.clear();
-
-                            let mut hash = [0u8; 20];
-                            reader
-                                .read_exact(&mut hash)
-                                .expect("20 bytes after mode+name for the hash");
-
-                            let ent = TreeEntry {
-                                mode,
-                                otype,
-                                name,
-                                hash,
-                            };
-                            tree_ents.push(ent);
-                        }
+            let not_found = || GitError::NotFound(format!("Not a valid object name {}", tree_ish));
+            let mut sha = rev_parse(repo, &tree_ish)?.ok_or_else(not_found)?;
+            // a commit, or a tag of one, stands for its tree
+            let tree_ents = loop {
+                match GitObject::load(repo, &sha)? {
+                    Some(GitObject::Tree(ents)) => break ents,
+                    Some(GitObject::Commit(commit)) => sha = hex::encode(commit.tree),
+                    Some(GitObject::Tag(tag)) => sha = hex::encode(tag.object),
+                    Some(obj) => {
+                        return Err(GitError::BadType(format!(
+                            "not a tree object (found {})",
+                            obj.obj_type().type_name()
+                        )))
+                    }
+                    None => return Err(not_found()),
+                }
+            };
+            let tree_ents = if recursive {
+                flatten_tree(repo, tree_ents, b"", dirs_only)
+                    .map_err(|e| GitError::Corrupt(e.to_string()))?
+            } else {
+                tree_ents
+            };
+            let tree_ents = tree_ents
+                .into_iter()
+                .filter(|ent| !dirs_only || matches!(ent.mode, TreeObjMode::Directory));
 
-                        if name_only {
-                            for ent in tree_ents {
-                                println!("{}", ent.name);
+            // -z keeps names containing newlines unambiguous for scripts, so they're left as they
+            // are rather than quoted
+            let term = if null_terminate { b'\0' } else { b'\n' };
+            let quote_high = repo
+                .config
+                .get::<bool>("core", "quotepath")?
+                .unwrap_or(true);
+            let shown_name = |ent: &TreeEntry| {
+                if null_terminate {
+                    ent.name.clone()
+                } else {
+                    quote_path(&ent.name, quote_high)
+                }
+            };
+            let fields = format.as_deref().map(parse_ls_tree_format).transpose()?;
+            let mut stdout = std::io::stdout();
+            let mut sizes = HashMap::new();
+            for ent in tree_ents {
+                let mut line = vec![];
+                if name_only {
+                    line.extend_from_slice(&shown_name(&ent));
+                } else if object_only {
+                    line.extend_from_slice(abbrev_hash(&ent.hash, abbrev).as_bytes());
+                } else if let Some(fields) = &fields {
+                    for field in fields {
+                        let text = match field {
+                            LsTreeField::Literal(text) => {
+                                line.extend_from_slice(text);
+                                continue;
                             }
-                        } else {
-                            for ent in tree_ents {
-                                println!("{}", ent);
+                            LsTreeField::Mode => ent.mode.to_string(),
+                            LsTreeField::Type => ent.otype.type_name().to_string(),
+                            LsTreeField::Name => abbrev_hash(&ent.hash, abbrev),
+                            LsTreeField::Size { padded: false } => {
+                                entry_size(repo, &ent, &mut sizes)?
                             }
-                        }
-                    }
-                    (objt, _, _) => {
-                        println!("fatal: not a tree object (found {})", objt.type_name());
-                        return ret_bad_file;
+                            LsTreeField::Size { padded: true } => {
+                                format!("{:>7}", entry_size(repo, &ent, &mut sizes)?)
+                            }
+                            LsTreeField::Path => {
+                                line.extend_from_slice(&shown_name(&ent));
+                                continue;
+                            }
+                        };
+                        line.extend_from_slice(text.as_bytes());
                     }
+                } else if long {
+                    let size = entry_size(repo, &ent, &mut sizes)?;
+                    line.extend_from_slice(
+                        format!(
+                            "{} {} {} {:>7}\t",
+                            ent.mode,
+                            ent.otype.type_name(),
+                            abbrev_hash(&ent.hash, abbrev),
+                            size,
+                        )
+                        .as_bytes(),
+                    );
+                    line.extend_from_slice(&shown_name(&ent));
+                } else {
+                    line.extend_from_slice(ent.ls_prefix(abbrev).as_bytes());
+                    line.extend_from_slice(&shown_name(&ent));
                 }
+                line.push(term);
+                stdout.write_all(&line).map_err(|_| GitError::Quiet)?;
             }
-
-            ExitCode::SUCCESS
+            stdout.flush().expect("to flush stdout");
         }
-        Commands::WriteTree => {
-            let cur_dir = std::env::current_dir().expect("read cwd");
-            let git_dir = {
-                let mut d = cur_dir.clone();
-                d.push(".git");
-                d
+        Commands::DiffTree {
+            recursive,
+            old,
+            new,
+        } => {
+            let old_tree = peel_to_tree(repo, &old)?;
+            let new_tree = peel_to_tree(repo, &new)?;
+            diff_trees(
+                repo,
+                &old_tree,
+                &new_tree,
+                b"",
+                recursive,
+                &mut |name, old, new| print_raw_change(repo, name, old, new),
+            )?;
+        }
+        Commands::FsckObject { obj_sha } => {
+            let obj_sha =
+                resolve_obj_sha(repo, &obj_sha).map_err(|e| GitError::NotFound(e.to_string()))?;
+            match verify_object(repo, &obj_sha)? {
+                Some(true) => {}
+                Some(false) => {
+                    return Err(GitError::Failed(format!("object {} is corrupt", obj_sha)))
+                }
+                None => {
+                    return Err(GitError::NotFound(format!(
+                        "Not a valid object name {}",
+                        obj_sha
+                    )))
+                }
+            }
+        }
+        Commands::CountObjects { verbose } => count_objects(repo, verbose)?,
+        Commands::UpdateIndex { add, file } => stage_file(repo, &file, add)?,
+        Commands::LsFiles { stage } => {
+            let index = index::Index::read(repo.path("index"))?;
+            // like git, only what's under the directory we were run from
+            let dir_prefix = if cwd_prefix.is_empty() {
+                String::new()
+            } else {
+                format!("{}/", cwd_prefix)
             };
-            assert!(
-                git_dir.exists() && git_dir.is_dir(),
-                "expect to be run in directory with .git"
-            );
-
-            let tree = write_tree_recursive(&cur_dir);
-            let hash = hash_tree(tree).expect("to insert a tree object for the current dir");
-
-            println!("{}", hex::encode(hash));
-
-            ExitCode::SUCCESS
+            for ent in &index.entries {
+                let name = match ent.name.strip_prefix(&dir_prefix) {
+                    Some(name) => name,
+                    None => continue,
+                };
+                if stage {
+                    println!(
+                        "{:06o} {} {}\t{}",
+                        ent.mode,
+                        hex::encode(ent.hash),
+                        ent.stage(),
+                        name
+                    );
+                } else {
+                    println!("{}", name);
+                }
+            }
+        }
+        Commands::WriteTree {
+            from_worktree,
+            prefix,
+            dry_run,
+            verify,
+            progress,
+        } => {
+            let progress = progress || is_terminal(STDERR_FD);
+            let hash =
+                write_current_tree(repo, from_worktree, prefix.as_deref(), !dry_run, progress)?;
+            let sha = hex::encode(hash);
+            // reading the tree back catches it being stored as anything but what hashes to its name
+            let problem = if verify {
+                match verify_object(repo, &sha)? {
+                    Some(true) => None,
+                    Some(false) => Some("does not hash back to its name"),
+                    None => Some("could not be read back"),
+                }
+            } else {
+                None
+            };
+            if let Some(problem) = problem {
+                return Err(GitError::Corrupt(format!(
+                    "written tree {} {}",
+                    sha, problem
+                )));
+            }
+            println!("{}", sha);
         }
         Commands::CommitTree {
             tree_sha,
             parent_sha,
             message,
         } => {
-            assert_eq!(parent_sha.len(), 40, "parent commit sha must be 20 hex chars");
-            assert_eq!(tree_sha.len(), 40, "tree sha must be 20 hex chars");
-
-            let mut tree = [0u8; 20];
-            hex::decode_to_slice(tree_sha, &mut tree).expect("could not decode tree sha");
-            let mut parent = [0u8; 20];
-            hex::decode_to_slice(parent_sha, &mut parent).expect("could not decode parent sha");
-
-            let c = Commit {
-                author_name: "Test User <test@user.net>".to_string(),
-                author_timestamp: SystemTime::now(),
-                committer_name: "Test User <test@user.net>".to_string(),
-                committer_timestamp: SystemTime::now(),
-                tree,
-                parent,
-                message,
-            };
+            for sha in std::iter::once(&tree_sha).chain(parent_sha.iter()) {
+                if !is_plausibly_obj_sha(sha) {
+                    return Err(GitError::NotFound(format!(
+                        "Not a valid object name {}",
+                        sha
+                    )));
+                }
+            }
 
-            let hash = hash_commit(&c).expect("failed creating the commit object");
+            let tree = decode_sha(&tree_sha)?;
+            let parents = parent_sha
+                .iter()
+                .map(|sha| decode_sha(sha))
+                .collect::<Result<_>>()?;
 
+            let c = Commit::new(repo, tree, parents, message)?;
+            let hash = hash_commit(repo, &c).context("creating the commit object")?;
+            println!("{}", hex::encode(hash));
+        }
+        Commands::RevParse {
+            verify,
+            short,
+            abbrev_ref,
+            rev,
+        } => {
+            let verify = verify || short.is_some();
+            let found = rev_parse(repo, &rev).and_then(|sha| match sha {
+                // only verifying checks the object is really there
+                Some(sha) if verify && open_object(repo, &sha)?.is_none() => Ok(None),
+                sha => Ok(sha),
+            });
+            let found = match (found, short) {
+                (Ok(Some(sha)), Some(digits)) => unique_abbrev(repo, &sha, digits).map(Some),
+                (found, _) => found,
+            };
+            match found {
+                // a rev that's no ref, like a sha, has no name to show
+                Ok(Some(_)) if abbrev_ref => {
+                    if let Some(name) = refs::abbrev_ref(repo, &rev)? {
+                        println!("{}", name);
+                    }
+                }
+                Ok(Some(sha)) => println!("{}", sha),
+                Ok(None) | Err(_) if verify => {
+                    return Err(GitError::NotFound("Needed a single revision".into()))
+                }
+                Ok(None) => {
+                    return Err(GitError::NotFound(format!(
+                    "ambiguous argument '{}': unknown revision or path not in the working tree.",
+                    rev
+                )))
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Commands::UpdateRef {
+            delete,
+            message,
+            ref_name,
+            new_value,
+        } => {
+            if delete {
+                refs::delete_ref(repo, &ref_name)?
+            } else {
+                update_ref(
+                    repo,
+                    &ref_name,
+                    &new_value.expect("clap to require a new value"),
+                    message.as_deref().unwrap_or_default(),
+                )?
+            }
+        }
+        Commands::ShowRef { heads, tags } => {
+            let mut shown = false;
+            for (refname, sha) in refs::list_refs(repo)? {
+                let wanted = (!heads && !tags)
+                    || (heads && refname.starts_with("refs/heads/"))
+                    || (tags && refname.starts_with("refs/tags/"));
+                if wanted {
+                    println!("{} {}", sha, refname);
+                    shown = true;
+                }
+            }
+            // like git, finding nothing to show is a failure
+            if !shown {
+                return Err(GitError::Quiet);
+            }
+        }
+        Commands::SymbolicRef { name, value: None } => {
+            let target = refs::symref_target(repo, &name)?;
+            if target == name {
+                return Err(GitError::Invalid(format!(
+                    "ref {} is not a symbolic ref",
+                    name
+                )));
+            }
+            println!("{}", target);
+        }
+        Commands::SymbolicRef {
+            name,
+            value: Some(value),
+        } => refs::write_symref(repo, &name, &value)?,
+        Commands::Show { object } => {
+            let sha = rev_parse(repo, &object)?.ok_or_else(|| {
+                GitError::NotFound(format!(
+                    "ambiguous argument '{}': unknown revision or path not in the working tree.\n\
+                    Use '--' to separate paths from revisions, like this:\n\
+                    'git <command> [<revision>...] -- [<file>...]'",
+                    object
+                ))
+            })?;
+            show(repo, &object, &sha)?;
+        }
+        Commands::Log { max_count } => match rev_parse(repo, "HEAD")? {
+            Some(head) => print_log(repo, &head, max_count)?,
+            None => {
+                return Err(GitError::NotFound(match refs::unborn_branch(repo)? {
+                    Some(branch) => format!(
+                        "your current branch '{}' does not have any commits yet",
+                        branch
+                    ),
+                    None => "bad default revision 'HEAD'".into(),
+                }))
+            }
+        },
+        Commands::Branch { delete, name } => match name {
+            Some(name) if delete => delete_branch(repo, &name)?,
+            Some(name) => create_branch(repo, &name)?,
+            None if delete => return Err(GitError::Invalid("branch name required".into())),
+            None => list_branches(repo)?,
+        },
+        Commands::MergeBase { a, b } => {
+            let a = peel_to_commit(repo, &a)?;
+            let b = peel_to_commit(repo, &b)?;
+            match merge_bases(repo, &a, &b)?.first() {
+                Some(base) => println!("{}", base),
+                // unrelated histories, which git answers with just the exit status
+                None => return Err(GitError::Quiet),
+            }
+        }
+        Commands::Tag {
+            annotate,
+            message,
+            tag_name,
+            object,
+        } => match tag_name {
+            Some(tag_name) => create_tag(repo, &tag_name, &object, annotate, message)?,
+            None => {
+                for refname in refs::list_refs(repo)?.into_keys() {
+                    if let Some(tag) = refname.strip_prefix("refs/tags/") {
+                        println!("{}", tag);
+                    }
+                }
+            }
+        },
+        Commands::IndexPack { fix_thin, pack } => {
+            if !pack.ends_with(".pack") {
+                return Err(GitError::Invalid(format!(
+                    "packfile name '{}' does not end with '.pack'",
+                    pack
+                )));
+            }
+            let checksum = pack::index_pack(Path::new(&pack), fix_thin.then(|| repo), false)?;
+            println!("{}", hex::encode(checksum));
+        }
+        Commands::UnpackObjects { pack } => pack::unpack_objects(repo, Path::new(&pack))?,
+        Commands::Clone {
+            depth,
+            progress,
+            url,
+            dir,
+        } => {
+            let progress = progress || is_terminal(STDERR_FD);
+            clone_repo(&url, dir.as_deref(), depth, progress)?
+        }
+        Commands::Status => print_status(repo, &cwd_prefix)?,
+        Commands::Checkout { force, commit } => checkout(repo, &commit, force)?,
+        Commands::Reflog { refname } => {
+            let full_name = refs::reflog_ref(repo, &refname)?.ok_or_else(|| {
+                GitError::NotFound(format!(
+                    "ambiguous argument '{}': unknown revision or path not in the working tree.\n\
+                    Use '--' to separate paths from revisions, like this:\n\
+                    'git <command> [<revision>...] -- [<file>...]'",
+                    refname
+                ))
+            })?;
+            // newest first, numbered the way `<ref>@{n}` counts back
+            for (n, (sha, message)) in refs::read_reflog(repo, &full_name)?
+                .iter()
+                .rev()
+                .enumerate()
+            {
+                println!(
+                    "{} {}@{{{}}}: {}",
+                    unique_abbrev(repo, sha, 7)?,
+                    refname,
+                    n,
+                    message
+                );
+            }
+        }
+        Commands::Commit { message } => {
+            let tree = write_current_tree(repo, false, None, true, false)?;
+            let hash = commit_to_head(repo, tree, message)?;
             println!("{}", hex::encode(hash));
+        }
+    }
+    Ok(())
+}
+
+// only init, clone, indexing a pack and hashing without writing work outside of a repository
+fn needs_repo(command: &Commands) -> bool {
+    !matches!(
+        command,
+        Commands::Init { .. }
+            | Commands::Clone { .. }
+            | Commands::IndexPack {
+                fix_thin: false,
+                ..
+            }
+            | Commands::HashObject { write: false, .. }
+    )
+}
 
-            ExitCode::SUCCESS
+// where `file`, named relative to `cur_dir`, is within the worktree at `top`
+fn worktree_path(top: &Path, cur_dir: &Path, file: &str) -> Result<String> {
+    let mut path = PathBuf::new();
+    for component in cur_dir.join(file).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                path.pop();
+            }
+            other => path.push(other),
         }
     }
+    let rel_path = path
+        .strip_prefix(top)
+        .ok()
+        .filter(|p| !p.as_os_str().is_empty())
+        .with_context(|| {
+            format!(
+                "'{}' is outside repository at '{}'",
+                file,
+                top.to_string_lossy()
+            )
+        })?;
+    Ok(rel_path.to_string_lossy().into_owned())
 }
 
+fn is_valid_branch_name(name: &str) -> bool {
+    // a subset of the rules from git-check-ref-format(1)
+    !name.is_empty()
+        && !name.starts_with('/')
+        && !name.ends_with('/')
+        && !name.starts_with('-')
+        && !name.ends_with('.')
+        && !name.ends_with(".lock")
+        && !name.contains("..")
+        && !name.contains("//")
+        && !name.contains("@{")
+        && name != "@"
+        && !name.split('/').any(|part| part.starts_with('.'))
+        && !name
+            .chars()
+            .any(|c| c.is_ascii_control() || " ~^:?*[\\".contains(c))
+}
 
-fn hash_object<P: AsRef<Path>>(path: P, do_write: bool) -> Result<[u8; 20]> {
-    let mut infile = File::open(path).context("opening file for hashing")?;
-    let hash = hash_file(&infile)?;
-    let hex_hash = hex::encode(hash);
+// anything already there is left alone, so this is safe to rerun on an existing repository
+fn create_git_dir(git_dir: &Path, bare: bool, initial_branch: &str) -> std::io::Result<()> {
+    std::fs::create_dir_all(git_dir.join("objects"))?;
+    std::fs::create_dir_all(git_dir.join("refs"))?;
+    if !git_dir.join("HEAD").exists() {
+        let head = format!("ref: refs/heads/{}\n", initial_branch);
+        std::fs::write(git_dir.join("HEAD"), head)?;
+    }
+    if !git_dir.join("config").exists() {
+        std::fs::write(git_dir.join("config"), default_config(bare))?;
+    }
+    Ok(())
+}
+
+fn default_config(bare: bool) -> String {
+    let mut config = String::from("[core]\n\trepositoryformatversion = 0\n\tfilemode = true\n");
+    if bare {
+        config.push_str("\tbare = true\n");
+    } else {
+        config.push_str("\tbare = false\n\tlogallrefupdates = true\n");
+    }
+    config
+}
+
+// clones over smart HTTP into `dir`, or a directory named after the repository, cleaning up
+// after itself if anything goes wrong
+fn clone_repo(url: &str, dir: Option<&str>, depth: Option<u32>, progress: bool) -> Result<()> {
+    ensure!(depth != Some(0), "depth 0 is not a positive number");
+    let dir = match dir {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            let name = url.trim_end_matches('/').rsplit('/').next().unwrap_or(url);
+            let name = name.strip_suffix(".git").unwrap_or(name);
+            ensure!(
+                !name.is_empty() && name.contains(|c| c != '.'),
+                "could not guess directory name from '{}'; please specify a directory",
+                url
+            );
+            PathBuf::from(name)
+        }
+    };
+    let existed = dir.exists();
+    ensure!(
+        !existed
+            || dir
+                .read_dir()
+                .map_or(false, |mut ents| ents.next().is_none()),
+        "destination path '{}' already exists and is not an empty directory.",
+        dir.to_string_lossy()
+    );
+    println!("Cloning into '{}'...", dir.to_string_lossy());
 
-    if do_write {
-        let obj_db_path = obj_path_from_sha(&hex_hash);
+    std::fs::create_dir_all(&dir)
+        .with_context(|| format!("could not create work tree dir '{}'", dir.to_string_lossy()))?;
+    let cloned = clone_into(url, &dir, depth, progress);
+    if cloned.is_err() {
+        let _ = std::fs::remove_dir_all(&dir);
+        if existed {
+            let _ = std::fs::create_dir(&dir);
+        }
+    }
+    cloned
+}
 
-        if !obj_db_path.exists() {
-            infile
-                .rewind()
-                .expect("start reading given file from beginning to copy into obj db");
+fn clone_into(url: &str, top: &Path, depth: Option<u32>, show_progress: bool) -> Result<()> {
+    let remote = remote::Remote::new(url)?;
+    let adv = remote.discover_refs()?;
 
-            let filesz = infile
-                .metadata()
-                .context("get input file metadata, for size")?
-                .len();
+    // a shallow clone only follows the default branch, as tags and other branches would mostly
+    // point into history it doesn't have
+    let single_branch = match depth {
+        Some(_) => adv.head_branch(),
+        None => None,
+    };
+    let repo = Repo::new(top.join(".git"));
+    create_git_dir(&repo.git_dir, false, "master").context("creating the git dir")?;
+    let mut config = OpenOptions::new()
+        .append(true)
+        .open(repo.path("config"))
+        .context("opening config")?;
+    let refspec = match single_branch.and_then(|b| b.strip_prefix("refs/heads/")) {
+        Some(branch) => format!("+refs/heads/{0}:refs/remotes/origin/{0}", branch),
+        None => "+refs/heads/*:refs/remotes/origin/*".to_string(),
+    };
+    write!(
+        config,
+        "[remote \"origin\"]\n\turl = {}\n\tfetch = {}\n",
+        url, refspec
+    )
+    .context("writing config")?;
 
-            encode_object(ObjType::Blob, &mut infile, filesz, obj_db_path)
-                .context("encoding object into database")?;
+    // peeled tags (`^{}`) are just the server being helpful about what a tag points at
+    let refs: Vec<&(String, String)> = adv
+        .refs
+        .iter()
+        .filter(|(name, _)| match (depth, single_branch) {
+            (Some(_), Some(branch)) => name == "HEAD" || name == branch,
+            (Some(_), None) => name == "HEAD",
+            (None, _) => {
+                (name == "HEAD"
+                    || name.starts_with("refs/heads/")
+                    || name.starts_with("refs/tags/"))
+                    && !name.ends_with("^{}")
+            }
+        })
+        .collect();
+    if refs.is_empty() {
+        println!("warning: You appear to have cloned an empty repository.");
+        return Ok(());
+    }
+    let mut wants: Vec<String> = vec![];
+    for (_, sha) in &refs {
+        if !wants.contains(sha) {
+            wants.push(sha.clone());
         }
     }
 
-    Ok(hash)
+    // the pack's name comes from its checksum, which is only known once it's all arrived
+    let pack_dir = repo.objects_dir().join("pack");
+    std::fs::create_dir_all(&pack_dir).context("creating pack dir")?;
+    let tmp_pack = pack_dir.join(format!("tmp_pack_{}.pack", std::process::id()));
+    let mut pack_file = std::io::BufWriter::new(
+        File::create(&tmp_pack).context("creating temp file for packfile")?,
+    );
+    let progress = Progress::bytes("Receiving objects", show_progress);
+    let receiving = progress::ProgressWriter {
+        inner: &mut pack_file,
+        progress: &progress,
+    };
+    let mut shallow = remote.fetch_pack(&adv, &wants, depth, receiving)?;
+    pack_file.flush().context("writing packfile")?;
+    progress.finish();
+    drop(pack_file);
+    let checksum = pack::index_pack(&tmp_pack, Some(&repo), show_progress)?;
+    let pack_path = pack_dir.join(format!("pack-{}.pack", hex::encode(checksum)));
+    std::fs::rename(
+        tmp_pack.with_extension("idx"),
+        pack_path.with_extension("idx"),
+    )
+    .context("moving pack index into place")?;
+    std::fs::rename(&tmp_pack, &pack_path).context("moving packfile into place")?;
+    if !shallow.is_empty() {
+        shallow.sort();
+        let mut contents = shallow.join("\n");
+        contents.push('\n');
+        std::fs::write(repo.path("shallow"), contents).context("writing shallow file")?;
+    }
+
+    // branches are tracked as the remote's, and only the one HEAD is on gets a local branch
+    for (name, sha) in &refs {
+        if let Some(branch) = name.strip_prefix("refs/heads/") {
+            refs::write_ref(&repo, &format!("refs/remotes/origin/{}", branch), sha)?;
+        } else if name.starts_with("refs/tags/") {
+            refs::write_ref(&repo, name, sha)?;
+        }
+    }
+    let head = match refs.iter().find(|(name, _)| name == "HEAD") {
+        Some((_, sha)) => sha,
+        None => {
+            println!("warning: remote HEAD refers to nonexistent ref, unable to checkout");
+            return Ok(());
+        }
+    };
+    let log_message = format!("clone: from {}", url);
+    match adv.head_branch() {
+        Some(head_ref) if head_ref.starts_with("refs/heads/") => {
+            let branch = &head_ref["refs/heads/".len()..];
+            refs::write_symref(&repo, "HEAD", head_ref)?;
+            move_ref(&repo, head_ref, head, &log_message)?;
+            refs::write_symref(
+                &repo,
+                "refs/remotes/origin/HEAD",
+                &format!("refs/remotes/origin/{}", branch),
+            )?;
+            write!(
+                config,
+                "[branch \"{}\"]\n\tremote = origin\n\tmerge = {}\n",
+                branch, head_ref
+            )
+            .context("writing config")?;
+        }
+        // without being told which branch, all that can be done is detach at the same commit
+        _ => move_ref(&repo, "HEAD", head, &log_message)?,
+    }
+
+    let commit = read_commit(&repo, head)?;
+    checkout_tree(&repo, top, commit.tree)
 }
 
-fn is_plausibly_obj_sha(maybe_obj_sha: &str) -> bool {
-    maybe_obj_sha.len() == 40 && maybe_obj_sha.chars().all(|c| c.is_ascii_hexdigit())
+// switches the worktree, index and HEAD over to `name`, a branch or anything else naming a
+// commit; unless forced, local changes to tracked files and untracked files in the way stop it
+// rather than being thrown away
+fn checkout(repo: &Repo, name: &str, force: bool) -> Result<(), GitError> {
+    if repo.config.get::<bool>("core", "bare")?.unwrap_or(false) {
+        return Err(GitError::Invalid(
+            "this operation must be run in a work tree".into(),
+        ));
+    }
+
+    // a branch wins over a tag of the same name, and checking out HEAD stays on its branch
+    let branch = if name == "HEAD" {
+        refs::symref_target(repo, "HEAD")?
+    } else {
+        format!("refs/heads/{}", name)
+    };
+    let branch_sha = if branch.starts_with("refs/heads/") {
+        refs::read_ref(repo, &branch)?
+    } else {
+        None
+    };
+    let on_branch = branch_sha.is_some();
+    let mut sha = match branch_sha {
+        Some(sha) => sha,
+        None => rev_parse(repo, name)?.ok_or_else(|| {
+            GitError::Failed(format!(
+                "pathspec '{}' did not match any file(s) known to git",
+                name
+            ))
+        })?,
+    };
+    let commit = loop {
+        match GitObject::load(repo, &sha)? {
+            Some(GitObject::Commit(commit)) => break commit,
+            Some(GitObject::Tag(tag)) => sha = hex::encode(tag.object),
+            _ => {
+                return Err(GitError::BadType(format!(
+                    "reference is not a tree: {}",
+                    name
+                )))
+            }
+        }
+    };
+
+    let index = index::Index::read(repo.path("index"))?;
+    let tree_sha = hex::encode(commit.tree);
+    let new_tree = flatten_tree(repo, read_tree(repo, &tree_sha)?, b"", false)?;
+    if !force {
+        let changes = tracked_changes(repo, &index)?;
+        if !changes.is_empty() {
+            let files: Vec<String> = changes.keys().map(|name| format!("\t{}", name)).collect();
+            return Err(GitError::Failed(format!(
+                "Your local changes to the following files would be overwritten by checkout:\n\
+                {}\n\
+                Please commit your changes or stash them before you switch branches.\n\
+                Aborting",
+                files.join("\n")
+            )));
+        }
+
+        // anything in the way that isn't tracked has nowhere else it's kept; a submodule's
+        // directory is only ever created, never replaced
+        let tracked: HashSet<&[u8]> = index
+            .entries
+            .iter()
+            .map(|ent| ent.name.as_bytes())
+            .collect();
+        let files: Vec<String> = new_tree
+            .iter()
+            .filter(|ent| !matches!(ent.mode, TreeObjMode::Gitlink))
+            .filter(|ent| !tracked.contains(ent.name.as_slice()))
+            .filter(|ent| std::fs::symlink_metadata(name_path(&ent.name)).is_ok())
+            .map(|ent| format!("\t{}", String::from_utf8_lossy(&ent.name)))
+            .collect();
+        if !files.is_empty() {
+            return Err(GitError::Failed(format!(
+                "The following untracked working tree files would be overwritten by checkout:\n\
+                {}\n\
+                Please move or remove them before you switch branches.\n\
+                Aborting",
+                files.join("\n")
+            )));
+        }
+    }
+
+    // tracked files that the new tree doesn't have go, along with directories left empty
+    let kept: HashSet<&[u8]> = new_tree.iter().map(|ent| ent.name.as_slice()).collect();
+    for ent in &index.entries {
+        if !kept.contains(ent.name.as_bytes()) {
+            remove_worktree_file(&ent.name)?;
+        }
+    }
+    checkout_tree(repo, Path::new("."), commit.tree)?;
+
+    // HEAD's log says where it moved from, by branch name or else the commit it was detached at
+    let old_head = refs::read_ref(repo, "HEAD")?;
+    let moved_from = match refs::symref_target(repo, "HEAD")? {
+        head if head == "HEAD" => old_head.clone().unwrap_or_default(),
+        head => head.trim_start_matches("refs/heads/").to_string(),
+    };
+    let branch_name = branch.trim_start_matches("refs/heads/");
+    if on_branch {
+        let already_on = refs::symref_target(repo, "HEAD")? == branch;
+        refs::write_symref(repo, "HEAD", &branch)?;
+        if already_on {
+            println!("Already on '{}'", branch_name);
+        } else {
+            println!("Switched to branch '{}'", branch_name);
+        }
+    } else {
+        refs::write_ref(repo, "HEAD", &sha)?;
+        let subject = commit.message.lines().next().unwrap_or_default();
+        println!(
+            "HEAD is now at {} {}",
+            unique_abbrev(repo, &sha, 7)?,
+            subject
+        );
+    }
+    let who = ident(repo, "COMMITTER")?.to_string();
+    refs::append_reflog(
+        repo,
+        "HEAD",
+        old_head.as_deref(),
+        &sha,
+        &who,
+        &format!("checkout: moving from {} to {}", moved_from, name),
+    )?;
+    Ok(())
 }
 
-fn obj_path_from_sha(obj_sha: &str) -> PathBuf {
-    let (obj_dirname, obj_filename) = obj_sha.split_at(2);
-    [".git", "objects", obj_dirname, obj_filename]
-        .iter()
-        .collect()
+fn remove_worktree_file(name: &str) -> Result<()> {
+    let path = Path::new(name);
+    match std::fs::symlink_metadata(path) {
+        // submodules are just an empty directory
+        Ok(meta) if meta.is_dir() => {
+            let _ = std::fs::remove_dir(path);
+        }
+        Ok(_) => std::fs::remove_file(path).with_context(|| format!("removing {}", name))?,
+        Err(_) => {}
+    }
+    for dir in path.ancestors().skip(1) {
+        if dir.as_os_str().is_empty() || std::fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+    Ok(())
 }
 
-fn hash_file(mut f: &File) -> Result<[u8; 20]> {
-    use sha1::{Digest, Sha1};
+// writes out the files of `tree` into a fresh worktree at `top` and stages them, leaving things
+// as if they'd just been committed
+fn checkout_tree(repo: &Repo, top: &Path, tree: [u8; 20]) -> Result<()> {
+    let tree_sha = hex::encode(tree);
+    let tree_ents = match open_object(repo, &tree_sha)? {
+        Some((ObjType::Tree, _objsz, mut reader)) => {
+            parse_tree(&mut reader).with_context(|| format!("corrupt tree object {}", tree_sha))?
+        }
+        _ => bail!("unable to read tree {}", tree_sha),
+    };
 
-    let mut hasher = Sha1::new_with_prefix("blob ");
-    let filesz = f.metadata().unwrap().len();
-    hasher.update(filesz.to_string());
-    hasher.update([0u8]);
-    let mut buf = [0u8; 1024];
-    let mut bytes_read = f.read(&mut buf).context("read given file for hashing")?;
-    while bytes_read > 0 {
-        hasher.update(&buf[..bytes_read]);
-        bytes_read = f.read(&mut buf).context("read given file for hashing")?;
+    let mut index = index::Index { entries: vec![] };
+    for ent in flatten_tree(repo, tree_ents, b"", false)? {
+        // the index only holds names that are utf-8
+        let name = String::from_utf8_lossy(&ent.name).into_owned();
+        let path = top.join(name_path(&ent.name));
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("creating directories for {}", name))?;
+        }
+
+        // submodules aren't cloned along with their superproject, just given an empty directory
+        if let TreeObjMode::Gitlink = ent.mode {
+            std::fs::create_dir_all(&path)
+                .with_context(|| format!("creating submodule dir {}", name))?;
+            let meta = std::fs::symlink_metadata(&path).context("reading checked out file")?;
+            let mut entry = index::IndexEntry::from_metadata(name, &meta, ent.hash);
+            entry.mode = 0o160000;
+            index.add(entry);
+            continue;
+        }
+
+        // whatever's there already is replaced, which checkout only lets happen to untracked files
+        // when forced, though never to a directory that's still in use
+        if let Ok(meta) = std::fs::symlink_metadata(&path) {
+            ensure!(
+                !meta.is_dir(),
+                "untracked working tree files in {} would be overwritten",
+                name
+            );
+            std::fs::remove_file(&path).with_context(|| format!("replacing {}", name))?;
+        }
+
+        let hex_hash = hex::encode(ent.hash);
+        let mut reader = match open_object(repo, &hex_hash)? {
+            Some((ObjType::Blob, _objsz, reader)) => reader,
+            _ => bail!("unable to read blob {} for {}", hex_hash, name),
+        };
+        if let TreeObjMode::Link = ent.mode {
+            let mut target = vec![];
+            reader
+                .read_to_end(&mut target)
+                .context("reading symlink target")?;
+            create_symlink(&target, &path).with_context(|| format!("creating symlink {}", name))?;
+        } else {
+            let mut file =
+                File::create(&path).with_context(|| format!("creating file {}", name))?;
+            std::io::copy(&mut reader, &mut file)
+                .with_context(|| format!("writing file {}", name))?;
+            #[cfg(unix)]
+            if let TreeObjMode::ExecutableFile = ent.mode {
+                file.set_permissions(std::fs::Permissions::from_mode(0o755))
+                    .with_context(|| format!("making {} executable", name))?;
+            }
+        }
+        let meta = std::fs::symlink_metadata(&path).context("reading checked out file")?;
+        index.add(index::IndexEntry::from_metadata(name, &meta, ent.hash));
     }
+    index.write(repo.path("index"))
+}
 
-    let mut h = hasher.finalize();
-    Ok(*h.as_mut())
+// one object per line of stdin, without exiting in between so callers can keep a pipe open;
+// names can be anything rev-parse takes, and the resolved sha is echoed back. With
+// `all_objects` it's instead every object there is, in order of sha
+fn cat_file_batch(repo: &Repo, with_contents: bool, all_objects: bool) -> Result<()> {
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    let names: Box<dyn Iterator<Item = Result<String>>> = if all_objects {
+        Box::new(all_object_shas(repo)?.into_iter().map(Ok))
+    } else {
+        Box::new(
+            std::io::stdin()
+                .lock()
+                .lines()
+                .map(|line| line.context("reading object names from stdin")),
+        )
+    };
+    for line in names {
+        let line = line?;
+        let name = line.trim();
+        let found = match rev_parse(repo, name) {
+            Ok(Some(sha)) => open_object(repo, &sha)?.map(|obj| (sha, obj)),
+            _ => None,
+        };
+        match found {
+            Some((sha, (objtype, objsz, mut reader))) => {
+                // a reader that's gone away, like `head` having seen enough, just ends the output
+                writeln!(out, "{} {} {}", sha, objtype.type_name(), objsz)
+                    .map_err(|_| GitError::Quiet)?;
+                if with_contents {
+                    // exactly the size just printed, which is all a reader has to go on, so a
+                    // corrupt object mustn't send more or less
+                    let mut content = (&mut reader).take(objsz as u64);
+                    let mut copied = 0;
+                    loop {
+                        let chunk = content
+                            .fill_buf()
+                            .with_context(|| format!("reading object {}", sha))?;
+                        if chunk.is_empty() {
+                            break;
+                        }
+                        let len = chunk.len();
+                        out.write_all(chunk).map_err(|_| GitError::Quiet)?;
+                        content.consume(len);
+                        copied += len as u64;
+                    }
+                    let trailing = reader.fill_buf().map_or(true, |b| !b.is_empty());
+                    ensure!(
+                        copied == objsz as u64 && !trailing,
+                        "object {} corrupt, size mismatch",
+                        sha
+                    );
+                    writeln!(out).map_err(|_| GitError::Quiet)?;
+                }
+            }
+            None => writeln!(out, "{} missing", name).map_err(|_| GitError::Quiet)?,
+        }
+        out.flush().map_err(|_| GitError::Quiet)?;
+    }
+    Ok(())
+}
+
+// big enough that hashing large files isn't dominated by read syscalls
+const READ_BUF_SIZE: usize = 64 * 1024;
+
+// with `autocrlf`, a blob's CRLF line endings are stored as LF, which changes the length the
+// header needs up front, so then the whole file is read in rather than streamed
+fn hash_object<P: AsRef<Path>>(
+    path: P,
+    otype: ObjType,
+    autocrlf: bool,
+    db: Option<&Repo>,
+) -> Result<[u8; 20]> {
+    if autocrlf && matches!(otype, ObjType::Blob) {
+        let content = std::fs::read(path).context("reading file for hashing")?;
+        return hash_bytes(otype, &crlf_to_lf(content), db);
+    }
+    let file = File::open(path).context("opening file for hashing")?;
+    let len = file
+        .metadata()
+        .context("reading file size for hashing")?
+        .len();
+    hash_stream(
+        otype,
+        BufReader::with_capacity(READ_BUF_SIZE, file),
+        len,
+        db,
+    )
+}
+
+fn hash_stdin(otype: ObjType, db: Option<&Repo>) -> Result<[u8; 20]> {
+    // the header needs the length before any content, so spool stdin to an already-unlinked temp
+    // file instead of holding it all in memory
+    let spool_path = std::env::temp_dir().join(format!(
+        "git_stdin_{}_{}",
+        std::process::id(),
+        TMP_OBJ_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut spool = OpenOptions::new()
+        .create_new(true)
+        .read(true)
+        .write(true)
+        .open(&spool_path)
+        .context("creating temp file for stdin")?;
+    let _ = std::fs::remove_file(&spool_path);
+
+    let len = std::io::copy(&mut std::io::stdin().lock(), &mut spool)
+        .context("reading object content from stdin")?;
+    spool
+        .seek(SeekFrom::Start(0))
+        .context("rewinding stdin temp file")?;
+    hash_stream(
+        otype,
+        BufReader::with_capacity(READ_BUF_SIZE, spool),
+        len,
+        db,
+    )
+}
+
+fn hash_link<P: AsRef<Path>>(path: P, db: Option<&Repo>) -> Result<[u8; 20]> {
+    // a symlink is stored as a blob whose content is the link target
+    let target = std::fs::read_link(path).context("reading symlink target for hashing")?;
+    #[cfg(unix)]
+    let target = target.into_os_string().into_vec();
+    #[cfg(not(unix))]
+    let target = target.to_string_lossy().replace('\\', "/").into_bytes();
+    hash_bytes(ObjType::Blob, &target, db)
+}
+
+// core.autocrlf, as true or input, is what has line endings converted on the way into the object
+// database; there are no .gitattributes to say otherwise yet
+fn converts_crlf(repo: &Repo) -> Result<bool> {
+    match repo.config.get::<bool>("core", "autocrlf") {
+        Ok(enabled) => Ok(enabled.unwrap_or(false)),
+        Err(e) => match repo.config.get::<String>("core", "autocrlf")? {
+            Some(value) if value.eq_ignore_ascii_case("input") => Ok(true),
+            _ => Err(e),
+        },
+    }
+}
+
+// a text file's CRLFs as LFs; git leaves alone anything it takes to be binary, going by the whole
+// file: a NUL, a CR that isn't part of a CRLF, or more than one in 128 bytes being control
+// characters other than the usual whitespace
+fn crlf_to_lf(content: Vec<u8>) -> Vec<u8> {
+    let (mut crlf, mut lone_cr, mut nul, mut printable, mut nonprintable) = (0, 0, 0, 0, 0);
+    let mut bytes = content.iter().peekable();
+    while let Some(&c) = bytes.next() {
+        match c {
+            b'\r' if bytes.peek() == Some(&&b'\n') => {
+                bytes.next();
+                crlf += 1;
+            }
+            b'\r' => lone_cr += 1,
+            b'\n' => {}
+            0 => nul += 1,
+            // backspace, tab, escape and form feed
+            8 | 9 | 27 | 12 => printable += 1,
+            127 | 1..=31 => nonprintable += 1,
+            _ => printable += 1,
+        }
+    }
+    // a DOS end-of-file marker to finish with doesn't count against it
+    if content.last() == Some(&26) {
+        nonprintable -= 1;
+    }
+    if crlf == 0 || lone_cr > 0 || nul > 0 || (printable >> 7) < nonprintable {
+        return content;
+    }
+    let mut converted = Vec::with_capacity(content.len() - crlf);
+    let mut bytes = content.iter().peekable();
+    while let Some(&c) = bytes.next() {
+        if c != b'\r' || bytes.peek() != Some(&&b'\n') {
+            converted.push(c);
+        }
+    }
+    converted
+}
+
+// how much of a blob git looks at when guessing whether it's binary
+const BINARY_CHECK_LEN: usize = 8000;
+
+const STDOUT_FD: i32 = 1;
+const STDERR_FD: i32 = 2;
+
+// std can't tell yet, but libc is linked in regardless
+#[cfg(unix)]
+fn is_terminal(fd: i32) -> bool {
+    extern "C" {
+        fn isatty(fd: i32) -> i32;
+    }
+    unsafe { isatty(fd) == 1 }
+}
+
+#[cfg(not(unix))]
+fn is_terminal(_fd: i32) -> bool {
+    false
+}
+
+// windows has neither exec bits nor symlinks that git would use, so there everything found in
+// the worktree is taken to be a regular file
+#[cfg(unix)]
+fn is_symlink(meta: &std::fs::Metadata) -> bool {
+    meta.file_type().is_symlink()
+}
+
+#[cfg(not(unix))]
+fn is_symlink(_meta: &std::fs::Metadata) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn is_executable(meta: &std::fs::Metadata) -> bool {
+    meta.permissions().mode() & 0o100 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_meta: &std::fs::Metadata) -> bool {
+    false
+}
+
+// like git with core.symlinks off, a link is checked out as a plain file holding its target
+// where there are no symlinks
+#[cfg(unix)]
+fn create_symlink(target: &[u8], path: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(std::ffi::OsStr::from_bytes(target), path)
+}
+
+#[cfg(not(unix))]
+fn create_symlink(target: &[u8], path: &Path) -> std::io::Result<()> {
+    std::fs::write(path, target)
+}
+
+// tree entry names are whatever bytes the filesystem had, which needn't be utf-8
+#[cfg(unix)]
+fn name_bytes(name: &std::ffi::OsStr) -> Vec<u8> {
+    name.as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+fn name_bytes(name: &std::ffi::OsStr) -> Vec<u8> {
+    name.to_string_lossy().into_owned().into_bytes()
+}
+
+#[cfg(unix)]
+fn name_path(name: &[u8]) -> PathBuf {
+    PathBuf::from(std::ffi::OsStr::from_bytes(name))
+}
+
+#[cfg(not(unix))]
+fn name_path(name: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(name).into_owned())
+}
+
+// names are shown the way git does with core.quotePath, in double quotes with C escapes if
+// they have anything unusual in them, bytes outside ascii included unless `quote_high` is off
+fn quote_path(name: &[u8], quote_high: bool) -> Vec<u8> {
+    let needs_quote =
+        |b: u8| b < 0x20 || b == b'"' || b == b'\\' || b == 0x7f || (quote_high && b >= 0x80);
+    if !name.iter().any(|&b| needs_quote(b)) {
+        return name.to_vec();
+    }
+    let mut quoted = vec![b'"'];
+    for &b in name {
+        let escape = match b {
+            0x07 => b'a',
+            0x08 => b'b',
+            b'\t' => b't',
+            b'\n' => b'n',
+            0x0b => b'v',
+            0x0c => b'f',
+            b'\r' => b'r',
+            b'"' | b'\\' => b,
+            _ if needs_quote(b) => {
+                quoted.extend_from_slice(format!("\\{:03o}", b).as_bytes());
+                continue;
+            }
+            _ => {
+                quoted.push(b);
+                continue;
+            }
+        };
+        quoted.extend_from_slice(&[b'\\', escape]);
+    }
+    quoted.push(b'"');
+    quoted
+}
+
+// hashes `len` bytes of `input` and, when writing, compresses them into the object db in the
+// same pass, so even huge files take constant memory
+fn hash_stream<R: Read>(otype: ObjType, input: R, len: u64, db: Option<&Repo>) -> Result<[u8; 20]> {
+    let mut input = HashingReader::new(input, otype, len);
+    let repo = match db {
+        Some(repo) => repo,
+        None => {
+            std::io::copy(&mut (&mut input).take(len), &mut std::io::sink())
+                .context("read given content for hashing")?;
+            return input.finish();
+        }
+    };
+
+    // the final path depends on the hash, so it's only known once everything's been written
+    let tmp_path = tmp_object_path(repo.objects_dir());
+    let written = write_object_file(
+        otype.type_name(),
+        (&mut input).take(len),
+        len,
+        &tmp_path,
+        repo.loose_compression()?,
+    )
+    .and_then(|_| input.finish());
+    let hash = match written {
+        Ok(hash) => hash,
+        Err(e) => {
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(insufficient_permission(repo, e));
+        }
+    };
+
+    let obj_sha = hex::encode(hash);
+    if repo.has_object_file(&obj_sha) {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Ok(hash);
+    }
+    let obj_db_path = new_obj_path(repo, &obj_sha);
+    let prefix_dir = obj_db_path
+        .parent()
+        .expect("object path to have a prefix dir");
+    if let Err(e) =
+        create_prefix_dir(prefix_dir).and_then(|_| move_object_into_place(&tmp_path, &obj_db_path))
+    {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(insufficient_permission(repo, e));
+    }
+    Ok(hash)
+}
+
+// tees everything read through it into an object hash
+struct HashingReader<R> {
+    inner: R,
+    hasher: sha1::Sha1,
+    expected_len: u64,
+    read_len: u64,
+}
+
+impl<R: Read> HashingReader<R> {
+    fn new(inner: R, otype: ObjType, len: u64) -> Self {
+        use sha1::Digest;
+
+        let header = format!("{} {}\0", otype.type_name(), len);
+        HashingReader {
+            inner,
+            hasher: sha1::Sha1::new_with_prefix(header),
+            expected_len: len,
+            read_len: 0,
+        }
+    }
+
+    // the content has to be exactly as long as the header said, e.g. a file mustn't have shrunk
+    // or grown while it was being hashed
+    fn finish(mut self) -> Result<[u8; 20]> {
+        use sha1::Digest;
+
+        let mut extra = [0u8; 1];
+        ensure!(
+            self.read_len == self.expected_len
+                && self.inner.read(&mut extra).context("read given content")? == 0,
+            "content changed size while hashing, expected {} bytes",
+            self.expected_len
+        );
+        Ok(*self.hasher.finalize().as_mut())
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        use sha1::Digest;
+
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.read_len += n as u64;
+        Ok(n)
+    }
+}
+
+fn hash_bytes(otype: ObjType, content: &[u8], db: Option<&Repo>) -> Result<[u8; 20]> {
+    let contentsz = content.len().try_into().unwrap();
+    let hash = hash_content(otype, content, contentsz)?;
+
+    if let Some(repo) = db {
+        let obj_sha = hex::encode(hash);
+        if !repo.has_object_file(&obj_sha) {
+            let obj_db_path = new_obj_path(repo, &obj_sha);
+            encode_object(repo, otype.type_name(), content, contentsz, obj_db_path)
+                .context("encoding object into database")?;
+        }
+    }
+
+    Ok(hash)
+}
+
+// like hash_bytes, but `type_name` can be anything, as `hash-object --literally` allows
+fn hash_literally(type_name: &str, content: &[u8], db: Option<&Repo>) -> Result<[u8; 20]> {
+    use sha1::{Digest, Sha1};
+
+    let mut hasher = Sha1::new_with_prefix(format!("{} {}\0", type_name, content.len()));
+    hasher.update(content);
+    let hash = *hasher.finalize().as_mut();
+
+    if let Some(repo) = db {
+        let obj_sha = hex::encode(hash);
+        if !repo.has_object_file(&obj_sha) {
+            let obj_db_path = new_obj_path(repo, &obj_sha);
+            encode_object(repo, type_name, content, content.len() as u64, obj_db_path)
+                .context("encoding object into database")?;
+        }
+    }
+    Ok(hash)
+}
+
+fn stage_file(repo: &Repo, name: &str, add: bool) -> Result<()> {
+    let index_path = repo.path("index");
+    let mut index = index::Index::read(&index_path)?;
+    ensure!(
+        add || index.find(name).is_some(),
+        "{}: cannot add to the index - missing --add option?",
+        name
+    );
+
+    let meta = std::fs::symlink_metadata(name)
+        .with_context(|| format!("{}: does not exist and --remove not passed", name))?;
+    let hash = if is_symlink(&meta) {
+        hash_link(name, Some(repo))?
+    } else {
+        ensure!(
+            meta.is_file(),
+            "{}: is a directory - add files inside instead",
+            name
+        );
+        hash_object(name, ObjType::Blob, converts_crlf(repo)?, Some(repo))?
+    };
+
+    index.add(index::IndexEntry::from_metadata(
+        name.to_string(),
+        &meta,
+        hash,
+    ));
+    index.write(&index_path)
+}
+
+fn is_plausibly_obj_sha(maybe_obj_sha: &str) -> bool {
+    maybe_obj_sha.len() == 40 && maybe_obj_sha.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn resolve_obj_sha(repo: &Repo, maybe_prefix: &str) -> Result<String> {
+    ensure!(
+        is_abbrev_sha(maybe_prefix),
+        "Not a valid object name {}",
+        maybe_prefix
+    );
+    find_abbrev_sha(repo, maybe_prefix)?
+        .with_context(|| format!("Not a valid object name {}", maybe_prefix))
+}
+
+fn is_abbrev_sha(name: &str) -> bool {
+    (4..=40).contains(&name.len()) && name.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// the one object whose sha starts with `prefix`, loose or packed; more than one is an error
+fn find_abbrev_sha(repo: &Repo, prefix: &str) -> Result<Option<String>> {
+    let prefix = prefix.to_ascii_lowercase();
+    if is_plausibly_obj_sha(&prefix) {
+        return Ok(Some(prefix));
+    }
+
+    let mut candidates = abbrev_candidates(repo, &prefix)?;
+    ensure!(
+        candidates.len() <= 1,
+        "ambiguous argument '{}': short object ID matches {} objects",
+        prefix,
+        candidates.len()
+    );
+    Ok(candidates.pop())
+}
+
+// every object, loose or packed, whose sha starts with the lowercase hex `prefix`
+fn abbrev_candidates(repo: &Repo, prefix: &str) -> Result<Vec<String>> {
+    let (obj_dirname, obj_fileprefix) = prefix.split_at(2);
+    let mut candidates = vec![];
+    for objects_dir in repo.object_dirs() {
+        let dirents = match objects_dir.join(obj_dirname).read_dir() {
+            Ok(dirents) => dirents,
+            Err(_) => continue,
+        };
+        for ent in dirents {
+            let ent = ent.context("reading object db prefix dir")?;
+            let filename = ent.file_name();
+            let sha = format!("{}{}", obj_dirname, filename.to_string_lossy());
+            if sha[2..].starts_with(obj_fileprefix) && !candidates.contains(&sha) {
+                candidates.push(sha);
+            }
+        }
+    }
+
+    for sha in pack::find_by_prefix(repo, prefix)? {
+        if !candidates.contains(&sha) {
+            candidates.push(sha);
+        }
+    }
+
+    Ok(candidates)
+}
+
+// how many loose objects there are and the disk they take up in KiB, and with `verbose` the same
+// for packs along with anything in the object dirs that doesn't belong there, warning about each
+fn count_objects(repo: &Repo, verbose: bool) -> Result<()> {
+    let mut loose = vec![];
+    let mut loose_size = 0;
+    let mut garbage = vec![];
+    for (hash, ent) in loose_files(&repo.objects_dir())? {
+        match hash {
+            Some(hash) => {
+                let meta = ent.metadata().context("reading loose object")?;
+                if meta.is_file() {
+                    loose.push(hash);
+                    loose_size += disk_usage(&meta);
+                }
+            }
+            None => garbage.push(("garbage found", ent.path())),
+        }
+    }
+    if !verbose {
+        println!("{} objects, {} kilobytes", loose.len(), loose_size / 1024);
+        return Ok(());
+    }
+
+    // git comes across what's in the pack dir first
+    let packs = pack::pack_stats(repo, &loose)?;
+    let garbage: Vec<_> = packs.garbage.into_iter().chain(garbage).collect();
+    let top = std::env::current_dir().context("reading cwd")?;
+    let mut garbage_size = 0;
+    for (why, path) in &garbage {
+        let shown = path.strip_prefix(&top).unwrap_or(path);
+        eprintln!("warning: {}: {}", why, shown.to_string_lossy());
+        garbage_size += path.metadata().map_or(0, |meta| meta.len());
+    }
+    println!("count: {}", loose.len());
+    println!("size: {}", loose_size / 1024);
+    println!("in-pack: {}", packs.objects);
+    println!("packs: {}", packs.packs);
+    println!("size-pack: {}", packs.size / 1024);
+    println!("prune-packable: {}", packs.packed_loose);
+    println!("garbage: {}", garbage.len());
+    println!("size-garbage: {}", garbage_size / 1024);
+    for dir in repo.alternates() {
+        println!(
+            "alternate: {}",
+            String::from_utf8_lossy(&quote_path(&name_bytes(dir.as_os_str()), true))
+        );
+    }
+    Ok(())
+}
+
+// everything in an object dir's two-hex-digit subdirs, with the hash it's the loose object for if
+// its name is one
+fn loose_files(objects_dir: &Path) -> Result<Vec<(Option<[u8; 20]>, std::fs::DirEntry)>> {
+    let mut files = vec![];
+    for first_byte in 0..=0xffu8 {
+        let dirname = format!("{:02x}", first_byte);
+        let dirents = match objects_dir.join(&dirname).read_dir() {
+            Ok(dirents) => dirents,
+            Err(_) => continue,
+        };
+        for ent in dirents {
+            let ent = ent.context("reading object db prefix dir")?;
+            let filename = ent.file_name();
+            let hash = filename
+                .to_str()
+                .filter(|name| name.len() == 38)
+                .and_then(|name| decode_sha(&format!("{}{}", dirname, name)).ok());
+            files.push((hash, ent));
+        }
+    }
+    Ok(files)
+}
+
+// every object, loose or packed, here or in an alternate, each once
+fn all_object_shas(repo: &Repo) -> Result<Vec<String>> {
+    let mut hashes = BTreeSet::new();
+    for objects_dir in repo.object_dirs() {
+        hashes.extend(
+            loose_files(objects_dir)?
+                .into_iter()
+                .filter_map(|(hash, _)| hash),
+        );
+    }
+    hashes.extend(pack::packed_objects(repo)?);
+    Ok(hashes.iter().map(hex::encode).collect())
+}
+
+// what a file really takes up, which for a small object is a whole block
+#[cfg(unix)]
+fn disk_usage(meta: &std::fs::Metadata) -> u64 {
+    meta.blocks() * 512
+}
+
+#[cfg(not(unix))]
+fn disk_usage(meta: &std::fs::Metadata) -> u64 {
+    meta.len()
+}
+
+// the shortest prefix of `sha`, at least `digits` long, that no other object shares
+fn unique_abbrev(repo: &Repo, sha: &str, digits: usize) -> Result<String> {
+    for len in digits.max(4)..40 {
+        if abbrev_candidates(repo, &sha[..len])?.len() <= 1 {
+            return Ok(sha[..len].to_string());
+        }
+    }
+    Ok(sha.to_string())
+}
+
+fn rev_parse(repo: &Repo, rev: &str) -> Result<Option<String>> {
+    // `<rev>:<path>` names what's at a path in a tree-ish, and `:<path>` what's staged there
+    if let Some((rev, path)) = rev.split_once(':') {
+        return match rev {
+            "" => index_path_sha(repo, path).map(Some),
+            rev => tree_path_sha(repo, rev, path).map(Some),
+        };
+    }
+    // `<rev>^{<type>}` peels tags, and a commit to its tree, until it gets to that type, while
+    // `<rev>^{}` peels tags to whatever they finally point at
+    if let Some((rev, otype)) = rev
+        .strip_suffix('}')
+        .and_then(|peeled| peeled.rsplit_once("^{"))
+    {
+        return match rev_parse(repo, rev)? {
+            Some(sha) => peel_object(repo, sha, otype),
+            None => Ok(None),
+        };
+    }
+    if is_plausibly_obj_sha(rev) {
+        return Ok(Some(rev.to_ascii_lowercase()));
+    }
+    if let Some(sha) = refs::resolve_ref(repo, rev)? {
+        return Ok(Some(sha));
+    }
+    // names that aren't refs may still be an abbreviated object id
+    if !is_abbrev_sha(rev) {
+        return Ok(None);
+    }
+    find_abbrev_sha(repo, rev)
+}
+
+fn peel_object(repo: &Repo, mut sha: String, wanted: &str) -> Result<Option<String>> {
+    loop {
+        let otype = match open_object(repo, &sha)? {
+            Some((otype, _, _)) => otype,
+            None => return Ok(None),
+        };
+        let is_tag = matches!(otype, ObjType::Tag);
+        if otype.type_name() == wanted || (wanted.is_empty() && !is_tag) {
+            return Ok(Some(sha));
+        }
+        sha = match GitObject::load(repo, &sha)? {
+            Some(GitObject::Tag(tag)) => hex::encode(tag.object),
+            Some(GitObject::Commit(commit)) if wanted == "tree" => hex::encode(commit.tree),
+            _ => return Ok(None),
+        };
+    }
+}
+
+// paths are from the top of the worktree; a trailing slash is allowed but empty components aren't
+fn tree_path_sha(repo: &Repo, rev: &str, path: &str) -> Result<String> {
+    let mut sha =
+        rev_parse(repo, rev)?.with_context(|| format!("invalid object name '{}'.", rev))?;
+    loop {
+        match GitObject::load(repo, &sha)? {
+            Some(GitObject::Commit(commit)) => sha = hex::encode(commit.tree),
+            Some(GitObject::Tag(tag)) => sha = hex::encode(tag.object),
+            Some(GitObject::Tree(_)) => break,
+            _ => bail!("invalid object name '{}'.", rev),
+        }
+    }
+    let missing = || {
+        let place = if Path::new(path).exists() {
+            "exists on disk, but not"
+        } else {
+            "does not exist"
+        };
+        anyhow::anyhow!("path '{}' {} in '{}'", path, place, rev)
+    };
+
+    let components = path.strip_suffix('/').unwrap_or(path);
+    for component in components.split('/').filter(|_| !components.is_empty()) {
+        let tree = match GitObject::load(repo, &sha)? {
+            Some(GitObject::Tree(tree)) => tree,
+            _ => return Err(missing()),
+        };
+        let ent = tree
+            .into_iter()
+            .find(|ent| ent.name == component.as_bytes())
+            .ok_or_else(missing)?;
+        sha = hex::encode(ent.hash);
+    }
+    Ok(sha)
+}
+
+fn index_path_sha(repo: &Repo, path: &str) -> Result<String> {
+    let index = index::Index::read(repo.path("index"))?;
+    match index.find(path) {
+        Some(ent) => Ok(hex::encode(ent.hash)),
+        None if Path::new(path).exists() => {
+            bail!("path '{}' exists on disk, but not in the index", path)
+        }
+        None => bail!(
+            "path '{}' does not exist (neither on disk nor in the index)",
+            path
+        ),
+    }
+}
+
+fn update_ref(repo: &Repo, ref_name: &str, new_value: &str, message: &str) -> Result<()> {
+    let sha =
+        rev_parse(repo, new_value)?.with_context(|| format!("{}: not a valid SHA1", new_value))?;
+    ensure!(
+        open_object(repo, &sha)?.is_some(),
+        "{}: not a valid SHA1, no such object",
+        new_value
+    );
+    let refname = refs::writable_ref_name(repo, ref_name)?;
+    move_ref(repo, &refname, &sha, message)
+}
+
+// points `refname` at `sha` and logs the move, for HEAD as well when it's the branch HEAD is on
+fn move_ref(repo: &Repo, refname: &str, sha: &str, message: &str) -> Result<()> {
+    let old = refs::read_ref(repo, refname)?;
+    refs::write_ref(repo, refname, sha)?;
+    let who = ident(repo, "COMMITTER")?.to_string();
+    refs::append_reflog(repo, refname, old.as_deref(), sha, &who, message)?;
+    if refname != "HEAD" && refs::symref_target(repo, "HEAD")? == refname {
+        refs::append_reflog(repo, "HEAD", old.as_deref(), sha, &who, message)?;
+    }
+    Ok(())
+}
+
+// where a loose object is, in the repo's own object dir or else one of its alternates; if it's in
+// none of them, where it'd be in the repo's own
+fn obj_path_from_sha(repo: &Repo, obj_sha: &str) -> PathBuf {
+    let (obj_dirname, obj_filename) = obj_sha.split_at(2);
+    repo.object_dirs()
+        .map(|dir| dir.join(obj_dirname).join(obj_filename))
+        .find(|path| path.exists())
+        .unwrap_or_else(|| new_obj_path(repo, obj_sha))
+}
+
+// where a loose object gets written, which is never in an alternate
+fn new_obj_path(repo: &Repo, obj_sha: &str) -> PathBuf {
+    let (obj_dirname, obj_filename) = obj_sha.split_at(2);
+    repo.objects_dir().join(obj_dirname).join(obj_filename)
+}
+
+fn hash_content<R: Read>(otype: ObjType, mut content: R, len: u64) -> Result<[u8; 20]> {
+    use sha1::{Digest, Sha1};
+
+    let mut hasher = Sha1::new_with_prefix(otype.type_name());
+    hasher.update(b" ");
+    hasher.update(len.to_string());
+    hasher.update([0u8]);
+    let mut buf = vec![0u8; READ_BUF_SIZE];
+    let mut bytes_read = content
+        .read(&mut buf)
+        .context("read given content for hashing")?;
+    while bytes_read > 0 {
+        hasher.update(&buf[..bytes_read]);
+        bytes_read = content
+            .read(&mut buf)
+            .context("read given content for hashing")?;
+    }
+
+    let mut h = hasher.finalize();
+    Ok(*h.as_mut())
+}
+
+fn hash_tree(tree: Vec<TreeEntry>, db: Option<&Repo>) -> Result<[u8; 20]> {
+    use sha1::{Digest, Sha1};
+
+    // a tree with a great many entries is big enough that it's serialized as it's read, once for
+    // hashing and again if it's stored, rather than held in memory twice over
+    let bufsz: u64 = tree.iter().map(|ent| tree_entry_len(ent) as u64).sum();
+    let bufsz_str = bufsz.to_string();
+
+    let mut header = Vec::with_capacity(5 + bufsz_str.len() + 1);
+    header.extend_from_slice(b"tree ");
+    header.extend_from_slice(bufsz_str.as_bytes());
+    header.push(b'\0');
+
+    let mut hasher = Sha1::new_with_prefix(header);
+    std::io::copy(&mut TreeReader::new(&tree), &mut hasher).context("hashing tree")?;
+    let hash = *hasher.finalize().as_mut();
+
+    if let Some(repo) = db {
+        let obj_sha = hex::encode(hash);
+        if !repo.has_object_file(&obj_sha) {
+            let obj_db_path = new_obj_path(repo, &obj_sha);
+            encode_object(
+                repo,
+                ObjType::Tree.type_name(),
+                TreeReader::new(&tree),
+                bufsz,
+                obj_db_path,
+            )
+            .context("encoding tree into db")?;
+        }
+    }
+    Ok(hash)
+}
+
+// `rel_path` is where `path` is relative to the top of the worktree, ending with a `/` below it;
+// objects are only written when there's a `db` to write them to. Memory goes with the widest
+// directory on the way down rather than the size of the worktree: each directory being walked
+// holds a `DirEntry` for each of its entries until that's hashed and a `TreeEntry` (mode, sha and
+// name) after, and its tree object is serialized as it's hashed and written rather than built up
+fn write_tree_recursive(
+    db: Option<&Repo>,
+    autocrlf: bool,
+    path: &Path,
+    rel_path: &str,
+    rules: &ignore::IgnoreRules,
+    progress: &Arc<Progress>,
+) -> Result<Vec<TreeEntry>> {
+    let rules = rules.for_dir(path, rel_path)?;
+    let reading_dir = || {
+        let shown = if rel_path.is_empty() { "." } else { rel_path };
+        format!("could not open directory '{}'", shown)
+    };
+    // only the bare dir entries are kept until they're hashed, since in a directory of hundreds
+    // of thousands of files their full paths and metadata would add up
+    let mut to_hash = vec![];
+    for ent in path.read_dir().with_context(reading_dir)? {
+        let ent = ent.with_context(reading_dir)?;
+        if ent.file_name() == ".git" {
+            continue;
+        }
+        let is_dir = ent.file_type().with_context(reading_dir)?.is_dir();
+        let ent_rel_path = format!("{}{}", rel_path, ent.file_name().to_string_lossy());
+        if rules.is_ignored(&ent_rel_path, is_dir) {
+            continue;
+        }
+        to_hash.push(ent);
+    }
+    // only once an entry's been hashed is it known whether it's a subtree, which that sorts by
+    let mut entries = hash_worktree_entries(
+        db,
+        autocrlf,
+        rel_path,
+        to_hash,
+        Arc::new(rules),
+        Arc::clone(progress),
+    )?;
+    entries.sort_by(tree_entry_cmp);
+    Ok(entries)
+}
+
+// shares a directory's entries out to as many threads as the global budget allows, keeping the
+// results in their original order; the first to fail stops the rest picking up any more
+fn hash_worktree_entries(
+    db: Option<&Repo>,
+    autocrlf: bool,
+    rel_path: &str,
+    to_hash: Vec<std::fs::DirEntry>,
+    rules: Arc<ignore::IgnoreRules>,
+    progress: Arc<Progress>,
+) -> Result<Vec<TreeEntry>> {
+    let count = to_hash.len();
+    let queue = Arc::new(Mutex::new(to_hash.into_iter().enumerate()));
+    let hashed = Arc::new(Mutex::new(Vec::with_capacity(count)));
+    let drain = {
+        let db = db.cloned();
+        let rel_path = rel_path.to_string();
+        let queue = Arc::clone(&queue);
+        let hashed = Arc::clone(&hashed);
+        move || loop {
+            let next = queue.lock().unwrap().next();
+            match next {
+                Some((i, ent)) => {
+                    let path = ent.path();
+                    let ent_rel_path = format!("{}{}", rel_path, ent.file_name().to_string_lossy());
+                    let ent = path
+                        .symlink_metadata()
+                        .with_context(|| format!("unable to stat '{}'", ent_rel_path))
+                        .and_then(|meta| {
+                            hash_worktree_entry(
+                                db.as_ref(),
+                                autocrlf,
+                                &path,
+                                &ent_rel_path,
+                                &meta,
+                                &rules,
+                                &progress,
+                            )
+                        });
+                    if ent.is_err() {
+                        queue.lock().unwrap().by_ref().for_each(drop);
+                    }
+                    hashed.lock().unwrap().push((i, ent));
+                }
+                None => break,
+            }
+        }
+    };
+
+    let mut workers = vec![];
+    while workers.len() + 1 < count && claim_worker() {
+        let drain = drain.clone();
+        workers.push(std::thread::spawn(drain));
+    }
+    drain();
+    for worker in workers {
+        let joined = worker.join();
+        SPARE_WORKERS.fetch_add(1, Ordering::Relaxed);
+        if let Err(panic) = joined {
+            std::panic::resume_unwind(panic);
+        }
+    }
+
+    let mut hashed = std::mem::take(&mut *hashed.lock().unwrap());
+    hashed.sort_by_key(|(i, _)| *i);
+    let mut entries = Vec::with_capacity(hashed.len());
+    for (_, ent) in hashed {
+        entries.extend(ent?);
+    }
+    Ok(entries)
+}
+
+// threads that may still be spawned for hashing, shared by every directory being walked so
+// nested directories don't multiply the thread count
+static SPARE_WORKERS: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+fn claim_worker() -> bool {
+    let max_spare = std::thread::available_parallelism().map_or(0, |n| n.get() - 1);
+    // the first claim sets up the budget
+    let _ =
+        SPARE_WORKERS.compare_exchange(usize::MAX, max_spare, Ordering::Relaxed, Ordering::Relaxed);
+    SPARE_WORKERS
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |spare| {
+            spare.checked_sub(1)
+        })
+        .is_ok()
+}
+
+fn hash_worktree_entry(
+    db: Option<&Repo>,
+    autocrlf: bool,
+    ent: &Path,
+    ent_rel_path: &str,
+    meta: &std::fs::Metadata,
+    rules: &ignore::IgnoreRules,
+    progress: &Arc<Progress>,
+) -> Result<Option<TreeEntry>> {
+    let entry_type: ObjType;
+    let entry_mode: TreeObjMode;
+    let entry_hash: [u8; 20];
+    let unable = || format!("unable to index file '{}'", ent_rel_path);
+    if is_symlink(meta) {
+        entry_hash = hash_link(ent, db).with_context(unable)?;
+        entry_type = ObjType::Blob;
+        entry_mode = TreeObjMode::Link;
+        progress.add(1);
+    } else if meta.is_dir() {
+        let tree = write_tree_recursive(
+            db,
+            autocrlf,
+            ent,
+            &format!("{}/", ent_rel_path),
+            rules,
+            progress,
+        )?;
+        // git doesn't track empty directories, e.g. ones holding only ignored files
+        if tree.is_empty() {
+            return Ok(None);
+        }
+        entry_hash = hash_tree(tree, db).with_context(unable)?;
+        entry_type = ObjType::Tree;
+        entry_mode = TreeObjMode::Directory;
+    } else {
+        entry_hash = hash_object(ent, ObjType::Blob, autocrlf, db).with_context(unable)?;
+        entry_type = ObjType::Blob;
+        progress.add(1);
+        entry_mode = if is_executable(meta) {
+            TreeObjMode::ExecutableFile
+        } else {
+            TreeObjMode::RegularFile
+        };
+    }
+    Ok(Some(TreeEntry {
+        name: name_bytes(ent.file_name().unwrap_or_else(|| {
+            panic!(
+                "entry `{}` has a file name since it isn't a dir",
+                ent.to_string_lossy()
+            )
+        })),
+        hash: entry_hash,
+        mode: entry_mode,
+        otype: entry_type,
+    }))
+}
+
+// `git status --short`: a column each for what's staged and what isn't, then untracked files
+fn print_status(repo: &Repo, cwd_prefix: &str) -> Result<()> {
+    let index = index::Index::read(repo.path("index"))?;
+    let changes = tracked_changes(repo, &index)?;
+    for (name, (staged, unstaged)) in changes {
+        println!(
+            "{}{} {}",
+            staged,
+            unstaged,
+            relative_path(&name, cwd_prefix)
+        );
+    }
+
+    let mut untracked = vec![];
+    let cur_dir = std::env::current_dir().context("reading cwd")?;
+    find_untracked(
+        &cur_dir,
+        "",
+        &ignore::IgnoreRules::default(),
+        &index,
+        &mut untracked,
+    )?;
+    for name in untracked {
+        println!("?? {}", relative_path(&name, cwd_prefix));
+    }
+    Ok(())
+}
+
+// how each tracked file that's changed differs from HEAD in the index and from the index in the
+// worktree, as the two status letters `status --short` shows
+fn tracked_changes(repo: &Repo, index: &index::Index) -> Result<BTreeMap<String, (char, char)>> {
+    let head_tree = match rev_parse(repo, "HEAD")? {
+        Some(head) => {
+            let tree = hex::encode(read_commit(repo, &head)?.tree);
+            flatten_tree(repo, read_tree(repo, &tree)?, b"", false)?
+        }
+        None => vec![],
+    };
+    let head_tree: HashMap<String, TreeEntry> = head_tree
+        .into_iter()
+        .map(|ent| (String::from_utf8_lossy(&ent.name).into_owned(), ent))
+        .collect();
+
+    let autocrlf = converts_crlf(repo)?;
+    let mut changes = BTreeMap::new();
+    for ent in &index.entries {
+        let staged = match head_tree.get(&ent.name) {
+            None => 'A',
+            Some(head_ent)
+                if head_ent.hash != ent.hash
+                    || head_ent.mode.as_bytes() != format!("{:o}", ent.mode).as_bytes() =>
+            {
+                'M'
+            }
+            Some(_) => ' ',
+        };
+        let unstaged = worktree_change(ent, autocrlf)?;
+        if staged != ' ' || unstaged != ' ' {
+            changes.insert(ent.name.clone(), (staged, unstaged));
+        }
+    }
+    for name in head_tree.into_keys() {
+        if index.find(&name).is_none() {
+            changes.insert(name, ('D', ' '));
+        }
+    }
+    Ok(changes)
+}
+
+// how the worktree copy of a staged file differs from what's staged, as a status letter
+fn worktree_change(ent: &index::IndexEntry, autocrlf: bool) -> Result<char> {
+    if ent.mode == 0o160000 {
+        // submodules would need their own HEAD checking
+        return Ok(' ');
+    }
+    let meta = match std::fs::symlink_metadata(&ent.name) {
+        Ok(meta) if !meta.is_dir() => meta,
+        _ => return Ok('D'),
+    };
+    let current = index::IndexEntry::from_metadata(ent.name.clone(), &meta, ent.hash);
+    if (current.mode & 0o170000) != (ent.mode & 0o170000) {
+        return Ok('T');
+    }
+    // unchanged size and timestamps mean it can't have been written to since it was staged
+    if current.mode == ent.mode && current.size == ent.size && current.mtime == ent.mtime {
+        return Ok(' ');
+    }
+    let hash = if is_symlink(&meta) {
+        hash_link(&ent.name, None)?
+    } else {
+        hash_object(&ent.name, ObjType::Blob, autocrlf, None)?
+    };
+    if hash != ent.hash || current.mode != ent.mode {
+        Ok('M')
+    } else {
+        Ok(' ')
+    }
+}
+
+// untracked files under `path`, a directory `rel_path` from the top of the worktree; like git, a
+// directory with nothing tracked in it is listed as a whole
+fn find_untracked(
+    path: &Path,
+    rel_path: &str,
+    rules: &ignore::IgnoreRules,
+    index: &index::Index,
+    untracked: &mut Vec<String>,
+) -> Result<()> {
+    let rules = rules.for_dir(path, rel_path)?;
+    let mut dirents = path
+        .read_dir()
+        .and_then(|ents| ents.collect::<std::io::Result<Vec<_>>>())
+        .with_context(|| format!("listing {}", path.to_string_lossy()))?;
+    dirents.sort_by_key(|ent| ent.file_name());
+    for ent in dirents {
+        if ent.file_name() == ".git" {
+            continue;
+        }
+        let is_dir = ent.file_type().context("reading file type")?.is_dir();
+        let ent_rel_path = format!("{}{}", rel_path, ent.file_name().to_string_lossy());
+        if rules.is_ignored(&ent_rel_path, is_dir) {
+            continue;
+        }
+        if !is_dir {
+            if index.find(&ent_rel_path).is_none() {
+                untracked.push(ent_rel_path);
+            }
+            continue;
+        }
+
+        let dir_prefix = format!("{}/", ent_rel_path);
+        let first_below = index
+            .entries
+            .partition_point(|ent| ent.name.as_bytes() < dir_prefix.as_bytes());
+        let has_tracked = index
+            .entries
+            .get(first_below)
+            .map_or(false, |ent| ent.name.starts_with(&dir_prefix));
+        let mut below = vec![];
+        find_untracked(&ent.path(), &dir_prefix, &rules, index, &mut below)?;
+        if has_tracked {
+            untracked.extend(below);
+        } else if !below.is_empty() {
+            untracked.push(dir_prefix);
+        }
+    }
+    Ok(())
+}
+
+// `path` from the top of the worktree as it's reached from `cwd_prefix`, e.g. `../a` from `d`
+fn relative_path(path: &str, cwd_prefix: &str) -> String {
+    let mut path = path;
+    let mut dirs = cwd_prefix.split('/').filter(|d| !d.is_empty()).peekable();
+    // drop the leading directories the two have in common
+    while let Some(rest) = dirs
+        .peek()
+        .and_then(|dir| path.strip_prefix(dir))
+        .and_then(|rest| rest.strip_prefix('/'))
+    {
+        path = rest;
+        dirs.next();
+    }
+    let rel_path = format!("{}{}", "../".repeat(dirs.count()), path);
+    if rel_path.is_empty() {
+        "./".to_string()
+    } else {
+        rel_path
+    }
+}
+
+// the tree of what's staged, or of the worktree itself when `from_worktree` asks for it or there's
+// no index yet; `prefix` picks out just that subdirectory's tree, and only with `write` are it and
+// anything else new stored
+fn write_current_tree(
+    repo: &Repo,
+    from_worktree: bool,
+    prefix: Option<&str>,
+    write: bool,
+    show_progress: bool,
+) -> Result<[u8; 20]> {
+    let db = write.then(|| repo);
+    let cur_dir = std::env::current_dir().context("reading cwd")?;
+    let index_path = repo.path("index");
+    let prefix = prefix
+        .map(|p| p.trim_start_matches("./").trim_end_matches('/'))
+        .filter(|p| !p.is_empty());
+
+    if from_worktree || !index_path.exists() {
+        let mut dir = cur_dir;
+        let mut rel_path = String::new();
+        let mut rules = ignore::IgnoreRules::default();
+        // .gitignore files above the prefix still apply inside it
+        for component in prefix.iter().flat_map(|p| p.split('/')) {
+            rules = rules.for_dir(&dir, &rel_path)?;
+            dir.push(component);
+            rel_path.push_str(component);
+            rel_path.push('/');
+        }
+        ensure!(
+            dir.is_dir(),
+            "git-write-tree: prefix {} not found",
+            rel_path
+        );
+        // there's no telling how many files there are until they've all been found
+        let progress = Arc::new(Progress::new("Writing objects", None, show_progress));
+        let tree =
+            write_tree_recursive(db, converts_crlf(repo)?, &dir, &rel_path, &rules, &progress)?;
+        progress.finish();
+        // an empty directory isn't tracked, so there's nothing for the prefix to name
+        ensure!(
+            prefix.is_none() || !tree.is_empty(),
+            "git-write-tree: prefix {} not found",
+            rel_path
+        );
+        hash_tree(tree, db).context("inserting a tree object for the current dir")
+    } else {
+        let index = index::Index::read(&index_path)?;
+        let (entries, prefix_len) = match prefix {
+            Some(prefix) => {
+                let dir_prefix = format!("{}/", prefix);
+                let start = index
+                    .entries
+                    .partition_point(|ent| ent.name.as_bytes() < dir_prefix.as_bytes());
+                let len = index.entries[start..]
+                    .iter()
+                    .take_while(|ent| ent.name.starts_with(&dir_prefix))
+                    .count();
+                ensure!(len > 0, "git-write-tree: prefix {} not found", dir_prefix);
+                (&index.entries[start..start + len], dir_prefix.len())
+            }
+            None => (&index.entries[..], 0),
+        };
+        let progress = Progress::new("Writing objects", Some(entries.len() as u64), show_progress);
+        let entries = tree_from_index(db, entries, prefix_len, &progress);
+        progress.finish();
+        entries
+            .and_then(|tree| hash_tree(tree, db))
+            .context("git-write-tree: error building trees")
+    }
+}
+
+// index entries are sorted by full path, so everything under a directory is contiguous and
+// already in the order git wants for tree entries
+fn tree_from_index(
+    db: Option<&Repo>,
+    entries: &[index::IndexEntry],
+    prefix_len: usize,
+    progress: &Progress,
+) -> Result<Vec<TreeEntry>> {
+    let mut res = vec![];
+    let mut i = 0;
+    while i < entries.len() {
+        let ent = &entries[i];
+        ensure!(ent.stage() == 0, "{} is unmerged", ent.name);
+        let rel_name = &ent.name[prefix_len..];
+        if let Some((dir_name, _)) = rel_name.split_once('/') {
+            let dir_prefix = &ent.name[..prefix_len + dir_name.len() + 1];
+            let end = i + entries[i..]
+                .iter()
+                .take_while(|e| e.name.starts_with(dir_prefix))
+                .count();
+            let subtree = tree_from_index(db, &entries[i..end], dir_prefix.len(), progress)?;
+            res.push(TreeEntry {
+                mode: TreeObjMode::Directory,
+                otype: ObjType::Tree,
+                hash: hash_tree(subtree, db)?,
+                name: dir_name.as_bytes().to_vec(),
+            });
+            i = end;
+        } else {
+            let mode = TreeObjMode::from(format!("{:o}", ent.mode).as_bytes())
+                .with_context(|| format!("index entry {}", ent.name))?;
+            res.push(TreeEntry {
+                otype: mode.obj_type(),
+                mode,
+                hash: ent.hash,
+                name: rel_name.as_bytes().to_vec(),
+            });
+            progress.add(1);
+            i += 1;
+        }
+    }
+    Ok(res)
+}
+
+fn read_tree(repo: &Repo, sha: &str) -> Result<Vec<TreeEntry>> {
+    match GitObject::load(repo, sha)? {
+        Some(GitObject::Tree(tree)) => Ok(tree),
+        Some(obj) => bail!(
+            "{} is not a tree (found {})",
+            sha,
+            obj.obj_type().type_name()
+        ),
+        None => bail!("unable to read tree {}", sha),
+    }
+}
+
+// the commit a commit-ish names, looking through any tags on the way
+fn peel_to_commit(repo: &Repo, name: &str) -> Result<String> {
+    let mut sha =
+        rev_parse(repo, name)?.with_context(|| format!("Not a valid object name {}", name))?;
+    loop {
+        match GitObject::load(repo, &sha)? {
+            Some(GitObject::Commit(_)) => return Ok(sha),
+            Some(GitObject::Tag(tag)) => sha = hex::encode(tag.object),
+            _ => bail!("{} is not a commit", name),
+        }
+    }
+}
+
+// the tree a tree-ish names, looking through any commits and tags on the way
+fn peel_to_tree(repo: &Repo, name: &str) -> Result<Vec<TreeEntry>> {
+    let mut sha =
+        rev_parse(repo, name)?.with_context(|| format!("Not a valid object name {}", name))?;
+    loop {
+        match open_object(repo, &sha)? {
+            Some((ObjType::Commit, _, _)) => sha = hex::encode(read_commit(repo, &sha)?.tree),
+            Some((ObjType::Tag, _, mut reader)) => {
+                let mut content = vec![];
+                reader
+                    .read_to_end(&mut content)
+                    .with_context(|| format!("reading tag {}", sha))?;
+                let tag =
+                    Tag::parse(&content).with_context(|| format!("corrupt tag object {}", sha))?;
+                sha = hex::encode(tag.object);
+            }
+            _ => return read_tree(repo, &sha),
+        }
+    }
+}
+
+// the order trees keep their entries in
+fn tree_entry_cmp(a: &TreeEntry, b: &TreeEntry) -> std::cmp::Ordering {
+    base_name_compare(
+        &a.name,
+        matches!(a.mode, TreeObjMode::Directory),
+        &b.name,
+        matches!(b.mode, TreeObjMode::Directory),
+    )
+}
+
+// git's comparison of names within a tree: bytewise, except that a subtree sorts as if it
+// ended in a `/`, so a file `foo.txt` comes before a directory `foo` but after a file `foo`
+fn base_name_compare(
+    name1: &[u8],
+    is_dir1: bool,
+    name2: &[u8],
+    is_dir2: bool,
+) -> std::cmp::Ordering {
+    let len = name1.len().min(name2.len());
+    name1[..len].cmp(&name2[..len]).then_with(|| {
+        let end = |name: &[u8], is_dir| match name.get(len) {
+            Some(&c) => c,
+            None if is_dir => b'/',
+            None => 0,
+        };
+        end(name1, is_dir1).cmp(&end(name2, is_dir2))
+    })
+}
+
+const NULL_SHA: &str = "0000000000000000000000000000000000000000";
+
+// what changed between two entries with the same path, one side missing if it was added or
+// deleted
+type EachChange<'a> = dyn FnMut(&[u8], Option<&TreeEntry>, Option<&TreeEntry>) -> Result<()> + 'a;
+
+// hands each path that differs between `old` and `new` to `each`, walking both in tree order so
+// each name is only visited once
+fn diff_trees(
+    repo: &Repo,
+    old: &[TreeEntry],
+    new: &[TreeEntry],
+    prefix: &[u8],
+    recursive: bool,
+    each: &mut EachChange,
+) -> Result<()> {
+    let mut old = old.iter().peekable();
+    let mut new = new.iter().peekable();
+    loop {
+        let order = match (old.peek(), new.peek()) {
+            (None, None) => return Ok(()),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (Some(o), Some(n)) => tree_entry_cmp(o, n),
+        };
+        match order {
+            std::cmp::Ordering::Less => {
+                diff_entry(repo, old.next(), None, prefix, recursive, each)?
+            }
+            std::cmp::Ordering::Greater => {
+                diff_entry(repo, None, new.next(), prefix, recursive, each)?
+            }
+            std::cmp::Ordering::Equal => {
+                diff_entry(repo, old.next(), new.next(), prefix, recursive, each)?
+            }
+        }
+    }
+}
+
+// both sides have the same name and are either both subtrees or both not
+fn diff_entry(
+    repo: &Repo,
+    old: Option<&TreeEntry>,
+    new: Option<&TreeEntry>,
+    prefix: &[u8],
+    recursive: bool,
+    each: &mut EachChange,
+) -> Result<()> {
+    let either = old.or(new).expect("an entry on at least one side");
+    let name = [prefix, &either.name].concat();
+    if let (Some(o), Some(n)) = (old, new) {
+        if o.hash == n.hash && o.mode.as_bytes() == n.mode.as_bytes() {
+            return Ok(());
+        }
+    }
+
+    if recursive && matches!(either.mode, TreeObjMode::Directory) {
+        let old_tree = match old {
+            Some(o) => read_tree(repo, &hex::encode(o.hash))?,
+            None => vec![],
+        };
+        let new_tree = match new {
+            Some(n) => read_tree(repo, &hex::encode(n.hash))?,
+            None => vec![],
+        };
+        return diff_trees(
+            repo,
+            &old_tree,
+            &new_tree,
+            &[&name, &b"/"[..]].concat(),
+            recursive,
+            each,
+        );
+    }
+    each(&name, old, new)
+}
+
+// a line of git's raw diff format
+fn print_raw_change(
+    repo: &Repo,
+    name: &[u8],
+    old: Option<&TreeEntry>,
+    new: Option<&TreeEntry>,
+) -> Result<()> {
+    let status = match (old, new) {
+        (Some(_), None) => 'D',
+        (None, Some(_)) => 'A',
+        // e.g. a file that became a symlink
+        (Some(o), Some(n)) if !same_file_kind(&o.mode, &n.mode) => 'T',
+        _ => 'M',
+    };
+    let side = |ent: Option<&TreeEntry>| match ent {
+        Some(ent) => (ent.mode.to_string(), hex::encode(ent.hash)),
+        None => ("000000".to_string(), NULL_SHA.to_string()),
+    };
+    let (old_mode, old_sha) = side(old);
+    let (new_mode, new_sha) = side(new);
+    let quote_high = repo
+        .config
+        .get::<bool>("core", "quotepath")?
+        .unwrap_or(true);
+    let mut line = format!(
+        ":{} {} {} {} {}\t",
+        old_mode, new_mode, old_sha, new_sha, status
+    )
+    .into_bytes();
+    line.extend_from_slice(&quote_path(name, quote_high));
+    line.push(b'\n');
+    std::io::stdout()
+        .write_all(&line)
+        .context("writing to stdout")?;
+    Ok(())
+}
+
+// only the executable bit differs between regular files, anything else is a change of type
+fn same_file_kind(a: &TreeObjMode, b: &TreeObjMode) -> bool {
+    use TreeObjMode::*;
+    matches!(
+        (a, b),
+        (RegularFile | ExecutableFile, RegularFile | ExecutableFile)
+            | (Link, Link)
+            | (Gitlink, Gitlink)
+            | (Directory, Directory)
+    )
+}
+
+fn tree_to_bytes(tree: &[TreeEntry]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(tree.len() * 48);
+    for ent in tree {
+        put_tree_entry(&mut buf, ent);
+    }
+    buf.freeze()
+}
+
+fn put_tree_entry<B: BufMut>(buf: &mut B, ent: &TreeEntry) {
+    buf.put_slice(&ent.mode.as_bytes());
+    buf.put_u8(b' ');
+    buf.put_slice(&ent.name);
+    buf.put_u8(b'\0');
+    buf.put_slice(&ent.hash);
+}
+
+fn tree_entry_len(ent: &TreeEntry) -> usize {
+    ent.mode.as_bytes().len() + 1 + ent.name.len() + 1 + ent.hash.len()
+}
+
+// a tree's contents an entry at a time, as they'd be stored
+struct TreeReader<'a> {
+    entries: std::slice::Iter<'a, TreeEntry>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<'a> TreeReader<'a> {
+    fn new(tree: &'a [TreeEntry]) -> Self {
+        TreeReader {
+            entries: tree.iter(),
+            buf: vec![],
+            pos: 0,
+        }
+    }
+}
+
+impl Read for TreeReader<'_> {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos == self.buf.len() {
+            let ent = match self.entries.next() {
+                Some(ent) => ent,
+                None => return Ok(0),
+            };
+            self.buf.clear();
+            self.pos = 0;
+            put_tree_entry(&mut self.buf, ent);
+        }
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+fn parse_tree<R: BufRead>(mut reader: R) -> Result<Vec<TreeEntry>> {
+    let mut tree_ents: Vec<TreeEntry> = vec![];
+    let mut pnbuf = vec![];
+    loop {
+        let nbytes = reader
+            .read_until(b' ', &mut pnbuf)
+            .context("reading next tree entry's mode")?;
+        if nbytes == 0 {
+            // EOF
+            break;
+        }
+        ensure!(
+            pnbuf.pop() == Some(b' '),
+            "tree entry ends before the space after its mode"
+        );
+        let mode = TreeObjMode::from(&pnbuf)?;
+        let otype = mode.obj_type();
+        pnbuf.clear();
+
+        reader
+            .read_until(b'\0', &mut pnbuf)
+            .context("reading the name after the tree entry's mode")?;
+        ensure!(
+            pnbuf.pop() == Some(b'\0'),
+            "tree entry ends before the NUL after its name"
+        );
+        let name = std::mem::take(&mut pnbuf);
+
+        let mut hash = [0u8; 20];
+        reader
+            .read_exact(&mut hash)
+            .context("reading 20 bytes after mode+name for the hash")?;
+
+        let ent = TreeEntry {
+            mode,
+            otype,
+            name,
+            hash,
+        };
+        tree_ents.push(ent);
+    }
+    Ok(tree_ents)
+}
+
+// `keep_trees` lists each subtree itself ahead of its contents, like `ls-tree -r -t`
+fn flatten_tree(
+    repo: &Repo,
+    tree: Vec<TreeEntry>,
+    prefix: &[u8],
+    keep_trees: bool,
+) -> Result<Vec<TreeEntry>> {
+    let mut res = vec![];
+    for ent in tree {
+        let name = [prefix, &ent.name].concat();
+        if let TreeObjMode::Directory = ent.mode {
+            let hex_hash = hex::encode(ent.hash);
+            if keep_trees {
+                res.push(TreeEntry {
+                    name: name.clone(),
+                    ..ent
+                });
+            }
+            match open_object(repo, &hex_hash)? {
+                Some((ObjType::Tree, _objsz, mut reader)) => {
+                    let subtree = parse_tree(&mut reader)
+                        .with_context(|| format!("corrupt tree object {}", hex_hash))?;
+                    res.extend(flatten_tree(
+                        repo,
+                        subtree,
+                        &[&name, &b"/"[..]].concat(),
+                        keep_trees,
+                    )?);
+                }
+                Some((objt, _, _)) => bail!(
+                    "subtree `{}` is not a tree (found {})",
+                    String::from_utf8_lossy(&name),
+                    objt.type_name()
+                ),
+                None => bail!(
+                    "subtree `{}` is missing from the object db",
+                    String::from_utf8_lossy(&name)
+                ),
+            }
+        } else {
+            res.push(TreeEntry { name, ..ent });
+        }
+    }
+    Ok(res)
+}
+
+// what `ls-tree -l` shows as an entry's size, which only blobs have
+fn entry_size(
+    repo: &Repo,
+    ent: &TreeEntry,
+    sizes: &mut HashMap<[u8; 20], usize>,
+) -> Result<String, GitError> {
+    match ent.otype {
+        ObjType::Blob => Ok(cached_object_size(repo, &ent.hash, sizes)
+            .map_err(|e| GitError::Corrupt(e.to_string()))?
+            .to_string()),
+        _ => Ok("-".to_string()),
+    }
+}
+
+// one piece of an `ls-tree --format` string
+enum LsTreeField {
+    Literal(Vec<u8>),
+    Mode,
+    Type,
+    Name,
+    Size { padded: bool },
+    Path,
+}
+
+// besides the `%(...)` placeholders there's `%%`, `%n` for a newline and `%xNN` for any byte
+fn parse_ls_tree_format(format: &str) -> Result<Vec<LsTreeField>, GitError> {
+    let bad_format = |msg: String| GitError::Invalid(format!("bad ls-tree format: {}", msg));
+    let mut fields = vec![];
+    let mut literal = vec![];
+    let mut rest = format;
+    while let Some(pos) = rest.find('%') {
+        literal.extend_from_slice(rest[..pos].as_bytes());
+        rest = &rest[pos + 1..];
+        if let Some(after) = rest.strip_prefix('%') {
+            literal.push(b'%');
+            rest = after;
+            continue;
+        }
+        if let Some(after) = rest.strip_prefix('n') {
+            literal.push(b'\n');
+            rest = after;
+            continue;
+        }
+        if let Some(byte) = rest
+            .strip_prefix('x')
+            .and_then(|hex| hex.get(..2))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            literal.push(byte);
+            rest = &rest[3..];
+            continue;
+        }
+
+        let element = rest.split('%').next().unwrap_or_default();
+        if !rest.starts_with('(') {
+            return Err(bad_format(format!(
+                "element '{}' does not start with '('",
+                element
+            )));
+        }
+        let (name, after) = match rest[1..].split_once(')') {
+            Some(split) => split,
+            None => {
+                return Err(bad_format(format!(
+                    "element '{}' does not end in ')'",
+                    element
+                )))
+            }
+        };
+        let field = match name {
+            "objectmode" => LsTreeField::Mode,
+            "objecttype" => LsTreeField::Type,
+            "objectname" => LsTreeField::Name,
+            "objectsize" => LsTreeField::Size { padded: false },
+            "objectsize:padded" => LsTreeField::Size { padded: true },
+            "path" => LsTreeField::Path,
+            _ => return Err(bad_format(format!("%({})", name))),
+        };
+        if !literal.is_empty() {
+            fields.push(LsTreeField::Literal(std::mem::take(&mut literal)));
+        }
+        fields.push(field);
+        rest = after;
+    }
+    literal.extend_from_slice(rest.as_bytes());
+    if !literal.is_empty() {
+        fields.push(LsTreeField::Literal(literal));
+    }
+    Ok(fields)
+}
+
+fn cached_object_size(
+    repo: &Repo,
+    hash: &[u8; 20],
+    cache: &mut HashMap<[u8; 20], usize>,
+) -> Result<usize> {
+    if let Some(objsz) = cache.get(hash) {
+        return Ok(*objsz);
+    }
+    let hex_hash = hex::encode(hash);
+    let (_otype, objsz, _reader) = open_object(repo, &hex_hash)?
+        .with_context(|| format!("could not get object info about '{}'", hex_hash))?;
+    cache.insert(*hash, objsz);
+    Ok(objsz)
+}
+
+// who's making a commit or tag, as `role` AUTHOR or COMMITTER; as in git, the GIT_<role>_* env vars
+// win over user.name and user.email, and without either a placeholder is made up unless
+// user.useConfigOnly forbids it
+fn ident(repo: &Repo, role: &str) -> Result<Signature> {
+    let use_config_only = repo
+        .config
+        .get::<bool>("user", "useconfigonly")?
+        .unwrap_or(false);
+    let lookup = |field: &str, placeholder: &str| -> Result<String> {
+        if let Ok(value) = std::env::var(format!("GIT_{}_{}", role, field.to_ascii_uppercase())) {
+            return Ok(value);
+        }
+        if let Some(value) = repo.config.get::<String>("user", field)? {
+            return Ok(value);
+        }
+        if use_config_only {
+            eprintln!(
+                "{} identity unknown\n\n\
+                *** Please tell me who you are.\n\n\
+                Run\n\n  \
+                git config --global user.email \"you@example.com\"\n  \
+                git config --global user.name \"Your Name\"\n\n\
+                to set your account's default identity.\n\
+                Omit --global to set the identity only in this repository.\n",
+                if role == "AUTHOR" {
+                    "Author"
+                } else {
+                    "Committer"
+                }
+            );
+            bail!("no {} was given and auto-detection is disabled", field);
+        }
+        Ok(placeholder.into())
+    };
+    let name = lookup("name", "Test User")?;
+    let email = lookup("email", "test@user.net")?;
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("timestamp after the epoch")
+        .as_secs() as i64;
+    Ok(Signature {
+        name,
+        email,
+        timestamp,
+        tz: "+0000".into(),
+    })
+}
+
+// commits `tree` on top of the branch HEAD points at and moves that branch to the new commit
+fn commit_to_head(repo: &Repo, tree: [u8; 20], message: String) -> Result<[u8; 20]> {
+    let branch = refs::writable_ref_name(repo, "HEAD")?;
+    // a branch that doesn't exist yet is unborn, so this is its root commit
+    let mut parents = vec![];
+    if let Some(parent_sha) = refs::read_ref(repo, &branch)? {
+        parents.push(decode_sha(&parent_sha)?);
+    }
+
+    let subject = message.lines().next().unwrap_or_default().to_string();
+    let log_message = if parents.is_empty() {
+        format!("commit (initial): {}", subject)
+    } else {
+        format!("commit: {}", subject)
+    };
+
+    let c = Commit::new(repo, tree, parents, message)?;
+    let hash = hash_commit(repo, &c).context("creating the commit object")?;
+    move_ref(repo, &branch, &hex::encode(hash), &log_message)?;
+    Ok(hash)
+}
+
+// the current branch is marked with a `*`, or a detached HEAD listed first in its place
+fn list_branches(repo: &Repo) -> Result<()> {
+    let current = refs::symref_target(repo, "HEAD")?;
+    if current == "HEAD" {
+        if let Some(sha) = refs::read_ref(repo, "HEAD")? {
+            println!("* (HEAD detached at {})", unique_abbrev(repo, &sha, 7)?);
+        }
+    }
+    for refname in refs::list_refs(repo)?.into_keys() {
+        if let Some(branch) = refname.strip_prefix("refs/heads/") {
+            let marker = if refname == current { '*' } else { ' ' };
+            println!("{} {}", marker, branch);
+        }
+    }
+    Ok(())
+}
+
+// a new branch starts off wherever HEAD is
+fn create_branch(repo: &Repo, name: &str) -> Result<(), GitError> {
+    let refname = format!("refs/heads/{}", name);
+    if name == "HEAD" || !is_valid_branch_name(&refname) {
+        return Err(GitError::Invalid(format!(
+            "'{}' is not a valid branch name",
+            name
+        )));
+    }
+    if refs::read_ref(repo, &refname)?.is_some() {
+        return Err(GitError::Invalid(format!(
+            "a branch named '{}' already exists",
+            name
+        )));
+    }
+    let head = refs::symref_target(repo, "HEAD")?;
+    let head = head.trim_start_matches("refs/heads/");
+    let sha = refs::read_ref(repo, "HEAD")?
+        .ok_or_else(|| GitError::NotFound(format!("not a valid object name: '{}'", head)))?;
+    Ok(move_ref(
+        repo,
+        &refname,
+        &sha,
+        &format!("branch: Created from {}", head),
+    )?)
+}
+
+fn delete_branch(repo: &Repo, name: &str) -> Result<(), GitError> {
+    let refname = format!("refs/heads/{}", name);
+    let sha = match refs::read_ref(repo, &refname)? {
+        Some(sha) => sha,
+        None => return Err(GitError::Failed(format!("branch '{}' not found.", name))),
+    };
+    if refs::symref_target(repo, "HEAD")? == refname {
+        // commands run from the top of the worktree
+        let top = std::env::current_dir().context("finding the worktree")?;
+        return Err(GitError::Failed(format!(
+            "Cannot delete branch '{}' checked out at '{}'",
+            name,
+            top.to_string_lossy()
+        )));
+    }
+    refs::delete_ref(repo, &refname)?;
+    println!(
+        "Deleted branch {} (was {}).",
+        name,
+        unique_abbrev(repo, &sha, 7)?
+    );
+    Ok(())
+}
+
+// a lightweight tag is just a ref, an annotated one points the ref at a new tag object
+fn create_tag(
+    repo: &Repo,
+    name: &str,
+    object: &str,
+    annotate: bool,
+    message: Option<String>,
+) -> Result<()> {
+    let refname = format!("refs/tags/{}", name);
+    ensure!(
+        is_valid_branch_name(&refname),
+        "'{}' is not a valid tag name.",
+        name
+    );
+    ensure!(
+        refs::read_ref(repo, &refname)?.is_none(),
+        "tag '{}' already exists",
+        name
+    );
+    let sha = rev_parse(repo, object)?
+        .with_context(|| format!("Failed to resolve '{}' as a valid ref.", object))?;
+    let otype = match open_object(repo, &sha)? {
+        Some((otype, _, _)) => otype,
+        None => bail!("Failed to resolve '{}' as a valid ref.", object),
+    };
+
+    if !annotate && message.is_none() {
+        return refs::write_ref(repo, &refname, &sha);
+    }
+    let mut message = message.context("no tag message given, use -m")?;
+    if !message.ends_with('\n') {
+        message.push('\n');
+    }
+    let tag = Tag {
+        object: decode_sha(&sha)?,
+        otype,
+        name: name.to_string(),
+        tagger: Some(ident(repo, "COMMITTER")?),
+        extra_headers: vec![],
+        message,
+    };
+    let hash = GitObject::Tag(tag)
+        .write(repo)
+        .context("creating the tag object")?;
+    refs::write_ref(repo, &refname, &hex::encode(hash))
+}
+
+fn hash_commit(repo: &Repo, commit: &Commit) -> Result<[u8; 20]> {
+    use sha1::{Digest, Sha1};
+
+    let buf = commit.to_bytes();
+    let bufsz = buf.len();
+
+    let mut hasher = Sha1::new_with_prefix(format!("commit {}\0", bufsz));
+    hasher.update(&buf);
+    let hash = *hasher.finalize().as_mut();
+    let hex_hash = hex::encode(hash);
+
+    if !repo.has_object_file(&hex_hash) {
+        let obj_db_path = new_obj_path(repo, &hex_hash);
+        encode_object(
+            repo,
+            ObjType::Commit.type_name(),
+            buf.reader(),
+            bufsz.try_into().unwrap(),
+            obj_db_path,
+        )
+        .context("encoding commit into db")?;
+    }
+    Ok(hash)
+}
+
+// shows the history reachable from `head` newest first, by committer date like git does
+fn print_log(repo: &Repo, head: &str, max_count: Option<usize>) -> Result<()> {
+    let shallow = repo.shallow_commits()?;
+    let mut queue = BinaryHeap::new();
+    let mut pending = HashMap::new();
+    let mut seen = HashSet::new();
+    let head_commit = read_commit(repo, head)?;
+    queue.push((head_commit.committer.timestamp, head.to_string()));
+    pending.insert(head.to_string(), head_commit);
+    seen.insert(head.to_string());
+
+    let mut shown = 0;
+    while let Some((_, sha)) = queue.pop() {
+        if max_count.map_or(false, |max| shown >= max) {
+            break;
+        }
+        let commit = pending.remove(&sha).expect("queued commits to be loaded");
+        let parents: Vec<String> = commit.parents.iter().map(hex::encode).collect();
+
+        if shown > 0 {
+            println!();
+        }
+        print_commit_header(&sha, &commit)?;
+        shown += 1;
+
+        // a shallow clone doesn't have anything from before here
+        if shallow.contains(&sha) {
+            continue;
+        }
+        for parent in parents {
+            if seen.insert(parent.clone()) {
+                let parent_commit = read_commit(repo, &parent)?;
+                queue.push((parent_commit.committer.timestamp, parent.clone()));
+                pending.insert(parent, parent_commit);
+            }
+        }
+    }
+    Ok(())
+}
+
+// the way log and show introduce a commit, ending with its indented message
+fn print_commit_header(sha: &str, commit: &Commit) -> Result<()> {
+    println!("commit {}", sha);
+    if commit.parents.len() > 1 {
+        let short: Vec<String> = commit
+            .parents
+            .iter()
+            .map(|p| hex::encode(p)[..7].to_string())
+            .collect();
+        println!("Merge: {}", short.join(" "));
+    }
+    println!("Author: {} <{}>", commit.author.name, commit.author.email);
+    println!(
+        "Date:   {}",
+        format_date(commit.author.timestamp, &commit.author.tz)?
+    );
+    println!();
+    for line in commit.message.trim_end_matches('\n').split('\n') {
+        println!("    {}", line);
+    }
+    Ok(())
+}
+
+// a commit with what it changed, a tag with what it points at, a tree's listing or a blob's
+// content; `name` is how the object was asked for
+fn show(repo: &Repo, name: &str, sha: &str) -> Result<()> {
+    let (otype, _objsz, mut reader) =
+        open_object(repo, sha)?.with_context(|| format!("bad object {}", name))?;
+    let quote_high = repo
+        .config
+        .get::<bool>("core", "quotepath")?
+        .unwrap_or(true);
+    match otype {
+        ObjType::Commit => {
+            let commit = read_commit(repo, sha)?;
+            print_commit_header(sha, &commit)?;
+            // like git without -m or --cc, a merge's changes aren't shown
+            if commit.parents.len() > 1 {
+                return Ok(());
+            }
+            let old_tree = match commit.parents.first() {
+                Some(parent) => {
+                    let parent = read_commit(repo, &hex::encode(parent))?;
+                    read_tree(repo, &hex::encode(parent.tree))?
+                }
+                None => vec![],
+            };
+            let new_tree = read_tree(repo, &hex::encode(commit.tree))?;
+            let mut stdout = std::io::stdout();
+            let mut first = true;
+            diff_trees(
+                repo,
+                &old_tree,
+                &new_tree,
+                b"",
+                true,
+                &mut |name, old, new| {
+                    let mut patch = vec![];
+                    if first {
+                        patch.push(b'\n');
+                        first = false;
+                    }
+                    write_patch(repo, &mut patch, name, old, new, quote_high)?;
+                    stdout.write_all(&patch).context("writing to stdout")
+                },
+            )?;
+        }
+        ObjType::Tag => {
+            let mut content = vec![];
+            reader
+                .read_to_end(&mut content)
+                .with_context(|| format!("reading tag {}", sha))?;
+            let tag =
+                Tag::parse(&content).with_context(|| format!("corrupt tag object {}", sha))?;
+            println!("tag {}", tag.name);
+            if let Some(tagger) = &tag.tagger {
+                println!("Tagger: {} <{}>", tagger.name, tagger.email);
+                println!("Date:   {}", format_date(tagger.timestamp, &tagger.tz)?);
+            }
+            println!();
+            print!("{}", tag.message);
+            println!();
+            let target = hex::encode(tag.object);
+            show(repo, &target, &target)?;
+        }
+        ObjType::Tree => {
+            let tree =
+                parse_tree(&mut reader).with_context(|| format!("corrupt tree object {}", sha))?;
+            let mut out = format!("tree {}\n\n", name).into_bytes();
+            for ent in tree {
+                let mut name = ent.name;
+                if let TreeObjMode::Directory = ent.mode {
+                    name.push(b'/');
+                }
+                out.extend_from_slice(&quote_path(&name, quote_high));
+                out.push(b'\n');
+            }
+            std::io::stdout()
+                .write_all(&out)
+                .context("writing to stdout")?;
+        }
+        ObjType::Blob => {
+            std::io::copy(&mut reader, &mut std::io::stdout())
+                .with_context(|| format!("reading blob {}", sha))?;
+        }
+    }
+    Ok(())
+}
+
+// one path's part of a patch, from the `diff --git` line on
+fn write_patch(
+    repo: &Repo,
+    out: &mut Vec<u8>,
+    name: &[u8],
+    old: Option<&TreeEntry>,
+    new: Option<&TreeEntry>,
+    quote_high: bool,
+) -> Result<()> {
+    // a file that became a symlink or the like can't be diffed, it goes and comes back instead
+    if let (Some(o), Some(n)) = (old, new) {
+        if !same_file_kind(&o.mode, &n.mode) {
+            write_patch(repo, out, name, old, None, quote_high)?;
+            return write_patch(repo, out, name, None, new, quote_high);
+        }
+    }
+    let side_name = |side: &[u8]| quote_path(&[side, name].concat(), quote_high);
+    let (a_name, b_name) = (side_name(b"a/"), side_name(b"b/"));
+    let abbrev = |ent: Option<&TreeEntry>| match ent {
+        Some(ent) => unique_abbrev(repo, &hex::encode(ent.hash), 7),
+        None => Ok(NULL_SHA[..7].to_string()),
+    };
+
+    out.extend_from_slice(b"diff --git ");
+    out.extend_from_slice(&a_name);
+    out.push(b' ');
+    out.extend_from_slice(&b_name);
+    out.push(b'\n');
+    // the mode only goes on the index line when there's no line of its own saying what it is
+    let mode_suffix = match (old, new) {
+        (None, Some(n)) => {
+            out.extend_from_slice(format!("new file mode {}\n", n.mode).as_bytes());
+            String::new()
+        }
+        (Some(o), None) => {
+            out.extend_from_slice(format!("deleted file mode {}\n", o.mode).as_bytes());
+            String::new()
+        }
+        (Some(o), Some(n)) if o.mode.as_bytes() != n.mode.as_bytes() => {
+            out.extend_from_slice(format!("old mode {}\nnew mode {}\n", o.mode, n.mode).as_bytes());
+            String::new()
+        }
+        (Some(o), Some(_)) => format!(" {}", o.mode),
+        (None, None) => unreachable!("a change has at least one side"),
+    };
+    if old.map(|o| o.hash) == new.map(|n| n.hash) {
+        return Ok(());
+    }
+    out.extend_from_slice(
+        format!("index {}..{}{}\n", abbrev(old)?, abbrev(new)?, mode_suffix).as_bytes(),
+    );
+
+    let (old_content, new_content) = (patch_content(repo, old)?, patch_content(repo, new)?);
+    let is_binary = |content: &[u8]| content[..content.len().min(BINARY_CHECK_LEN)].contains(&0);
+    let (old_label, new_label) = (
+        old.map_or(b"/dev/null".to_vec(), |_| a_name.clone()),
+        new.map_or(b"/dev/null".to_vec(), |_| b_name.clone()),
+    );
+    if is_binary(&old_content) || is_binary(&new_content) {
+        out.extend_from_slice(b"Binary files ");
+        out.extend_from_slice(&old_label);
+        out.extend_from_slice(b" and ");
+        out.extend_from_slice(&new_label);
+        out.extend_from_slice(b" differ\n");
+        return Ok(());
+    }
+    let hunks = diff::hunks(&old_content, &new_content);
+    // an empty file added or removed has no lines to show
+    if hunks.is_empty() {
+        return Ok(());
+    }
+    out.extend_from_slice(b"--- ");
+    out.extend_from_slice(&old_label);
+    out.extend_from_slice(b"\n+++ ");
+    out.extend_from_slice(&new_label);
+    out.push(b'\n');
+    out.extend_from_slice(&hunks);
+    Ok(())
 }
 
-fn hash_tree(tree: Vec<TreeEntry>) -> Result<[u8; 20]> {
-    use sha1::{Digest, Sha1};
+// what a side of a patch is made of, nothing if it's missing; a submodule is shown by the commit
+// it's at
+fn patch_content(repo: &Repo, ent: Option<&TreeEntry>) -> Result<Vec<u8>> {
+    let ent = match ent {
+        Some(ent) => ent,
+        None => return Ok(vec![]),
+    };
+    let sha = hex::encode(ent.hash);
+    if let TreeObjMode::Gitlink = ent.mode {
+        return Ok(format!("Subproject commit {}\n", sha).into_bytes());
+    }
+    match GitObject::load(repo, &sha)? {
+        Some(GitObject::Blob(content)) => Ok(content),
+        _ => bail!("unable to read blob {}", sha),
+    }
+}
 
-    let mut buf = BytesMut::with_capacity(tree.len() * 48);
-    for ent in tree {
-        buf.put_slice(&ent.mode.as_bytes());
-        buf.put_u8(b' ');
-        buf.put_slice(ent.name.as_bytes());
-        buf.put_u8(b'\0');
-        buf.put_slice(&ent.hash);
+// the best common ancestors of `a` and `b`: commits reachable from both that aren't themselves
+// an ancestor of another such commit, newest first
+fn merge_bases(repo: &Repo, a: &str, b: &str) -> Result<Vec<String>> {
+    let shallow = repo.shallow_commits()?;
+    let from_a = ancestors(repo, a, &shallow)?;
+    // walking back from `b`, the first commits `a` can reach too are candidates, and there's no
+    // need to look past them
+    let mut candidates = vec![];
+    let mut queue = vec![b.to_string()];
+    let mut seen = HashSet::from([b.to_string()]);
+    while let Some(sha) = queue.pop() {
+        if from_a.contains(&sha) {
+            candidates.push(sha);
+            continue;
+        }
+        for parent in parents(repo, &sha, &shallow)? {
+            if seen.insert(parent.clone()) {
+                queue.push(parent);
+            }
+        }
     }
-    let buf = buf.freeze();
-    let bufsz = buf.len();
-    let bufsz_str = bufsz.to_string();
 
-    let mut header = Vec::with_capacity(5 + bufsz_str.len() + 1);
-    header.extend_from_slice(b"tree ");
-    header.extend_from_slice(bufsz_str.as_bytes());
-    header.push(b'\0');
+    // with criss-cross merges, one candidate can still be reached through another
+    let mut behind = HashSet::new();
+    for sha in &candidates {
+        for parent in parents(repo, sha, &shallow)? {
+            behind.extend(ancestors(repo, &parent, &shallow)?);
+        }
+    }
+    let mut bases = vec![];
+    for sha in candidates {
+        if !behind.contains(&sha) {
+            bases.push((read_commit(repo, &sha)?.committer.timestamp, sha));
+        }
+    }
+    bases.sort_by(|x, y| y.cmp(x));
+    Ok(bases.into_iter().map(|(_, sha)| sha).collect())
+}
 
-    let mut hasher = Sha1::new_with_prefix(header);
-    hasher.update(&buf);
-    let hash = *hasher.finalize().as_mut();
-    let hex_hash = hex::encode(hash);
+// every commit reachable from `sha`, including itself
+fn ancestors(repo: &Repo, sha: &str, shallow: &HashSet<String>) -> Result<HashSet<String>> {
+    let mut seen = HashSet::from([sha.to_string()]);
+    let mut queue = vec![sha.to_string()];
+    while let Some(sha) = queue.pop() {
+        for parent in parents(repo, &sha, shallow)? {
+            if seen.insert(parent.clone()) {
+                queue.push(parent);
+            }
+        }
+    }
+    Ok(seen)
+}
 
-    let obj_db_path = obj_path_from_sha(&hex_hash);
-    if !obj_db_path.exists() {
-        encode_object(
-            ObjType::Tree,
-            buf.reader(),
-            bufsz.try_into().unwrap(),
-            obj_db_path,
-        )
-        .context("encoding tree into db")?;
+// a commit's parents, though in a shallow clone the oldest commits it has are treated as having
+// none
+fn parents(repo: &Repo, sha: &str, shallow: &HashSet<String>) -> Result<Vec<String>> {
+    if shallow.contains(sha) {
+        return Ok(vec![]);
     }
-    Ok(hash)
+    Ok(read_commit(repo, sha)?
+        .parents
+        .iter()
+        .map(hex::encode)
+        .collect())
 }
 
-fn write_tree_recursive(path: &Path) -> Vec<TreeEntry> {
-    let mut res = vec![];
-    let sorted_dirents = {
-        let mut dirents: Vec<std::fs::DirEntry> =
-            path.read_dir().unwrap().map(|re| re.unwrap()).collect();
-        dirents.sort_by_key(|enta| enta.file_name());
-        dirents
-    };
-    for ent in sorted_dirents {
-        if ent.file_name() == ".git" {
-            continue;
-        }
-        let ent = ent.path();
-        let entry_type: ObjType;
-        let entry_mode: TreeObjMode;
-        let entry_hash: [u8; 20];
-        if ent.is_dir() {
-            let tree = write_tree_recursive(&ent);
-            entry_hash = hash_tree(tree).expect("to hash every entry");
-            entry_type = ObjType::Tree;
-            entry_mode = TreeObjMode::Directory;
-        } else {
-            entry_hash = hash_object(&ent, true).expect("to hash every entry");
-            entry_type = ObjType::Blob;
-            entry_mode = TreeObjMode::RegularFile;
-        }
-        res.push(TreeEntry {
-            name: ent
-                .file_name()
-                .unwrap_or_else(|| {
-                    panic!(
-                        "entry `{}` has a file name since it isn't a dir",
-                        ent.to_string_lossy()
-                    )
-                })
-                .to_string_lossy()
-                .to_string(),
-            hash: entry_hash,
-            mode: entry_mode,
-            otype: entry_type,
-        })
+fn read_commit(repo: &Repo, sha: &str) -> Result<Commit> {
+    match GitObject::load(repo, sha)? {
+        Some(GitObject::Commit(commit)) => Ok(commit),
+        Some(obj) => bail!(
+            "{} is not a commit (found {})",
+            sha,
+            obj.obj_type().type_name()
+        ),
+        None => bail!("commit {} is missing from the object db", sha),
     }
-    res
 }
 
-fn hash_commit(commit: &Commit) -> Result<[u8; 20]> {
-    use sha1::{Digest, Sha1};
+fn decode_sha(hex_sha: &str) -> Result<[u8; 20]> {
+    let mut hash = [0u8; 20];
+    hex::decode_to_slice(hex_sha, &mut hash)
+        .with_context(|| format!("invalid object id {}", hex_sha))?;
+    Ok(hash)
+}
 
-    let mut buf = BytesMut::with_capacity(
-        4 // "tree"
-            + 1 // 0x20
-            + commit.tree.len() * 2
-            + 1 // 0x0A
-            + 6 // "parent"
-            + 1 // 0x20
-            + commit.parent.len() * 2
-            + 1 // 0x0A
-            + 6 // "author"
-            + 1 // 0x20
-            + commit.author_name.len()
-            + 1 // 0x20
-            + 16 // author timestamp
-            + 1 // 0x0A
-            + 9 // "committer"
-            + 1 // 0x20
-            + commit.committer_name.len()
-            + 1 // 0x20
-            + 16 // committer timestamp
-            + 2 // double 0x0A
-            + commit.message.len()
-            + 1, // 0x0A
+// git's default date format, e.g. `Thu Oct 14 09:30:00 2026 +0200`, in the given timezone
+fn format_date(unix_time: i64, tz: &str) -> Result<String> {
+    ensure!(
+        tz.len() == 5
+            && (tz.starts_with('+') || tz.starts_with('-'))
+            && tz[1..].chars().all(|c| c.is_ascii_digit()),
+        "malformed timezone offset: {}",
+        tz
     );
+    let offset_mins = tz[1..3].parse::<i64>()? * 60 + tz[3..].parse::<i64>()?;
+    let offset = if tz.starts_with('-') {
+        -offset_mins
+    } else {
+        offset_mins
+    } * 60;
 
-    buf.put_slice(b"tree");
-    buf.put_u8(0x20);
-    buf.put_slice(hex::encode(commit.tree).as_bytes());
-    buf.put_u8(0x0a);
-    buf.put_slice(b"parent");
-    buf.put_u8(0x20);
-    buf.put_slice(hex::encode(commit.parent).as_bytes());
-    buf.put_u8(0x0a);
-    buf.put_slice(b"author");
-    buf.put_u8(0x20);
-    buf.put_slice(commit.author_name.as_bytes());
-    buf.put_u8(0x20);
-    buf.put_slice(commit.author_timestamp.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs().to_string().as_bytes());
-    buf.put_slice(b" +0000");
-    buf.put_u8(0x0a);
-    buf.put_slice(b"committer");
-    buf.put_u8(0x20);
-    buf.put_slice(commit.committer_name.as_bytes());
-    buf.put_u8(0x20);
-    buf.put_slice(commit.committer_timestamp.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs().to_string().as_bytes());
-    buf.put_slice(b" +0000");
-    buf.put_u8(0x0a);
-    buf.put_u8(0x0a);
-    buf.put_slice(commit.message.as_bytes());
-    buf.put_u8(0x0a);
+    let local = unix_time + offset;
+    let days = local.div_euclid(86400);
+    let secs = local.rem_euclid(86400);
 
-    let bufsz = buf.len();
-    let hasher = Sha1::new_with_prefix(&buf);
-    let hash = *hasher.finalize().as_mut();
-    let hex_hash = hex::encode(hash);
+    // days since the epoch to a civil date, from Howard Hinnant's date algorithms
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
 
-    let obj_db_path = obj_path_from_sha(&hex_hash);
-    if !obj_db_path.exists() {
-        encode_object(
-            ObjType::Commit,
-            buf.reader(),
-            bufsz.try_into().unwrap(),
-            obj_db_path,
-        )
-        .context("encoding tree into db")?;
-    }
-    Ok(hash)
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    Ok(format!(
+        "{} {} {} {:02}:{:02}:{:02} {} {}",
+        WEEKDAYS[days.rem_euclid(7) as usize],
+        MONTHS[(month - 1) as usize],
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        year,
+        tz
+    ))
 }
 
 fn encode_object<P: AsRef<Path>, R: Read>(
-    otype: ObjType,
-    mut input: R,
+    repo: &Repo,
+    type_name: &str,
+    input: R,
     filesz: u64,
     obj_db_path: P,
 ) -> Result<()> {
@@ -428,24 +3538,112 @@ fn encode_object<P: AsRef<Path>, R: Read>(
         )
     })?;
 
-    if obj_db_dir.exists() {
-        ensure!(
-            obj_db_dir.is_dir(),
-            "object database should only have directories at top level"
-        );
-    } else {
-        std::fs::create_dir(obj_db_dir).context("creating prefix dir in obj db")?;
+    create_prefix_dir(obj_db_dir).map_err(|e| insufficient_permission(repo, e))?;
+
+    // write to a temp file first and move it into place once complete, so an interrupted write
+    // never leaves a corrupt object at the final path
+    let tmp_path = tmp_object_path(
+        obj_db_dir
+            .parent()
+            .context("object prefix dir should be inside the object db")?,
+    );
+
+    if let Err(e) = write_object_file(
+        type_name,
+        input,
+        filesz,
+        &tmp_path,
+        repo.loose_compression()?,
+    ) {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(insufficient_permission(repo, e));
+    }
+
+    move_object_into_place(&tmp_path, obj_db_path).map_err(|e| {
+        let _ = std::fs::remove_file(&tmp_path);
+        insufficient_permission(repo, e)
+    })
+}
+
+// an object database that can't be written to gets git's own report rather than whichever step
+// happened to fail
+fn insufficient_permission(repo: &Repo, e: anyhow::Error) -> anyhow::Error {
+    let denied = e.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .map_or(false, |e| e.kind() == std::io::ErrorKind::PermissionDenied)
+    });
+    if !denied {
+        return e;
+    }
+    // named from the top of the worktree, where commands run
+    let objects_dir = repo.objects_dir();
+    let shown = std::env::current_dir()
+        .ok()
+        .and_then(|cwd| objects_dir.strip_prefix(cwd).ok())
+        .unwrap_or(&objects_dir);
+    GitError::NoPermission(format!(
+        "insufficient permission for adding an object to repository database {}",
+        shown.to_string_lossy()
+    ))
+    .into()
+}
+
+// an object being rewritten is read-only like any other, which would stop it being replaced on
+// some platforms, so it's made writable first
+fn move_object_into_place(tmp_path: &Path, obj_db_path: &Path) -> Result<()> {
+    #[cfg(unix)]
+    if let Ok(meta) = std::fs::symlink_metadata(obj_db_path) {
+        let mut perms = meta.permissions();
+        perms.set_mode(perms.mode() | 0o200);
+        std::fs::set_permissions(obj_db_path, perms)
+            .context("making the object being replaced writable")?;
+    }
+    std::fs::rename(tmp_path, obj_db_path).context("moving finished object into place in db")
+}
+
+// another thread may be writing an object with the same two-char prefix at the same time
+fn create_prefix_dir(obj_db_dir: &Path) -> Result<()> {
+    match std::fs::create_dir(obj_db_dir) {
+        Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => {
+            Err(e).context("creating prefix dir in obj db")
+        }
+        _ => {
+            ensure!(
+                obj_db_dir.is_dir(),
+                "object database should only have directories at top level"
+            );
+            Ok(())
+        }
     }
+}
 
+static TMP_OBJ_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+fn tmp_object_path<P: AsRef<Path>>(objects_dir: P) -> PathBuf {
+    objects_dir.as_ref().join(format!(
+        "tmp_obj_{}_{}",
+        std::process::id(),
+        TMP_OBJ_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+fn write_object_file<R: Read>(
+    type_name: &str,
+    mut input: R,
+    filesz: u64,
+    tmp_path: &Path,
+    level: flate2::Compression,
+) -> Result<()> {
     let outputfile = OpenOptions::new()
-        .create(true)
+        .create_new(true)
         .write(true)
-        .open(obj_db_path)
-        .context("Failed to open file for writing object to db")?;
+        .open(tmp_path)
+        .context("Failed to open temp file for writing object to db")?;
 
-    let header = format!("{} {}\0", otype.type_name(), filesz);
+    let header = format!("{} {}\0", type_name, filesz);
 
-    let mut compressedout = ZlibEncoder::new(outputfile, flate2::Compression::default());
+    let mut compressedout = ZlibEncoder::new(outputfile, level);
 
     compressedout
         .write_all(header.as_bytes())
@@ -454,9 +3652,17 @@ fn encode_object<P: AsRef<Path>, R: Read>(
     std::io::copy(&mut input, &mut compressedout)
         .context("copying given file's contents to object in db")?;
 
+    let outputfile = compressedout
+        .finish()
+        .context("finishing compression of object file in db")?;
+    outputfile
+        .sync_all()
+        .context("flushing object file in db to disk")?;
+
+    // set file read-only (i.e. 0400) once it's been written, as og impl does; on windows
+    // the read-only attribute would instead stop the object ever being replaced or deleted
+    #[cfg(unix)]
     {
-        // set file read-only (i.e. 0400) once it's been written, as og impl does
-        let outputfile = compressedout.get_ref();
         let mut perms = outputfile
             .metadata()
             .context("getting db obj file metadata, after writing")?
@@ -469,9 +3675,8 @@ fn encode_object<P: AsRef<Path>, R: Read>(
     Ok(())
 }
 
-#[allow(dead_code)]
+#[derive(Clone, Copy)]
 enum ObjType {
-    None,
     Commit,
     Tree,
     Blob,
@@ -479,52 +3684,123 @@ enum ObjType {
 }
 
 impl ObjType {
+    // nothing for a type name that isn't one of the four git knows
+    fn from(name: &[u8]) -> Option<Self> {
+        match name {
+            b"commit" => Some(ObjType::Commit),
+            b"tree" => Some(ObjType::Tree),
+            b"blob" => Some(ObjType::Blob),
+            b"tag" => Some(ObjType::Tag),
+            _ => None,
+        }
+    }
+
     fn type_name(&self) -> &'static str {
         match self {
             ObjType::Commit => "commit",
             ObjType::Tree => "tree",
             ObjType::Blob => "blob",
             ObjType::Tag => "tag",
-            _ => unimplemented!("unexpected object type for type_name()"),
         }
     }
 }
 
-trait DbObj {}
+// any object from the db, parsed according to its type
+enum GitObject {
+    Blob(Vec<u8>),
+    Tree(Vec<TreeEntry>),
+    Commit(Commit),
+    Tag(Tag),
+}
+
+impl GitObject {
+    fn load(repo: &Repo, sha: &str) -> Result<Option<Self>> {
+        match open_object(repo, sha)? {
+            Some((otype, _objsz, reader)) => GitObject::decode(otype, reader)
+                .with_context(|| format!("corrupt {} object {}", otype.type_name(), sha))
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // parses the content that follows an object's header
+    fn decode<R: BufRead>(otype: ObjType, mut reader: R) -> Result<Self> {
+        // a tree is parsed as it's read, everything else once it's all been read
+        let mut content = vec![];
+        if !matches!(otype, ObjType::Tree) {
+            reader
+                .read_to_end(&mut content)
+                .context("reading object content")?;
+        }
+        match otype {
+            ObjType::Tree => parse_tree(reader).map(GitObject::Tree),
+            ObjType::Blob => Ok(GitObject::Blob(content)),
+            ObjType::Commit => Commit::parse(&content).map(GitObject::Commit),
+            ObjType::Tag => Tag::parse(&content).map(GitObject::Tag),
+        }
+    }
+
+    fn obj_type(&self) -> ObjType {
+        match self {
+            GitObject::Blob(_) => ObjType::Blob,
+            GitObject::Tree(_) => ObjType::Tree,
+            GitObject::Commit(_) => ObjType::Commit,
+            GitObject::Tag(_) => ObjType::Tag,
+        }
+    }
+
+    // the content as stored after the header
+    fn to_bytes(&self) -> Bytes {
+        match self {
+            GitObject::Blob(content) => Bytes::copy_from_slice(content),
+            GitObject::Tree(tree) => tree_to_bytes(tree),
+            GitObject::Commit(commit) => commit.to_bytes(),
+            GitObject::Tag(tag) => tag.to_bytes(),
+        }
+    }
 
-//struct Blob {}
+    fn write(&self, repo: &Repo) -> Result<[u8; 20]> {
+        hash_bytes(self.obj_type(), &self.to_bytes(), Some(repo))
+    }
+}
 
 #[derive(Debug)]
 enum TreeObjMode {
     Directory,
     RegularFile,
-    #[allow(dead_code)]
     ExecutableFile,
     Link,
+    Gitlink,
 }
 
 impl TreeObjMode {
-    fn from(bytes: &[u8]) -> Self {
-        match bytes[0] {
-            b'1' => match bytes[1] {
-                b'0' => Self::RegularFile,
-                b'2' => Self::Link,
-                unk => {
-                    unimplemented!("unknown object type: 0{:o}", unk);
-                }
-            },
-            b'4' => Self::Directory,
-            unk => {
-                unimplemented!("unknown object type: {:o}", unk);
-            }
+    fn from(bytes: &[u8]) -> Result<Self> {
+        match bytes {
+            b"100644" => Ok(Self::RegularFile),
+            b"100755" => Ok(Self::ExecutableFile),
+            b"120000" => Ok(Self::Link),
+            b"160000" => Ok(Self::Gitlink),
+            b"40000" | b"040000" => Ok(Self::Directory),
+            unk => bail!("unknown object mode: {}", String::from_utf8_lossy(unk)),
+        }
+    }
+
+    fn obj_type(&self) -> ObjType {
+        match &self {
+            Self::Directory => ObjType::Tree,
+            Self::RegularFile | Self::ExecutableFile | Self::Link => ObjType::Blob,
+            // submodules are recorded as the commit they're checked out at
+            Self::Gitlink => ObjType::Commit,
         }
     }
 
     fn as_bytes(&self) -> Bytes {
         match &self {
             Self::RegularFile => Bytes::from_static(b"100644"),
+            Self::ExecutableFile => Bytes::from_static(b"100755"),
+            Self::Link => Bytes::from_static(b"120000"),
+            Self::Gitlink => Bytes::from_static(b"160000"),
             Self::Directory => Bytes::from_static(b"40000"),
-            _ => unimplemented!(),
         }
     }
 }
@@ -534,7 +3810,9 @@ impl std::fmt::Display for TreeObjMode {
         match &self {
             TreeObjMode::Directory => write!(f, "040000"),
             TreeObjMode::RegularFile => write!(f, "100644"),
-            omode => unimplemented!("can't display mode {:?}", omode),
+            TreeObjMode::ExecutableFile => write!(f, "100755"),
+            TreeObjMode::Link => write!(f, "120000"),
+            TreeObjMode::Gitlink => write!(f, "160000"),
         }
     }
 }
@@ -543,77 +3821,654 @@ struct TreeEntry {
     mode: TreeObjMode,
     otype: ObjType,
     hash: [u8; 20],
+    name: Vec<u8>,
+}
+
+impl TreeEntry {
+    // what ls-tree shows ahead of the name; `abbrev` shortens the hash to that many digits
+    fn ls_prefix(&self, abbrev: Option<usize>) -> String {
+        format!(
+            "{} {} {}\t",
+            self.mode,
+            self.otype.type_name(),
+            abbrev_hash(&self.hash, abbrev)
+        )
+    }
+}
+
+// like git, never shorter than 4 digits
+fn abbrev_hash(hash: &[u8; 20], digits: Option<usize>) -> String {
+    let mut hex = hex::encode(hash);
+    if let Some(digits) = digits {
+        hex.truncate(digits.max(4));
+    }
+    hex
+}
+
+struct Signature {
     name: String,
+    email: String,
+    timestamp: i64,
+    // offset from UTC the way git writes it, e.g. `-0700`
+    tz: String,
+}
+
+impl Signature {
+    // `Name <email> <unix time> <tz offset>`, as on author and committer lines
+    fn parse(value: &str) -> Result<Self> {
+        let mut parts = value.rsplitn(3, ' ');
+        let tz = parts.next().unwrap_or_default();
+        let timestamp = parts.next().and_then(|t| t.parse().ok());
+        let ident = parts
+            .next()
+            .and_then(|ident| ident.strip_suffix('>'))
+            .and_then(|ident| ident.rsplit_once('<'));
+        match (ident, timestamp) {
+            (Some((name, email)), Some(timestamp)) => Ok(Signature {
+                name: name.trim_end().to_string(),
+                email: email.to_string(),
+                timestamp,
+                tz: tz.to_string(),
+            }),
+            _ => bail!("malformed ident: {}", value),
+        }
+    }
 }
 
-impl std::fmt::Display for TreeEntry {
+impl std::fmt::Display for Signature {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "{} {} {}\t{}",
-            self.mode,
-            self.otype.type_name(),
-            hex::encode(self.hash),
-            self.name
+            "{} <{}> {} {}",
+            self.name, self.email, self.timestamp, self.tz
         )
     }
 }
 
 struct Commit {
     tree: [u8; 20],
-    parent: [u8; 20],
-    author_name: String,
-    author_timestamp: SystemTime,
-    committer_name: String,
-    committer_timestamp: SystemTime,
+    parents: Vec<[u8; 20]>,
+    author: Signature,
+    committer: Signature,
+    // anything after the committer (gpgsig, encoding, ...) is kept so the commit re-serializes to
+    // the same bytes
+    extra_headers: Vec<(String, String)>,
+    message: String,
+}
+
+impl Commit {
+    // a commit by whoever the environment or config says, made now
+    fn new(
+        repo: &Repo,
+        tree: [u8; 20],
+        parents: Vec<[u8; 20]>,
+        mut message: String,
+    ) -> Result<Self> {
+        if !message.ends_with('\n') {
+            message.push('\n');
+        }
+        Ok(Commit {
+            tree,
+            parents,
+            author: ident(repo, "AUTHOR")?,
+            committer: ident(repo, "COMMITTER")?,
+            extra_headers: vec![],
+            message,
+        })
+    }
+
+    fn parse(content: &[u8]) -> Result<Self> {
+        let content = String::from_utf8_lossy(content);
+        let (headers, message) = split_headers(&content)?;
+
+        // git always writes tree, parents, author and committer first and in that order
+        let mut headers = headers.into_iter().peekable();
+        let tree = decode_sha(&expect_header(&mut headers, "tree")?)?;
+        let mut parents = vec![];
+        while let Some((_, parent)) = headers.next_if(|(k, _)| k == "parent") {
+            parents.push(decode_sha(&parent)?);
+        }
+        let author = Signature::parse(&expect_header(&mut headers, "author")?)?;
+        let committer = Signature::parse(&expect_header(&mut headers, "committer")?)?;
+
+        Ok(Commit {
+            tree,
+            parents,
+            author,
+            committer,
+            extra_headers: headers.collect(),
+            message: message.to_string(),
+        })
+    }
+
+    fn to_bytes(&self) -> Bytes {
+        let mut buf = BytesMut::new();
+        buf.put_slice(format!("tree {}\n", hex::encode(self.tree)).as_bytes());
+        for parent in &self.parents {
+            buf.put_slice(format!("parent {}\n", hex::encode(parent)).as_bytes());
+        }
+        buf.put_slice(format!("author {}\n", self.author).as_bytes());
+        buf.put_slice(format!("committer {}\n", self.committer).as_bytes());
+        for (key, value) in &self.extra_headers {
+            buf.put_slice(format!("{} {}\n", key, value.replace('\n', "\n ")).as_bytes());
+        }
+        buf.put_u8(b'\n');
+        buf.put_slice(self.message.as_bytes());
+        buf.freeze()
+    }
+}
+
+struct Tag {
+    object: [u8; 20],
+    otype: ObjType,
+    name: String,
+    // very old tags were made without a tagger line
+    tagger: Option<Signature>,
+    extra_headers: Vec<(String, String)>,
     message: String,
 }
 
-//struct Tag {}
+impl Tag {
+    fn parse(content: &[u8]) -> Result<Self> {
+        let content = String::from_utf8_lossy(content);
+        let (headers, message) = split_headers(&content)?;
+
+        let mut headers = headers.into_iter().peekable();
+        let object = decode_sha(&expect_header(&mut headers, "object")?)?;
+        let type_name = expect_header(&mut headers, "type")?;
+        let otype = ObjType::from(type_name.as_bytes())
+            .with_context(|| format!("unknown object type {}", type_name))?;
+        let name = expect_header(&mut headers, "tag")?;
+        let tagger = match headers.next_if(|(k, _)| k == "tagger") {
+            Some((_, tagger)) => Some(Signature::parse(&tagger)?),
+            None => None,
+        };
+
+        Ok(Tag {
+            object,
+            otype,
+            name,
+            tagger,
+            extra_headers: headers.collect(),
+            message: message.to_string(),
+        })
+    }
+
+    fn to_bytes(&self) -> Bytes {
+        let mut buf = BytesMut::new();
+        buf.put_slice(format!("object {}\n", hex::encode(self.object)).as_bytes());
+        buf.put_slice(format!("type {}\n", self.otype.type_name()).as_bytes());
+        buf.put_slice(format!("tag {}\n", self.name).as_bytes());
+        if let Some(tagger) = &self.tagger {
+            buf.put_slice(format!("tagger {}\n", tagger).as_bytes());
+        }
+        for (key, value) in &self.extra_headers {
+            buf.put_slice(format!("{} {}\n", key, value.replace('\n', "\n ")).as_bytes());
+        }
+        buf.put_u8(b'\n');
+        buf.put_slice(self.message.as_bytes());
+        buf.freeze()
+    }
+}
+
+// splits a commit or tag body into its header lines and the message after the first blank line
+fn split_headers(content: &str) -> Result<(Vec<(String, String)>, &str)> {
+    let (header_block, message) = content
+        .split_once("\n\n")
+        .unwrap_or((content.trim_end_matches('\n'), ""));
+
+    let mut headers: Vec<(String, String)> = vec![];
+    for line in header_block.split('\n') {
+        // multi-line values like gpgsig continue on lines starting with a space
+        if let Some(continued) = line.strip_prefix(' ') {
+            let (_, value) = headers
+                .last_mut()
+                .context("header continues before it starts")?;
+            value.push('\n');
+            value.push_str(continued);
+            continue;
+        }
+        let (key, value) = line
+            .split_once(' ')
+            .with_context(|| format!("malformed header line: {}", line))?;
+        headers.push((key.to_string(), value.to_string()));
+    }
+    Ok((headers, message))
+}
+
+fn expect_header(
+    headers: &mut impl Iterator<Item = (String, String)>,
+    key: &str,
+) -> Result<String> {
+    match headers.next() {
+        Some((k, v)) if k == key => Ok(v),
+        _ => bail!("missing {} line", key),
+    }
+}
+
+type DecodedObject = (ObjType, usize, Box<dyn BufRead>);
+
+fn open_object(repo: &Repo, obj_sha: &str) -> Result<Option<DecodedObject>> {
+    if let Ok(objfile) = File::open(obj_path_from_sha(repo, obj_sha)) {
+        let (otype, objsz, reader) = object_decoder(objfile, obj_sha)?;
+        return Ok(Some((otype, objsz, Box::new(reader))));
+    }
+
+    // not a loose object, so it may have been packed
+    let mut hash = [0u8; 20];
+    hex::decode_to_slice(obj_sha, &mut hash).context("decoding object sha")?;
+    let packed = pack::find_object(repo, &hash)?.map(|(otype, content)| {
+        let objsz = content.len();
+        let reader: Box<dyn BufRead> = Box::new(std::io::Cursor::new(content));
+        (otype, objsz, reader)
+    });
+    Ok(packed)
+}
+
+// whether an object's content still hashes to the name it's stored under, streaming it through
+// so even huge blobs take constant memory
+fn verify_object(repo: &Repo, obj_sha: &str) -> Result<Option<bool>> {
+    let (otype, objsz, reader) = match open_object(repo, obj_sha)? {
+        Some(obj) => obj,
+        None => return Ok(None),
+    };
+    let mut hashing = HashingReader::new(reader, otype, objsz as u64);
+    let intact = std::io::copy(&mut hashing, &mut std::io::sink()).is_ok()
+        && hashing
+            .finish()
+            .map_or(false, |hash| hex::encode(hash) == obj_sha);
+    Ok(Some(intact))
+}
+
+fn object_decoder(
+    object: File,
+    obj_sha: &str,
+) -> Result<(ObjType, usize, BufReader<LooseObjectReader>)> {
+    let mut brzdf = loose_object_reader(object, obj_sha);
+    let (type_name, objsz) = read_loose_header(&mut brzdf)?;
+    let otype = ObjType::from(type_name.as_bytes())
+        .with_context(|| format!("invalid object type \"{}\"", type_name))?;
+    Ok((otype, objsz, brzdf))
+}
+
+fn loose_object_reader(object: File, obj_sha: &str) -> BufReader<LooseObjectReader> {
+    BufReader::new(LooseObjectReader {
+        inner: ZlibDecoder::new(object),
+        obj_sha: obj_sha.to_string(),
+    })
+}
+
+// zlib failing partway through doesn't look like the end of the content, and says which object
+// is damaged
+struct LooseObjectReader {
+    inner: ZlibDecoder<File>,
+    obj_sha: String,
+}
+
+impl Read for LooseObjectReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("corrupt loose object {}: {}", self.obj_sha, e),
+            )
+        })
+    }
+}
 
-fn object_decoder(object: File) -> (ObjType, usize, BufReader<ZlibDecoder<File>>) {
-    let mut z = ZlibDecoder::new(object);
+// a damaged stream is reported as such, anything else wrong with the header as unrecognized
+fn read_loose_header<R: BufRead>(reader: &mut R) -> Result<(String, usize)> {
+    read_object_header(reader).map_err(|e| match e.downcast_ref::<std::io::Error>() {
+        Some(_) => e,
+        None => e.context("unrecognized object header"),
+    })
+}
 
-    let mut magic = [0u8; 4];
-    if let Err(e) = z.read_exact(&mut magic) {
-        panic!("{}", e); // TODO
+// the type and size an object says it has, even when git wouldn't know what to do with the type
+fn object_header(repo: &Repo, obj_sha: &str) -> Result<Option<(String, usize)>> {
+    if let Ok(objfile) = File::open(obj_path_from_sha(repo, obj_sha)) {
+        let mut brzdf = loose_object_reader(objfile, obj_sha);
+        return read_loose_header(&mut brzdf).map(Some);
     }
-    let mut brzdf = BufReader::new(z);
+    // packs can only hold the four known types
+    let header =
+        open_object(repo, obj_sha)?.map(|(otype, objsz, _)| (otype.type_name().to_string(), objsz));
+    Ok(header)
+}
+
+// anything that isn't `<type> <length>\0` is refused rather than guessed at; read errors are
+// passed on as they are
+fn read_object_header<R: BufRead>(reader: &mut R) -> Result<(String, usize)> {
+    let mut otype = vec![];
+    reader.read_until(b' ', &mut otype)?;
+    ensure!(
+        otype.pop() == Some(b' '),
+        "object header is missing its type"
+    );
+
     let mut objsz = vec![];
-    match &magic {
-        b"blob" => {
-            brzdf
-                .read_exact(&mut [0u8; 1])
-                .expect("to consume space before object length in header");
-            brzdf
-                .read_until(0u8, &mut objsz)
-                .expect("object has >5 bytes");
-            objsz.pop(); // remove terminating null byte before parsing
-            let objsz = usize::from_str(&String::from_utf8(objsz).unwrap())
-                .expect("blob header concludes with object len");
-
-            (ObjType::Blob, objsz, brzdf)
-        }
-        b"tree" => {
-            brzdf
-                .read_exact(&mut [0u8; 1])
-                .expect("to consume space before object length in header");
-            brzdf
-                .read_until(0u8, &mut objsz)
-                .expect("object has >5 bytes");
-            objsz.pop(); // remove terminating null byte before parsing
-            let objsz = usize::from_str(&String::from_utf8(objsz).unwrap())
-                .expect("blob header concludes with object len");
-
-            (ObjType::Tree, objsz, brzdf)
-        }
-        b"comm" => {
-            brzdf
-                .read_exact(&mut [0u8; 3])
-                .expect("to consume \"it \" before object length in header");
-            (ObjType::Commit, 0, brzdf)
-        }
-        b"tag " => (ObjType::Tag, 0, brzdf),
-        _ => (ObjType::Blob, 0, brzdf),
+    reader.read_until(0u8, &mut objsz)?;
+    ensure!(
+        objsz.pop() == Some(0),
+        "object header is missing its length"
+    );
+    let objsz = std::str::from_utf8(&objsz)
+        .ok()
+        .and_then(|sz| usize::from_str(sz).ok())
+        .context("object header has a bad length")?;
+
+    Ok((String::from_utf8_lossy(&otype).into_owned(), objsz))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_object_matches_git() {
+        // by way of the file, whose content has to come to the size the header was given
+        let dir = scratch::scratch_dir("hash-object");
+        let file = dir.join("hello");
+        std::fs::write(&file, "hello\n").unwrap();
+        let blob = hash_object(&file, ObjType::Blob, false, None).unwrap();
+        assert_eq!(
+            hex::encode(blob),
+            "ce013625030ba8dba906f756967f9e9ca394464a"
+        );
+
+        let mut tree = b"100644 hello\0".to_vec();
+        tree.extend_from_slice(&blob);
+        let tree_hash = hash_content(ObjType::Tree, &tree[..], tree.len() as u64).unwrap();
+        assert_eq!(
+            hex::encode(tree_hash),
+            "b4d01e9b0c4a9356736dfddf8830ba9a54f5271c"
+        );
+    }
+
+    // writes `raw` zlib-compressed where a loose object would be, cut short to `keep` bytes; the
+    // open file can still be read once its scratch dir is gone
+    fn loose_file(name: &str, raw: &[u8], keep: Option<usize>) -> File {
+        let mut enc = ZlibEncoder::new(vec![], flate2::Compression::default());
+        enc.write_all(raw).unwrap();
+        let mut compressed = enc.finish().unwrap();
+        compressed.truncate(keep.unwrap_or(compressed.len()));
+        let dir = scratch::scratch_dir(name);
+        let path = dir.join("object");
+        std::fs::write(&path, compressed).unwrap();
+        File::open(path).unwrap()
+    }
+
+    #[test]
+    fn object_decoder_reads_header() {
+        let objects: [(&[u8], ObjType); 4] = [
+            (b"blob", ObjType::Blob),
+            (b"tree", ObjType::Tree),
+            (b"commit", ObjType::Commit),
+            (b"tag", ObjType::Tag),
+        ];
+        for (type_name, otype) in objects {
+            let mut raw = type_name.to_vec();
+            raw.extend_from_slice(b" 6\0hello\n");
+            let name = format!("decoder-{}", String::from_utf8_lossy(type_name));
+            let (found, objsz, mut reader) =
+                object_decoder(loose_file(&name, &raw, None), "x").unwrap();
+            assert_eq!(found.type_name(), otype.type_name());
+            assert_eq!(objsz, 6);
+            let mut content = vec![];
+            reader.read_to_end(&mut content).unwrap();
+            assert_eq!(content, b"hello\n");
+        }
+
+        let mut reader = &b"commit 12"[..];
+        let err = read_object_header(&mut reader).unwrap_err();
+        assert_eq!(err.to_string(), "object header is missing its length");
+    }
+
+    #[test]
+    fn object_decoder_refuses_bad_header() {
+        // xorshift, so the same "random" bytes every run
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let noise: Vec<u8> = (0..256)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        for raw in [&noise[..], b"blob six\0hello\n", b"blob 6", b"blob"] {
+            let err = object_decoder(loose_file("decoder-bad", raw, None), "x")
+                .err()
+                .expect("a bad header to be refused");
+            assert!(
+                format!("{:#}", err).starts_with("unrecognized object header"),
+                "{:#}",
+                err
+            );
+        }
+
+        let err = object_decoder(loose_file("decoder-bad", b"blorb 6\0hello\n", None), "x")
+            .err()
+            .expect("an unknown type to be refused");
+        assert_eq!(err.to_string(), "invalid object type \"blorb\"");
+    }
+
+    #[test]
+    fn open_object_reports_empty_file() {
+        let dir = scratch::scratch_dir("empty-object");
+        let git_dir = dir.join(".git");
+        let repo = Repo::new(&git_dir);
+        let obj_sha = "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391";
+        let obj_path = obj_path_from_sha(&repo, obj_sha);
+        std::fs::create_dir_all(obj_path.parent().unwrap()).unwrap();
+        File::create(&obj_path).unwrap();
+
+        let err = GitError::from(open_object(&repo, obj_sha).err().expect("an error"));
+        assert!(err.to_string().starts_with("fatal: "), "{}", err);
+        assert_eq!(err.code(), 128);
+    }
+
+    #[test]
+    fn object_decoder_reports_truncated_stream() {
+        // cut off inside the header, and again partway through the content
+        let raw = [&b"blob 4096\0"[..], &[7u8; 4096]].concat();
+        let err = object_decoder(loose_file("decoder-short", &raw, Some(4)), "abc")
+            .err()
+            .expect("a truncated header to be refused");
+        assert!(
+            format!("{:#}", err).contains("corrupt loose object abc"),
+            "{:#}",
+            err
+        );
+
+        let (_, objsz, mut reader) =
+            object_decoder(loose_file("decoder-short", &raw, Some(20)), "abc").unwrap();
+        assert_eq!(objsz, 4096);
+        let mut content = vec![];
+        let err = reader.read_to_end(&mut content).unwrap_err();
+        assert!(
+            err.to_string().contains("corrupt loose object abc"),
+            "{}",
+            err
+        );
+    }
+
+    // a repo of the test's own, with just enough of a git dir to read and write objects, which
+    // lasts as long as its scratch dir
+    fn scratch_repo(name: &str) -> (scratch::ScratchDir, Repo) {
+        let dir = scratch::scratch_dir(name);
+        let git_dir = dir.join(".git");
+        std::fs::create_dir_all(git_dir.join("objects")).unwrap();
+        (dir, Repo::new(git_dir))
+    }
+
+    #[test]
+    fn truncated_tree_is_corrupt() {
+        let (_dir, repo) = scratch_repo("truncated-tree");
+        // cut off partway through the entry's hash
+        let tree = [&b"100644 hello\0"[..], &[0xce, 0x01, 0x36]].concat();
+        let sha = hex::encode(hash_bytes(ObjType::Tree, &tree, Some(&repo)).unwrap());
+
+        let err = GitObject::load(&repo, &sha)
+            .err()
+            .expect("a truncated tree to be refused");
+        let err = GitError::from(err);
+        assert!(
+            err.to_string()
+                .starts_with(&format!("fatal: corrupt tree object {}", sha)),
+            "{}",
+            err
+        );
+        assert_eq!(err.code(), 128);
+    }
+
+    #[test]
+    fn tree_obj_mode_round_trips() {
+        let modes = [
+            (TreeObjMode::Directory, "040000"),
+            (TreeObjMode::RegularFile, "100644"),
+            (TreeObjMode::ExecutableFile, "100755"),
+            (TreeObjMode::Link, "120000"),
+            (TreeObjMode::Gitlink, "160000"),
+        ];
+        for (mode, shown) in modes {
+            let parsed = TreeObjMode::from(&mode.as_bytes()).unwrap();
+            assert_eq!(format!("{:?}", parsed), format!("{:?}", mode));
+            assert_eq!(mode.to_string(), shown);
+        }
+    }
+
+    #[test]
+    fn commit_round_trips() {
+        // made by git, with an extra header and a multi-line message
+        let raw = b"tree 89c3397e0d3d6b3640be7d0bd7e7f34cb26ffbfd\n\
+            parent 0bcefa70a830b838753627d9d3073edddbc39c7e\n\
+            author Ada Lovelace <ada@example.com> 1700000000 -0700\n\
+            committer Charles Babbage <charles@example.com> 1700000100 +0200\n\
+            encoding ISO-8859-1\n\
+            \n\
+            Add a second line\n\
+            \n\
+            The body runs over\n\
+            more than one line.\n";
+        let commit = Commit::parse(raw).unwrap();
+        assert_eq!(
+            hex::encode(commit.tree),
+            "89c3397e0d3d6b3640be7d0bd7e7f34cb26ffbfd"
+        );
+        assert_eq!(commit.parents.len(), 1);
+        assert_eq!(commit.author.name, "Ada Lovelace");
+        assert_eq!(commit.author.email, "ada@example.com");
+        assert_eq!(commit.author.timestamp, 1700000000);
+        assert_eq!(commit.author.tz, "-0700");
+        assert_eq!(commit.committer.name, "Charles Babbage");
+        assert!(commit.message.starts_with("Add a second line\n"));
+
+        let bytes = commit.to_bytes();
+        assert_eq!(&bytes[..], &raw[..]);
+        let hash = hash_content(ObjType::Commit, &bytes[..], bytes.len() as u64).unwrap();
+        assert_eq!(
+            hex::encode(hash),
+            "5170f3fa671d1b7773d7f556f98f6aabf4015d13"
+        );
+    }
+
+    // the tree write-tree would make of `dir` as a worktree, without writing anything
+    fn worktree_tree_sha(dir: &Path) -> String {
+        let progress = Arc::new(Progress::new("Writing objects", None, false));
+        let rules = ignore::IgnoreRules::default();
+        let tree = write_tree_recursive(None, false, dir, "", &rules, &progress).unwrap();
+        hex::encode(hash_tree(tree, None).unwrap())
+    }
+
+    // writes each of `files` under `top`, creating directories along the way
+    fn write_files(top: &Path, files: &[(&str, &str)]) {
+        for (name, content) in files {
+            let path = top.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+    }
+
+    #[test]
+    fn write_tree_leaves_out_ignored_files() {
+        let top = scratch::scratch_dir("ignored-dir");
+        write_files(
+            &top,
+            &[
+                (".gitignore", "target/\n*.log\n!keep.log\n"),
+                ("src/main.rs", "fn main() {}\n"),
+                ("target/debug/app", "binary\n"),
+                ("build.log", "noise\n"),
+                ("keep.log", "kept\n"),
+                // only a directory named target is ignored
+                ("docs/target", "not a dir\n"),
+            ],
+        );
+        // as git write-tree has it after `git add -A`
+        assert_eq!(
+            worktree_tree_sha(&top),
+            "5ad9e5284ccce48e906e8d04beb112991afd8dbd"
+        );
+    }
+
+    #[test]
+    fn write_tree_leaves_out_empty_dirs() {
+        let top = scratch::scratch_dir("empty-dir");
+        write_files(&top, &[("hello", "hello\n")]);
+        std::fs::create_dir(top.join("foo")).unwrap();
+        std::fs::create_dir_all(top.join("nested/empty")).unwrap();
+        // the same tree as if there were only `hello`
+        assert_eq!(
+            worktree_tree_sha(&top),
+            "b4d01e9b0c4a9356736dfddf8830ba9a54f5271c"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn force_rewrites_corrupt_object() {
+        let (_dir, mut repo) = scratch_repo("force-rewrite");
+        let file = repo.git_dir.with_file_name("hello");
+        std::fs::write(&file, "hello\n").unwrap();
+        let sha = hex::encode(hash_object(&file, ObjType::Blob, false, Some(&repo)).unwrap());
+
+        // content that doesn't hash to the object's name, left read-only like any other object
+        let obj_path = obj_path_from_sha(&repo, &sha);
+        std::fs::set_permissions(&obj_path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let mut enc = ZlibEncoder::new(vec![], flate2::Compression::default());
+        enc.write_all(b"blob 6\0jello\n").unwrap();
+        std::fs::write(&obj_path, enc.finish().unwrap()).unwrap();
+        std::fs::set_permissions(&obj_path, std::fs::Permissions::from_mode(0o444)).unwrap();
+        assert_eq!(verify_object(&repo, &sha).unwrap(), Some(false));
+
+        // without force the object that's there is trusted
+        hash_object(&file, ObjType::Blob, false, Some(&repo)).unwrap();
+        assert_eq!(verify_object(&repo, &sha).unwrap(), Some(false));
+
+        repo.rewrite_objects = true;
+        hash_object(&file, ObjType::Blob, false, Some(&repo)).unwrap();
+        assert_eq!(verify_object(&repo, &sha).unwrap(), Some(true));
+        let perms = std::fs::metadata(&obj_path).unwrap().permissions();
+        assert!(perms.readonly());
+    }
+
+    #[test]
+    fn hash_object_writes_loose_object() {
+        let (_dir, repo) = scratch_repo("hash-object-write");
+        // a few read buffers' worth, so it's hashed and compressed over more than one read
+        let content: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+        let file = repo.git_dir.with_file_name("big");
+        std::fs::write(&file, &content).unwrap();
+        let sha = hex::encode(hash_object(&file, ObjType::Blob, false, Some(&repo)).unwrap());
+        // as `git hash-object` has it
+        assert_eq!(sha, "4b5aafc78e13e1979389caabe88bd0fbc339398e");
+
+        let mut stored = vec![];
+        ZlibDecoder::new(File::open(obj_path_from_sha(&repo, &sha)).unwrap())
+            .read_to_end(&mut stored)
+            .unwrap();
+        assert_eq!(stored, [&b"blob 200000\0"[..], &content[..]].concat());
+        assert_eq!(verify_object(&repo, &sha).unwrap(), Some(true));
     }
 }
@@ -0,0 +1,1150 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use anyhow::{bail, ensure, Context, Result};
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use sha1::{Digest, Sha1};
+
+use crate::progress::Progress;
+use crate::repo::Repo;
+use crate::{hash_bytes, hash_content, open_object, ObjType};
+
+const IDX_V2_MAGIC: &[u8] = b"\xfftOc";
+const IDX_FANOUT_OFFSET: usize = 8;
+const IDX_SHAS_OFFSET: usize = IDX_FANOUT_OFFSET + 256 * 4;
+// the original format has no header, just the fanout and then each offset with its sha
+const IDX_V1_ENTRIES_OFFSET: usize = 256 * 4;
+const IDX_V1_ENTRY_LEN: usize = 4 + 20;
+
+const OBJ_COMMIT: u8 = 1;
+const OBJ_TREE: u8 = 2;
+const OBJ_BLOB: u8 = 3;
+const OBJ_TAG: u8 = 4;
+const OBJ_OFS_DELTA: u8 = 6;
+const OBJ_REF_DELTA: u8 = 7;
+
+// a repo's packs, each index read in and pack opened the first time an object is looked for and
+// then kept for every lookup after; a clone of the repo starts out without any and reads its own
+#[derive(Default)]
+pub(crate) struct PackCache(RefCell<Option<Vec<Pack>>>);
+
+impl Clone for PackCache {
+    fn clone(&self) -> Self {
+        PackCache::default()
+    }
+}
+
+pub(crate) fn find_object(repo: &Repo, hash: &[u8; 20]) -> Result<Option<(ObjType, Vec<u8>)>> {
+    let mut cache = repo.packs.0.borrow_mut();
+    let mut searched = 0;
+    if let Some(packs) = &mut *cache {
+        if let Some(obj) = read_from_packs(packs, hash)? {
+            return Ok(Some(obj));
+        }
+        searched = packs.len();
+    }
+    // packs written since they were read in, like by clone or index-pack, only turn up on a miss
+    let packs = load_packs(repo, &mut cache)?;
+    read_from_packs(&mut packs[searched..], hash)
+}
+
+fn read_from_packs(packs: &mut [Pack], hash: &[u8; 20]) -> Result<Option<(ObjType, Vec<u8>)>> {
+    for pack in packs {
+        if let Some(offset) = pack.idx.find(hash)? {
+            return pack.read_object(offset).map(Some);
+        }
+    }
+    Ok(None)
+}
+
+// the packs in `cache` along with any of the repo's that it doesn't have yet
+fn load_packs<'a>(repo: &Repo, cache: &'a mut Option<Vec<Pack>>) -> Result<&'a mut Vec<Pack>> {
+    let packs = cache.get_or_insert_with(Vec::new);
+    for idx_path in pack_indexes(repo)? {
+        let pack_path = idx_path.with_extension("pack");
+        if !packs.iter().any(|pack| pack.path == pack_path) {
+            let idx = PackIndex::open(&idx_path)?;
+            packs.push(Pack::open(pack_path, idx)?);
+        }
+    }
+    Ok(packs)
+}
+
+pub(crate) fn find_by_prefix(repo: &Repo, prefix: &str) -> Result<Vec<String>> {
+    let mut res = vec![];
+    let mut cache = repo.packs.0.borrow_mut();
+    for pack in load_packs(repo, &mut cache)?.iter() {
+        let idx = &pack.idx;
+        for i in 0..idx.count {
+            let sha = hex::encode(idx.sha(i));
+            if sha.starts_with(prefix) && !res.contains(&sha) {
+                res.push(sha);
+            }
+        }
+    }
+    Ok(res)
+}
+
+pub(crate) fn packed_objects(repo: &Repo) -> Result<Vec<[u8; 20]>> {
+    let mut res = vec![];
+    let mut cache = repo.packs.0.borrow_mut();
+    for pack in load_packs(repo, &mut cache)?.iter() {
+        let idx = &pack.idx;
+        for i in 0..idx.count {
+            res.push(idx.sha(i).try_into().expect("a sha to be 20 bytes"));
+        }
+    }
+    Ok(res)
+}
+
+// what `count-objects -v` has to say about the repo's own packs: how many there are and the
+// objects in them, the bytes they take up along with their indexes, and how many of `loose`
+// they've got too
+pub(crate) struct PackStats {
+    pub(crate) packs: usize,
+    pub(crate) objects: usize,
+    pub(crate) size: u64,
+    pub(crate) packed_loose: usize,
+    // files in the pack dir that aren't part of a pack, with what's wrong with each
+    pub(crate) garbage: Vec<(&'static str, PathBuf)>,
+}
+
+pub(crate) fn pack_stats(repo: &Repo, loose: &[[u8; 20]]) -> Result<PackStats> {
+    let mut stats = PackStats {
+        packs: 0,
+        objects: 0,
+        size: 0,
+        packed_loose: 0,
+        garbage: vec![],
+    };
+    let mut indexes = vec![];
+    for idx_path in pack_indexes_in(&repo.objects_dir())? {
+        let pack_path = idx_path.with_extension("pack");
+        let pack_len = pack_path.metadata().context("reading pack size")?.len();
+        let idx = PackIndex::open(&idx_path)?;
+        stats.packs += 1;
+        stats.objects += idx.count;
+        stats.size += pack_len + idx.bytes.len() as u64;
+        indexes.push(idx);
+    }
+    for hash in loose {
+        for idx in &indexes {
+            if idx.find(hash)?.is_some() {
+                stats.packed_loose += 1;
+                break;
+            }
+        }
+    }
+
+    // like git, a pack is only all there with both its .pack and .idx, and the other files that
+    // go along with one are left alone
+    let mut by_pack: BTreeMap<PathBuf, (Vec<PathBuf>, bool, bool)> = BTreeMap::new();
+    if let Ok(dirents) = repo.objects_dir().join("pack").read_dir() {
+        for ent in dirents {
+            let path = ent.context("listing pack dir")?.path();
+            let ext = path.extension().and_then(|ext| ext.to_str());
+            if path
+                .file_name()
+                .map_or(false, |name| name == "multi-pack-index")
+            {
+                continue;
+            }
+            match ext {
+                Some("pack" | "idx" | "rev" | "bitmap" | "keep" | "promisor" | "mtimes") => {
+                    let files = by_pack.entry(path.with_extension("")).or_default();
+                    files.1 |= ext == Some("pack");
+                    files.2 |= ext == Some("idx");
+                    files.0.push(path);
+                }
+                _ => stats.garbage.push(("garbage found", path)),
+            }
+        }
+    }
+    for (mut files, has_pack, has_idx) in by_pack.into_values() {
+        let why = match (has_pack, has_idx) {
+            (true, true) => continue,
+            (true, false) => "no corresponding .idx",
+            (false, true) => "no corresponding .pack",
+            (false, false) => "no corresponding .idx or .pack",
+        };
+        files.sort();
+        stats
+            .garbage
+            .extend(files.into_iter().map(|path| (why, path)));
+    }
+    Ok(stats)
+}
+
+// the repo's own packs first, then those of its alternates
+fn pack_indexes(repo: &Repo) -> Result<Vec<PathBuf>> {
+    let mut res = vec![];
+    for objects_dir in repo.object_dirs() {
+        res.extend(pack_indexes_in(objects_dir)?);
+    }
+    Ok(res)
+}
+
+fn pack_indexes_in(objects_dir: &Path) -> Result<Vec<PathBuf>> {
+    let pack_dir = objects_dir.join("pack");
+    let dirents = match pack_dir.read_dir() {
+        Ok(dirents) => dirents,
+        Err(_) => return Ok(vec![]),
+    };
+    let mut res = vec![];
+    for ent in dirents {
+        let path = ent.context("listing pack dir")?.path();
+        // like git, an index whose pack has gone is left alone
+        if path.extension().map_or(false, |ext| ext == "idx")
+            && path.with_extension("pack").is_file()
+        {
+            res.push(path);
+        }
+    }
+    res.sort();
+    Ok(res)
+}
+
+// checks a packfile's trailing checksum and writes the .idx next to it, resolving every delta
+// along the way to learn each object's sha; returns the pack's checksum. Given a repo, a thin
+// pack's missing delta bases are taken from it and added to the pack, as `--fix-thin` does
+pub(crate) fn index_pack(
+    pack_path: &Path,
+    repo: Option<&Repo>,
+    show_progress: bool,
+) -> Result<[u8; 20]> {
+    let (pack, entries) = read_packfile(pack_path)?;
+    let (body, checksum) = pack.split_at(pack.len() - 20);
+    let progress = Progress::new(
+        "Resolving objects",
+        Some(entries.len() as u64),
+        show_progress,
+    );
+    let (shas, external_bases) =
+        resolve_objects(body, &entries, repo, &progress, |_, _, _| Ok(()))?;
+    progress.finish();
+    if !external_bases.is_empty() {
+        complete_thin_pack(pack_path, body, &external_bases)?;
+        return index_pack(pack_path, None, false);
+    }
+    let count = entries.len();
+
+    let mut order: Vec<usize> = (0..count).collect();
+    order.sort_by_key(|&i| shas[i]);
+    let mut idx = Vec::with_capacity(IDX_SHAS_OFFSET + count * (20 + 4 + 4) + 40);
+    idx.extend_from_slice(IDX_V2_MAGIC);
+    idx.extend_from_slice(&2u32.to_be_bytes());
+    for first_byte in 0..=255u8 {
+        let below = order.partition_point(|&i| shas[i][0] <= first_byte);
+        idx.extend_from_slice(&(below as u32).to_be_bytes());
+    }
+    for &i in &order {
+        idx.extend_from_slice(&shas[i]);
+    }
+    for &i in &order {
+        let ent = &entries[i];
+        let crc = crc32(&body[ent.offset as usize..ent.end]);
+        idx.extend_from_slice(&crc.to_be_bytes());
+    }
+    // offsets that don't fit in 31 bits go in a trailing table of 64-bit values
+    let mut large_offsets = vec![];
+    for &i in &order {
+        let offset = entries[i].offset;
+        let off32 = if offset < 0x8000_0000 {
+            offset as u32
+        } else {
+            large_offsets.push(offset);
+            0x8000_0000 | (large_offsets.len() - 1) as u32
+        };
+        idx.extend_from_slice(&off32.to_be_bytes());
+    }
+    for offset in large_offsets {
+        idx.extend_from_slice(&offset.to_be_bytes());
+    }
+    idx.extend_from_slice(checksum);
+    let idx_checksum = Sha1::digest(&idx);
+    idx.extend_from_slice(&idx_checksum);
+
+    let idx_path = pack_path.with_extension("idx");
+    std::fs::write(&idx_path, idx)
+        .with_context(|| format!("writing pack index {}", idx_path.to_string_lossy()))?;
+    Ok(checksum.try_into().unwrap())
+}
+
+// appends whole copies of the bases a thin pack left out, so it no longer depends on other
+// objects in the repo, and rewrites its object count and checksum to match
+fn complete_thin_pack(pack_path: &Path, body: &[u8], bases: &[ResolvedObject]) -> Result<()> {
+    let mut pack = body.to_vec();
+    let count = be_u32(&body[8..12]) as usize + bases.len();
+    pack[8..12].copy_from_slice(&(count as u32).to_be_bytes());
+    for base in bases {
+        let (otype, content) = &**base;
+        let type_id = match otype {
+            ObjType::Commit => OBJ_COMMIT,
+            ObjType::Tree => OBJ_TREE,
+            ObjType::Blob => OBJ_BLOB,
+            ObjType::Tag => OBJ_TAG,
+        };
+        write_entry_header(&mut pack, type_id, content.len());
+        let mut encoder = ZlibEncoder::new(pack, flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, content).context("compressing delta base")?;
+        pack = encoder.finish().context("compressing delta base")?;
+    }
+    let checksum = Sha1::digest(&pack);
+    pack.extend_from_slice(&checksum);
+    std::fs::write(pack_path, pack)
+        .with_context(|| format!("writing packfile {}", pack_path.to_string_lossy()))
+}
+
+// writes every object in a packfile out as a loose object
+pub(crate) fn unpack_objects(repo: &Repo, pack_path: &Path) -> Result<()> {
+    let (pack, entries) = read_packfile(pack_path)?;
+    resolve_objects(
+        &pack[..pack.len() - 20],
+        &entries,
+        Some(repo),
+        &Progress::new("Unpacking objects", None, false),
+        |otype, content, _| hash_bytes(otype, content, Some(repo)).map(drop),
+    )?;
+    Ok(())
+}
+
+// the whole packfile once its checksum has been checked, along with where each entry is in it
+fn read_packfile(pack_path: &Path) -> Result<(Vec<u8>, Vec<PackEntry>)> {
+    let pack = std::fs::read(pack_path)
+        .with_context(|| format!("reading packfile {}", pack_path.to_string_lossy()))?;
+    ensure!(
+        pack.len() >= 12 + 20 && &pack[..4] == b"PACK",
+        "packfile is missing PACK signature"
+    );
+    let version = be_u32(&pack[4..8]);
+    ensure!(
+        version == 2 || version == 3,
+        "unsupported packfile version {}",
+        version
+    );
+    let (body, checksum) = pack.split_at(pack.len() - 20);
+    ensure!(
+        Sha1::digest(body).as_slice() == checksum,
+        "packfile checksum mismatch"
+    );
+
+    let count = be_u32(&body[8..12]) as usize;
+    let mut entries = Vec::with_capacity(count);
+    let mut pos = 12;
+    for _ in 0..count {
+        let mut reader = body.get(pos..).context("packfile is truncated")?;
+        let (type_id, size) = read_entry_header(&mut reader)?;
+        let base = match type_id {
+            OBJ_COMMIT => EntryBase::Whole(ObjType::Commit),
+            OBJ_TREE => EntryBase::Whole(ObjType::Tree),
+            OBJ_BLOB => EntryBase::Whole(ObjType::Blob),
+            OBJ_TAG => EntryBase::Whole(ObjType::Tag),
+            OBJ_OFS_DELTA => {
+                let base_distance = read_base_distance(&mut reader)?;
+                ensure!(
+                    base_distance <= pos as u64,
+                    "delta base offset points before start of packfile"
+                );
+                ensure!(base_distance > 0, "delta is its own base");
+                EntryBase::Offset(pos as u64 - base_distance)
+            }
+            OBJ_REF_DELTA => {
+                let mut base_hash = [0u8; 20];
+                reader
+                    .read_exact(&mut base_hash)
+                    .context("reading delta base sha")?;
+                EntryBase::Ref(base_hash)
+            }
+            unk => bail!("unknown packed object type {}", unk),
+        };
+        let data_start = body.len() - reader.len();
+
+        // the compressed length isn't stored anywhere, so inflate it to find where it ends
+        let mut decoder = flate2::bufread::ZlibDecoder::new(reader);
+        let inflated =
+            std::io::copy(&mut decoder, &mut std::io::sink()).context("inflating packed object")?;
+        ensure!(
+            inflated == size as u64,
+            "packed object inflated to {} bytes, expected {}",
+            inflated,
+            size
+        );
+        let end = body.len() - decoder.into_inner().len();
+
+        entries.push(PackEntry {
+            offset: pos as u64,
+            data_start,
+            end,
+            size,
+            base,
+        });
+        pos = end;
+    }
+    ensure!(pos == body.len(), "packfile has data after its last object");
+    Ok((pack, entries))
+}
+
+// rebuilds every object in a pack, handing each to `each` along with its sha; returns the shas
+// in the same order as `entries`, and any delta bases that had to come from `repo` since the
+// pack didn't have them
+fn resolve_objects<F>(
+    body: &[u8],
+    entries: &[PackEntry],
+    repo: Option<&Repo>,
+    progress: &Progress,
+    mut each: F,
+) -> Result<(Vec<[u8; 20]>, Vec<ResolvedObject>)>
+where
+    F: FnMut(ObjType, &[u8], [u8; 20]) -> Result<()>,
+{
+    let count = entries.len();
+    let mut resolver = DeltaResolver::new(body, entries);
+    let mut shas: Vec<Option<[u8; 20]>> = vec![None; count];
+    let mut by_sha = HashMap::new();
+    // a ref delta's base can come later in the pack than the delta, so those are set aside by the
+    // sha they wait on and picked up again once an object with that sha turns up
+    let mut waiting: HashMap<[u8; 20], Vec<usize>> = HashMap::new();
+    let mut ready: VecDeque<usize> = (0..count).collect();
+    let mut unresolved = count;
+    loop {
+        while let Some(i) = ready.pop_front() {
+            if shas[i].is_some() {
+                continue;
+            }
+            if let Some(hash) = resolver.waiting_on(i, &by_sha)? {
+                waiting.entry(hash).or_default().push(i);
+                continue;
+            }
+            let object = resolver.object(i, &by_sha)?;
+            let (otype, content) = &*object;
+            let sha = hash_content(*otype, &content[..], content.len() as u64)?;
+            each(*otype, content, sha)?;
+            resolver.found(i, sha, object);
+            shas[i] = Some(sha);
+            by_sha.insert(sha, i);
+            unresolved -= 1;
+            progress.add(1);
+            ready.extend(waiting.remove(&sha).unwrap_or_default());
+        }
+        if unresolved == 0 {
+            break;
+        }
+        // a thin pack leaves out bases the receiver is known to have already
+        let thin = match repo {
+            Some(repo) => resolver.load_external_bases(repo, &by_sha)?,
+            None => false,
+        };
+        ensure!(
+            thin,
+            "packfile has deltas against objects it doesn't contain"
+        );
+        ready.extend(waiting.drain().flat_map(|(_, dependents)| dependents));
+    }
+    let external_bases = resolver.external.into_values().collect();
+    Ok((shas.into_iter().flatten().collect(), external_bases))
+}
+
+type ResolvedObject = Rc<(ObjType, Vec<u8>)>;
+
+// an object as stored in a packfile, before resolving any delta
+struct PackEntry {
+    offset: u64,
+    // where the compressed data starts and the whole entry ends
+    data_start: usize,
+    end: usize,
+    size: usize,
+    base: EntryBase,
+}
+
+enum EntryBase {
+    Whole(ObjType),
+    Offset(u64),
+    Ref([u8; 20]),
+}
+
+// rebuilds objects from an in-memory pack; anything that's used as a delta base is kept once
+// resolved so long chains aren't inflated over and over
+struct DeltaResolver<'a> {
+    body: &'a [u8],
+    entries: &'a [PackEntry],
+    ofs_bases: HashSet<u64>,
+    ref_bases: HashSet<[u8; 20]>,
+    bases: HashMap<usize, ResolvedObject>,
+    // bases from outside the pack, by sha so a fixed thin pack always comes out the same
+    external: BTreeMap<[u8; 20], ResolvedObject>,
+}
+
+impl<'a> DeltaResolver<'a> {
+    fn new(body: &'a [u8], entries: &'a [PackEntry]) -> Self {
+        let mut ofs_bases = HashSet::new();
+        let mut ref_bases = HashSet::new();
+        for ent in entries {
+            match ent.base {
+                EntryBase::Offset(offset) => {
+                    ofs_bases.insert(offset);
+                }
+                EntryBase::Ref(hash) => {
+                    ref_bases.insert(hash);
+                }
+                EntryBase::Whole(_) => {}
+            }
+        }
+        DeltaResolver {
+            body,
+            entries,
+            ofs_bases,
+            ref_bases,
+            bases: HashMap::new(),
+            external: BTreeMap::new(),
+        }
+    }
+
+    // the sha of the ref base that entry `i` still has to wait for, if any, following offset
+    // bases back to the start of its chain without inflating anything
+    fn waiting_on(&self, i: usize, by_sha: &HashMap<[u8; 20], usize>) -> Result<Option<[u8; 20]>> {
+        let mut i = i;
+        loop {
+            if self.bases.contains_key(&i) {
+                return Ok(None);
+            }
+            match self.entries[i].base {
+                EntryBase::Whole(_) => return Ok(None),
+                EntryBase::Offset(offset) => i = self.entry_at(offset)?,
+                EntryBase::Ref(hash) => {
+                    let known = by_sha.contains_key(&hash) || self.external.contains_key(&hash);
+                    return Ok((!known).then(|| hash));
+                }
+            }
+        }
+    }
+
+    // only once `waiting_on` has nothing left to wait for
+    fn object(&mut self, i: usize, by_sha: &HashMap<[u8; 20], usize>) -> Result<ResolvedObject> {
+        if let Some(object) = self.bases.get(&i) {
+            return Ok(Rc::clone(object));
+        }
+        let ent = &self.entries[i];
+        let base = match ent.base {
+            EntryBase::Whole(otype) => {
+                let data = inflate(&self.body[ent.data_start..ent.end], ent.size)?;
+                return Ok(Rc::new((otype, data)));
+            }
+            EntryBase::Offset(offset) => self.object(self.entry_at(offset)?, by_sha)?,
+            EntryBase::Ref(hash) => match by_sha.get(&hash) {
+                Some(&base_idx) => self.object(base_idx, by_sha)?,
+                None => self
+                    .external
+                    .get(&hash)
+                    .cloned()
+                    .context("delta base isn't known yet")?,
+            },
+        };
+        let delta = inflate(&self.body[ent.data_start..ent.end], ent.size)?;
+        let object = Rc::new((base.0, apply_delta(&base.1, &delta)?));
+        if self.ofs_bases.contains(&ent.offset) {
+            self.bases.insert(i, Rc::clone(&object));
+        }
+        Ok(object)
+    }
+
+    fn entry_at(&self, offset: u64) -> Result<usize> {
+        self.entries
+            .binary_search_by_key(&offset, |ent| ent.offset)
+            .ok()
+            .context("delta base offset doesn't point at an object")
+    }
+
+    // looks in `repo` for the ref delta bases that aren't in the pack and haven't been loaded
+    // yet; false if there were none to be found
+    fn load_external_bases(
+        &mut self,
+        repo: &Repo,
+        by_sha: &HashMap<[u8; 20], usize>,
+    ) -> Result<bool> {
+        let mut loaded = false;
+        for ent in self.entries {
+            let hash = match ent.base {
+                EntryBase::Ref(hash) if !by_sha.contains_key(&hash) => hash,
+                _ => continue,
+            };
+            if self.external.contains_key(&hash) {
+                continue;
+            }
+            if let Some((otype, objsz, mut reader)) = open_object(repo, &hex::encode(hash))? {
+                let mut content = Vec::with_capacity(objsz);
+                reader
+                    .read_to_end(&mut content)
+                    .with_context(|| format!("reading delta base {}", hex::encode(hash)))?;
+                self.external.insert(hash, Rc::new((otype, content)));
+                loaded = true;
+            }
+        }
+        Ok(loaded)
+    }
+
+    // ref delta bases are only recognisable once their sha is known
+    fn found(&mut self, i: usize, sha: [u8; 20], object: ResolvedObject) {
+        if self.ref_bases.contains(&sha) || self.ofs_bases.contains(&self.entries[i].offset) {
+            self.bases.insert(i, object);
+        }
+    }
+}
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+// the zlib/PNG flavour of CRC-32 that pack indexes store for each entry
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in bytes {
+        crc = CRC32_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+struct PackIndex {
+    bytes: Vec<u8>,
+    count: usize,
+    version: u32,
+}
+
+impl PackIndex {
+    fn open(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path)
+            .with_context(|| format!("reading pack index {}", path.to_string_lossy()))?;
+        // a v1 index's first fanout entry can't look like the magic, as packs hold far fewer
+        // than 0xff744f63 objects
+        if !bytes.starts_with(IDX_V2_MAGIC) {
+            ensure!(
+                bytes.len() >= IDX_V1_ENTRIES_OFFSET,
+                "truncated pack index: {}",
+                path.to_string_lossy()
+            );
+            let count = be_u32(&bytes[IDX_V1_ENTRIES_OFFSET - 4..]) as usize;
+            ensure!(
+                bytes.len() >= IDX_V1_ENTRIES_OFFSET + count * IDX_V1_ENTRY_LEN + 40,
+                "truncated pack index: {}",
+                path.to_string_lossy()
+            );
+            return Ok(PackIndex {
+                bytes,
+                count,
+                version: 1,
+            });
+        }
+        ensure!(
+            bytes.len() >= IDX_SHAS_OFFSET,
+            "unsupported pack index format: {}",
+            path.to_string_lossy()
+        );
+        ensure!(
+            be_u32(&bytes[4..8]) == 2,
+            "unsupported pack index version: {}",
+            path.to_string_lossy()
+        );
+        let count = be_u32(&bytes[IDX_SHAS_OFFSET - 4..]) as usize;
+        // shas, crcs and 32-bit offsets, followed by the pack and index checksums
+        ensure!(
+            bytes.len() >= IDX_SHAS_OFFSET + count * (20 + 4 + 4) + 40,
+            "truncated pack index: {}",
+            path.to_string_lossy()
+        );
+        Ok(PackIndex {
+            bytes,
+            count,
+            version: 2,
+        })
+    }
+
+    fn fanout(&self, first_byte: usize) -> usize {
+        let fanout_offset = match self.version {
+            1 => 0,
+            _ => IDX_FANOUT_OFFSET,
+        };
+        be_u32(&self.bytes[fanout_offset + first_byte * 4..]) as usize
+    }
+
+    fn sha(&self, i: usize) -> &[u8] {
+        let start = match self.version {
+            1 => IDX_V1_ENTRIES_OFFSET + i * IDX_V1_ENTRY_LEN + 4,
+            _ => IDX_SHAS_OFFSET + i * 20,
+        };
+        &self.bytes[start..start + 20]
+    }
+
+    fn offset(&self, i: usize) -> Result<u64> {
+        // v1 only ever had 32-bit offsets
+        if self.version == 1 {
+            let start = IDX_V1_ENTRIES_OFFSET + i * IDX_V1_ENTRY_LEN;
+            return Ok(be_u32(&self.bytes[start..]).into());
+        }
+        let off32_table = IDX_SHAS_OFFSET + self.count * (20 + 4);
+        let off32 = be_u32(&self.bytes[off32_table + i * 4..]);
+        if off32 & 0x8000_0000 == 0 {
+            return Ok(off32.into());
+        }
+
+        // large packs keep offsets >2GiB in a trailing table of 64-bit values
+        let off64_table = off32_table + self.count * 4;
+        let start = off64_table + (off32 & 0x7fff_ffff) as usize * 8;
+        let off64 = self
+            .bytes
+            .get(start..start + 8)
+            .context("pack index 64-bit offset out of range")?;
+        Ok(u64::from_be_bytes(off64.try_into().unwrap()))
+    }
+
+    fn find(&self, hash: &[u8; 20]) -> Result<Option<u64>> {
+        let first_byte = hash[0] as usize;
+        let mut lo = if first_byte == 0 {
+            0
+        } else {
+            self.fanout(first_byte - 1)
+        };
+        let mut hi = self.fanout(first_byte);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            match self.sha(mid).cmp(&hash[..]) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => return self.offset(mid).map(Some),
+            }
+        }
+        Ok(None)
+    }
+}
+
+// git's default core.deltaBaseCacheLimit
+const DELTA_BASE_CACHE_LIMIT: usize = 96 * 1024 * 1024;
+
+struct Pack {
+    path: PathBuf,
+    file: File,
+    idx: PackIndex,
+    // delta bases already rebuilt, by offset, so objects in the same chain don't each rebuild them
+    // all over again; emptied whenever it would grow past DELTA_BASE_CACHE_LIMIT bytes
+    bases: HashMap<u64, (ObjType, Vec<u8>)>,
+    bases_len: usize,
+}
+
+impl Pack {
+    fn open<P: AsRef<Path>>(path: P, idx: PackIndex) -> Result<Self> {
+        let path = path.as_ref();
+        let mut file = File::open(path)
+            .with_context(|| format!("opening packfile {}", path.to_string_lossy()))?;
+
+        let mut header = [0u8; 12];
+        file.read_exact(&mut header)
+            .context("reading packfile header")?;
+        ensure!(
+            &header[..4] == b"PACK",
+            "packfile is missing PACK signature"
+        );
+        let version = be_u32(&header[4..8]);
+        ensure!(
+            version == 2 || version == 3,
+            "unsupported packfile version {}",
+            version
+        );
+
+        Ok(Pack {
+            path: path.to_path_buf(),
+            file,
+            idx,
+            bases: HashMap::new(),
+            bases_len: 0,
+        })
+    }
+
+    fn read_object(&mut self, offset: u64) -> Result<(ObjType, Vec<u8>)> {
+        self.file
+            .seek(SeekFrom::Start(offset))
+            .context("seeking to object in packfile")?;
+        let mut reader = BufReader::new(&mut self.file);
+        let (type_id, size) = read_entry_header(&mut reader)?;
+
+        let otype = match type_id {
+            OBJ_COMMIT => ObjType::Commit,
+            OBJ_TREE => ObjType::Tree,
+            OBJ_BLOB => ObjType::Blob,
+            OBJ_TAG => ObjType::Tag,
+            OBJ_OFS_DELTA => {
+                let base_distance = read_base_distance(&mut reader)?;
+                ensure!(
+                    base_distance <= offset,
+                    "delta base offset points before start of packfile"
+                );
+                let delta = inflate(reader, size)?;
+                let (otype, base) = self.read_base(offset - base_distance)?;
+                return Ok((otype, apply_delta(base, &delta)?));
+            }
+            OBJ_REF_DELTA => {
+                let mut base_hash = [0u8; 20];
+                reader
+                    .read_exact(&mut base_hash)
+                    .context("reading delta base sha")?;
+                let delta = inflate(reader, size)?;
+                let base_offset = self.idx.find(&base_hash)?.with_context(|| {
+                    format!("missing delta base object {}", hex::encode(base_hash))
+                })?;
+                let (otype, base) = self.read_base(base_offset)?;
+                return Ok((otype, apply_delta(base, &delta)?));
+            }
+            unk => bail!("unknown packed object type {}", unk),
+        };
+
+        Ok((otype, inflate(reader, size)?))
+    }
+
+    fn read_base(&mut self, offset: u64) -> Result<(ObjType, &[u8])> {
+        if !self.bases.contains_key(&offset) {
+            let (otype, base) = self.read_object(offset)?;
+            if self.bases_len + base.len() > DELTA_BASE_CACHE_LIMIT {
+                self.bases.clear();
+                self.bases_len = 0;
+            }
+            self.bases_len += base.len();
+            self.bases.insert(offset, (otype, base));
+        }
+        let (otype, base) = &self.bases[&offset];
+        Ok((*otype, base))
+    }
+}
+
+fn write_entry_header(out: &mut Vec<u8>, type_id: u8, size: usize) {
+    let mut byte = (type_id << 4) | (size & 0x0f) as u8;
+    let mut rest = size >> 4;
+    while rest != 0 {
+        out.push(byte | 0x80);
+        byte = (rest & 0x7f) as u8;
+        rest >>= 7;
+    }
+    out.push(byte);
+}
+
+fn read_entry_header<R: BufRead>(reader: &mut R) -> Result<(u8, usize)> {
+    // type is in bits 4-6 of the first byte, and the inflated size is a little-endian varint
+    // made of the low 4 bits of the first byte followed by 7 bits from each continuation
+    let mut byte = read_byte(reader)?;
+    let type_id = (byte >> 4) & 0b111;
+    let mut size = (byte & 0x0f) as usize;
+    let mut shift = 4;
+    while byte & 0x80 != 0 {
+        byte = read_byte(reader)?;
+        ensure!(shift < usize::BITS, "packed object size is too large");
+        size |= ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+    }
+    Ok((type_id, size))
+}
+
+fn read_base_distance<R: BufRead>(reader: &mut R) -> Result<u64> {
+    // big-endian varint distance back from the delta entry, where each continuation also adds
+    // one so that no two encodings mean the same distance
+    let mut byte = read_byte(reader)?;
+    let mut distance = (byte & 0x7f) as u64;
+    while byte & 0x80 != 0 {
+        byte = read_byte(reader)?;
+        let next = distance
+            .checked_add(1)
+            .filter(|d| d.leading_zeros() >= 7)
+            .context("delta base offset is too large")?;
+        distance = (next << 7) | (byte & 0x7f) as u64;
+    }
+    Ok(distance)
+}
+
+fn read_byte<R: BufRead>(reader: &mut R) -> Result<u8> {
+    let mut byte = [0u8; 1];
+    reader
+        .read_exact(&mut byte)
+        .context("reading packed object header")?;
+    Ok(byte[0])
+}
+
+fn inflate<R: Read>(reader: R, size: usize) -> Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(size);
+    ZlibDecoder::new(reader)
+        .read_to_end(&mut data)
+        .context("inflating packed object")?;
+    ensure!(
+        data.len() == size,
+        "packed object inflated to {} bytes, expected {}",
+        data.len(),
+        size
+    );
+    Ok(data)
+}
+
+fn apply_delta(base: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+    let mut pos = 0;
+    let base_size = delta_size(delta, &mut pos)?;
+    ensure!(
+        base_size == base.len(),
+        "delta expects a {} byte base, found {}",
+        base_size,
+        base.len()
+    );
+    let target_size = delta_size(delta, &mut pos)?;
+
+    // every op adds at least a byte, so a corrupt size can't make this allocate much more than
+    // the delta could ever produce
+    let max_target = (delta.len() - pos).saturating_mul(0x10000);
+    let mut target = Vec::with_capacity(target_size.min(max_target));
+    while pos < delta.len() {
+        let op = delta[pos];
+        pos += 1;
+        if op & 0x80 != 0 {
+            // copy from base: bits 0-3 say which offset bytes follow, bits 4-6 which size bytes
+            let mut copy_offset = 0usize;
+            let mut copy_size = 0usize;
+            for i in 0..4 {
+                if op & (1 << i) != 0 {
+                    copy_offset |=
+                        (*delta.get(pos).context("truncated delta")? as usize) << (i * 8);
+                    pos += 1;
+                }
+            }
+            for i in 0..3 {
+                if op & (0x10 << i) != 0 {
+                    copy_size |= (*delta.get(pos).context("truncated delta")? as usize) << (i * 8);
+                    pos += 1;
+                }
+            }
+            if copy_size == 0 {
+                copy_size = 0x10000;
+            }
+            let chunk = base
+                .get(copy_offset..copy_offset + copy_size)
+                .context("delta copies past end of base")?;
+            target.extend_from_slice(chunk);
+        } else if op != 0 {
+            // insert the next `op` bytes of the delta literally
+            let chunk = delta
+                .get(pos..pos + op as usize)
+                .context("truncated delta")?;
+            target.extend_from_slice(chunk);
+            pos += op as usize;
+        } else {
+            bail!("delta contains reserved opcode 0");
+        }
+        ensure!(
+            target.len() <= target_size,
+            "delta produced more than the {} bytes expected",
+            target_size
+        );
+    }
+
+    ensure!(
+        target.len() == target_size,
+        "delta produced {} bytes, expected {}",
+        target.len(),
+        target_size
+    );
+    Ok(target)
+}
+
+fn delta_size(delta: &[u8], pos: &mut usize) -> Result<usize> {
+    let mut size = 0usize;
+    let mut shift = 0;
+    loop {
+        let byte = *delta.get(*pos).context("truncated delta header")?;
+        *pos += 1;
+        ensure!(shift < usize::BITS, "delta size is too large");
+        size |= ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(size);
+        }
+    }
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes[..4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply_delta_copies_from_base() {
+        // base size 11, target size 5, then copy 5 bytes from offset 6
+        let delta = [11, 5, 0x80 | 0x01 | 0x10, 6, 5];
+        assert_eq!(apply_delta(b"hello world", &delta).unwrap(), b"world");
+
+        // a copy that gives no size bytes copies 0x10000
+        let base = vec![7u8; 0x10000];
+        let delta = [0x80, 0x80, 0x04, 0x80, 0x80, 0x04, 0x80];
+        assert_eq!(apply_delta(&base, &delta).unwrap(), base);
+    }
+
+    #[test]
+    fn apply_delta_inserts_literals() {
+        let delta = [3, 4, 4, b'w', b'x', b'y', b'z'];
+        assert_eq!(apply_delta(b"abc", &delta).unwrap(), b"wxyz");
+    }
+
+    #[test]
+    fn apply_delta_mixes_copies_and_inserts() {
+        let delta = [
+            11,
+            13,
+            // "hello", with no offset bytes meaning offset 0
+            0x80 | 0x10,
+            5,
+            2,
+            b',',
+            b' ',
+            // "world"
+            0x80 | 0x01 | 0x10,
+            6,
+            5,
+            1,
+            b'!',
+        ];
+        assert_eq!(
+            apply_delta(b"hello world", &delta).unwrap(),
+            b"hello, world!"
+        );
+    }
+
+    #[test]
+    fn apply_delta_refuses_bad_deltas() {
+        let err = apply_delta(b"hello", &[11, 5, 0x91, 6, 5]).unwrap_err();
+        assert_eq!(err.to_string(), "delta expects a 11 byte base, found 5");
+
+        let err = apply_delta(b"hello", &[5, 4, 0x91, 3, 4]).unwrap_err();
+        assert_eq!(err.to_string(), "delta copies past end of base");
+
+        let mut delta = vec![0x80; 11];
+        delta.push(0);
+        let err = apply_delta(b"hello", &delta).unwrap_err();
+        assert_eq!(err.to_string(), "delta size is too large");
+    }
+
+    // the same pack of two commits indexed both ways, with `seq 1 200` stored as a delta against
+    // `seq 1 201`
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    #[test]
+    fn pack_index_versions_find_same_object() {
+        let hello = hex::decode("ce013625030ba8dba906f756967f9e9ca394464a").unwrap();
+        let numbers = hex::decode("aa5e3f802c6a6d3eb7eac845d2293dec38ccfff1").unwrap();
+        let seq: String = (1..=200).map(|n| format!("{}\n", n)).collect();
+        for (idx_name, version) in [("pack-v1.idx", 1), ("pack-v2.idx", 2)] {
+            let idx = PackIndex::open(&fixture(idx_name)).unwrap();
+            assert_eq!(idx.version, version);
+            assert_eq!(idx.count, 7);
+            let hello_offset = idx.find(hello[..].try_into().unwrap()).unwrap();
+            let numbers_offset = idx.find(numbers[..].try_into().unwrap()).unwrap();
+            assert_eq!(hello_offset, Some(306));
+            assert_eq!(numbers_offset, Some(738));
+
+            let mut pack = Pack::open(fixture("pack.pack"), idx).unwrap();
+            let (otype, content) = pack.read_object(306).unwrap();
+            assert!(matches!(otype, ObjType::Blob));
+            assert_eq!(content, b"hello\n");
+            let (otype, content) = pack.read_object(738).unwrap();
+            assert!(matches!(otype, ObjType::Blob));
+            assert_eq!(content, seq.as_bytes());
+        }
+    }
+
+    #[test]
+    fn find_object_reads_packs_in_once() {
+        let dir = crate::scratch::scratch_dir("pack-cache");
+        let git_dir = dir.join(".git");
+        let pack_dir = git_dir.join("objects/pack");
+        std::fs::create_dir_all(&pack_dir).unwrap();
+        let repo = Repo::new(&git_dir);
+        let hello: [u8; 20] = hex::decode("ce013625030ba8dba906f756967f9e9ca394464a")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert!(find_object(&repo, &hello).unwrap().is_none());
+
+        // a pack that turns up after the first lookup, the way clone writes one
+        std::fs::copy(fixture("pack.pack"), pack_dir.join("pack-test.pack")).unwrap();
+        std::fs::copy(fixture("pack-v2.idx"), pack_dir.join("pack-test.idx")).unwrap();
+        let (_, content) = find_object(&repo, &hello).unwrap().unwrap();
+        assert_eq!(content, b"hello\n");
+
+        // the second time round, the delta's base comes from the cache
+        let numbers: [u8; 20] = hex::decode("aa5e3f802c6a6d3eb7eac845d2293dec38ccfff1")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let seq: String = (1..=200).map(|n| format!("{}\n", n)).collect();
+        for _ in 0..2 {
+            let (_, content) = find_object(&repo, &numbers).unwrap().unwrap();
+            assert_eq!(content, seq.as_bytes());
+        }
+        let cache = repo.packs.0.borrow();
+        let packs = cache.as_ref().unwrap();
+        assert_eq!(packs.len(), 1);
+        assert_eq!(packs[0].bases.len(), 1);
+    }
+
+    // appends `data` zlib-compressed, the way a pack entry's data follows its header
+    fn push_compressed(pack: Vec<u8>, data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(pack, flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn index_pack_resolves_ref_delta_ahead_of_its_base() {
+        let base = b"hello world";
+        let base_sha = hash_content(ObjType::Blob, &base[..], base.len() as u64).unwrap();
+        let mut pack = b"PACK\0\0\0\x02\0\0\0\x02".to_vec();
+        // "world", copied out of a base that only comes after it
+        let delta = [11, 5, 0x80 | 0x01 | 0x10, 6, 5];
+        write_entry_header(&mut pack, OBJ_REF_DELTA, delta.len());
+        pack.extend_from_slice(&base_sha);
+        pack = push_compressed(pack, &delta);
+        write_entry_header(&mut pack, OBJ_BLOB, base.len());
+        pack = push_compressed(pack, base);
+        let checksum = Sha1::digest(&pack);
+        pack.extend_from_slice(&checksum);
+        let dir = crate::scratch::scratch_dir("ref-delta");
+        let path = dir.join("pack-test.pack");
+        std::fs::write(&path, pack).unwrap();
+
+        index_pack(&path, None, false).unwrap();
+        let idx = PackIndex::open(&path.with_extension("idx")).unwrap();
+        assert_eq!(idx.count, 2);
+        let world = hash_content(ObjType::Blob, &b"world"[..], 5).unwrap();
+        let offset = idx.find(&world).unwrap().unwrap();
+        let mut pack = Pack::open(&path, idx).unwrap();
+        assert_eq!(pack.read_object(offset).unwrap().1, b"world");
+    }
+}
@@ -0,0 +1,134 @@
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// a count without a total has no percentage to watch, so it's redrawn this often instead
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+// a `Title:  42% (1234/2900)` line on stderr, redrawn in place as work gets done; shared between
+// threads, and next to free when it isn't shown
+pub(crate) struct Progress {
+    title: &'static str,
+    total: Option<u64>,
+    // whether what's counted is bytes, shown as a size rather than a bare number
+    bytes: bool,
+    shown: bool,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    done: u64,
+    percent: Option<u64>,
+    drawn_at: Option<Instant>,
+    // how long the last line drawn was, so a shorter one can cover it up
+    width: usize,
+}
+
+impl Progress {
+    pub(crate) fn new(title: &'static str, total: Option<u64>, shown: bool) -> Self {
+        Progress {
+            title,
+            total,
+            bytes: false,
+            shown,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub(crate) fn bytes(title: &'static str, shown: bool) -> Self {
+        Progress {
+            bytes: true,
+            ..Progress::new(title, None, shown)
+        }
+    }
+
+    pub(crate) fn add(&self, n: u64) {
+        if !self.shown {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.done += n;
+        let redraw = match self.total {
+            Some(total) => {
+                let percent = Some(percent(state.done, total));
+                let changed = percent != state.percent;
+                state.percent = percent;
+                changed
+            }
+            None => state
+                .drawn_at
+                .map_or(true, |at| at.elapsed() >= REDRAW_INTERVAL),
+        };
+        if redraw {
+            state.drawn_at = Some(Instant::now());
+            self.draw(&mut state, "", '\r');
+        }
+    }
+
+    pub(crate) fn finish(&self) {
+        if self.shown {
+            self.draw(&mut self.state.lock().unwrap(), ", done.", '\n');
+        }
+    }
+
+    fn draw(&self, state: &mut State, suffix: &str, end: char) {
+        let done = state.done;
+        let mut line = match self.total {
+            Some(total) => format!(
+                "{}: {:3}% ({}/{})",
+                self.title,
+                percent(done, total),
+                done,
+                total
+            ),
+            None if self.bytes => format!("{}: {}", self.title, human_size(done)),
+            None => format!("{}: {}", self.title, done),
+        };
+        line.push_str(suffix);
+        let width = line.len();
+        // progress is only ever a nicety, so a stderr that's gone away doesn't matter
+        let _ = write!(std::io::stderr(), "{:w$}{}", line, end, w = state.width);
+        state.width = width;
+    }
+}
+
+// passes writes through, counting the bytes as progress
+pub(crate) struct ProgressWriter<'a, W> {
+    pub(crate) inner: W,
+    pub(crate) progress: &'a Progress,
+}
+
+impl<W: Write> Write for ProgressWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.progress.add(n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn percent(done: u64, total: u64) -> u64 {
+    match total {
+        0 => 100,
+        _ => done * 100 / total,
+    }
+}
+
+// the way git shows transfer sizes, e.g. `1.20 MiB`
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 3] = ["KiB", "MiB", "GiB"];
+    if bytes < 1024 {
+        return format!("{} bytes", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.2} {}", size, UNITS[unit])
+}
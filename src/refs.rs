@@ -0,0 +1,406 @@
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, ensure, Context, Result};
+
+use crate::repo::Repo;
+use crate::{is_plausibly_obj_sha, is_valid_branch_name};
+
+// what a reflog has as the old value of a ref that didn't exist yet
+const NULL_SHA: &str = "0000000000000000000000000000000000000000";
+
+// same limit git uses before deciding symbolic refs are looping
+const MAX_SYMREF_DEPTH: usize = 5;
+
+// tries `name` in the same places git does, so `main` finds `refs/heads/main`
+pub(crate) fn resolve_ref(repo: &Repo, name: &str) -> Result<Option<String>> {
+    for refname in dwim_candidates(name) {
+        if let Some(sha) = read_ref(repo, &refname)? {
+            return Ok(Some(sha));
+        }
+    }
+    Ok(None)
+}
+
+// the full ref whose reflog `name` means, found the way resolve_ref finds refs, preferring one
+// that has a log; a ref that exists but was never logged just has an empty one
+pub(crate) fn reflog_ref(repo: &Repo, name: &str) -> Result<Option<String>> {
+    let candidates = dwim_candidates(name);
+    if let Some(refname) = candidates
+        .iter()
+        .find(|refname| repo.path(&format!("logs/{}", refname)).is_file())
+    {
+        return Ok(Some(refname.clone()));
+    }
+    for refname in candidates {
+        if read_ref(repo, &refname)?.is_some() {
+            return Ok(Some(refname));
+        }
+    }
+    Ok(None)
+}
+
+// the ways a short name is spelled out in full, as a prefix and suffix, in the order they're tried
+const DWIM_RULES: [(&str, &str); 6] = [
+    ("", ""),
+    ("refs/", ""),
+    ("refs/tags/", ""),
+    ("refs/heads/", ""),
+    ("refs/remotes/", ""),
+    ("refs/remotes/", "/HEAD"),
+];
+
+fn dwim_candidates(name: &str) -> Vec<String> {
+    (0..DWIM_RULES.len())
+        .filter_map(|rule| dwim_expand(rule, name))
+        .collect()
+}
+
+fn dwim_expand(rule: usize, name: &str) -> Option<String> {
+    // only things like HEAD or ORIG_HEAD live directly in .git, not e.g. `config`
+    if rule == 0
+        && !name.starts_with("refs/")
+        && !name.chars().all(|c| c.is_ascii_uppercase() || c == '_')
+    {
+        return None;
+    }
+    let (prefix, suffix) = DWIM_RULES[rule];
+    Some(format!("{}{}{}", prefix, name, suffix))
+}
+
+// what `name` names as a ref, by its shortest unambiguous name and after following symbolic refs,
+// so HEAD on refs/heads/main is `main`, and a detached one just `HEAD`; None for anything that
+// isn't a ref, like a sha
+pub(crate) fn abbrev_ref(repo: &Repo, name: &str) -> Result<Option<String>> {
+    for refname in dwim_candidates(name) {
+        if read_ref(repo, &refname)?.is_some() {
+            let target = symref_target(repo, &refname)?;
+            return Ok(Some(shorten_ref(repo, &target)?));
+        }
+    }
+    Ok(None)
+}
+
+// like git, the most a ref's name can be cut down to while no other way of spelling the short name
+// out finds a ref, falling back to the full name
+fn shorten_ref(repo: &Repo, refname: &str) -> Result<String> {
+    for rule in (1..DWIM_RULES.len()).rev() {
+        let (prefix, suffix) = DWIM_RULES[rule];
+        let short = match refname
+            .strip_prefix(prefix)
+            .and_then(|rest| rest.strip_suffix(suffix))
+        {
+            Some(short) if !short.is_empty() => short,
+            _ => continue,
+        };
+        let mut ambiguous = false;
+        for other in (0..DWIM_RULES.len()).filter(|&other| other != rule) {
+            if let Some(candidate) = dwim_expand(other, short) {
+                ambiguous |= read_ref(repo, &candidate)?.is_some();
+            }
+        }
+        if !ambiguous {
+            return Ok(short.to_string());
+        }
+    }
+    Ok(refname.to_string())
+}
+
+// the sha a ref points at, following symbolic refs like `ref: refs/heads/master` in HEAD; a loose
+// ref file wins, falling back to packed-refs once a repo has been packed
+pub(crate) fn read_ref(repo: &Repo, refname: &str) -> Result<Option<String>> {
+    let refname = symref_target(repo, refname)?;
+    let ref_path = repo.path(&refname);
+    if !ref_path.is_file() {
+        return Ok(packed_refs(repo)?.remove(&refname));
+    }
+    let contents =
+        std::fs::read_to_string(&ref_path).with_context(|| format!("reading ref {}", refname))?;
+    let contents = contents.trim_end();
+    ensure!(
+        is_plausibly_obj_sha(contents),
+        "bad ref {}: {}",
+        refname,
+        contents
+    );
+    Ok(Some(contents.to_ascii_lowercase()))
+}
+
+// every ref under refs/ with the sha it points at, sorted by name
+pub(crate) fn list_refs(repo: &Repo) -> Result<BTreeMap<String, String>> {
+    let mut refs = packed_refs(repo)?;
+
+    let mut dirs = vec![String::from("refs")];
+    while let Some(dir) = dirs.pop() {
+        let dirents = match repo.path(&dir).read_dir() {
+            Ok(dirents) => dirents,
+            Err(_) => continue,
+        };
+        for ent in dirents {
+            let ent = ent.context("listing refs")?;
+            let refname = format!("{}/{}", dir, ent.file_name().to_string_lossy());
+            if ent.file_type().context("listing refs")?.is_dir() {
+                dirs.push(refname);
+            } else if !refname.ends_with(".lock") {
+                // symbolic refs pointing at a branch that doesn't exist yet have nothing to show
+                if let Some(sha) = read_ref(repo, &refname)? {
+                    refs.insert(refname, sha);
+                }
+            }
+        }
+    }
+    Ok(refs)
+}
+
+// refs moved into packed-refs by `git pack-refs` or a clone, by name
+fn packed_refs(repo: &Repo) -> Result<BTreeMap<String, String>> {
+    let mut refs = BTreeMap::new();
+    let packed_path = repo.path("packed-refs");
+    if !packed_path.is_file() {
+        return Ok(refs);
+    }
+    let packed = std::fs::read_to_string(&packed_path).context("reading packed-refs")?;
+    // skip the `# pack-refs with: ...` header and the `^<sha>` lines peeling annotated tags
+    for line in packed.lines() {
+        if line.starts_with('#') || line.starts_with('^') || line.is_empty() {
+            continue;
+        }
+        let (sha, refname) = line
+            .split_once(' ')
+            .with_context(|| format!("bad packed-refs line: {}", line))?;
+        ensure!(is_plausibly_obj_sha(sha), "bad packed-refs line: {}", line);
+        refs.insert(refname.to_string(), sha.to_ascii_lowercase());
+    }
+    Ok(refs)
+}
+
+pub(crate) fn delete_ref(repo: &Repo, ref_name: &str) -> Result<()> {
+    let refname = writable_ref_name(repo, ref_name)?;
+    let ref_path = repo.path(&refname);
+    if ref_path.is_file() {
+        std::fs::remove_file(&ref_path).with_context(|| format!("deleting ref {}", refname))?;
+    }
+    // the ref's history goes with it
+    let log_path = repo.path(&format!("logs/{}", refname));
+    if log_path.is_file() {
+        std::fs::remove_file(&log_path)
+            .with_context(|| format!("deleting reflog for {}", refname))?;
+    }
+    // tidy up directories like refs/heads/feature/ left empty, but keep refs/heads itself;
+    // removing one that still has other refs in it fails harmlessly
+    let refs_dir = repo.path("refs");
+    for dir in ref_path.ancestors().skip(1) {
+        if !dir.starts_with(&refs_dir)
+            || dir.parent() == Some(refs_dir.as_path())
+            || std::fs::remove_dir(dir).is_err()
+        {
+            break;
+        }
+    }
+
+    // otherwise a packed copy would make the ref reappear
+    if packed_refs(repo)?.contains_key(&refname) {
+        let packed =
+            std::fs::read_to_string(repo.path("packed-refs")).context("reading packed-refs")?;
+        let mut kept = String::new();
+        let mut dropping = false;
+        for line in packed.lines() {
+            // a peeled line belongs to the ref just before it
+            if line.starts_with('^') && dropping {
+                continue;
+            }
+            dropping = line.split_once(' ').map(|(_, name)| name) == Some(refname.as_str());
+            if !dropping {
+                kept.push_str(line);
+                kept.push('\n');
+            }
+        }
+        replace_file(&repo.path("packed-refs"), kept.as_bytes())
+            .context("rewriting packed-refs")?;
+    }
+    Ok(())
+}
+
+// HEAD is only ever updated through the branch it points at
+pub(crate) fn writable_ref_name(repo: &Repo, ref_name: &str) -> Result<String> {
+    ensure!(
+        (ref_name == "HEAD" || ref_name.starts_with("refs/")) && is_valid_branch_name(ref_name),
+        "invalid ref name: '{}'",
+        ref_name
+    );
+    let refname = symref_target(repo, ref_name)?;
+    ensure!(
+        refname != "HEAD",
+        "refusing to update HEAD directly since it isn't a symbolic ref"
+    );
+    Ok(refname)
+}
+
+// points `ref_name` at another ref, the way HEAD names the current branch
+pub(crate) fn write_symref(repo: &Repo, ref_name: &str, target: &str) -> Result<()> {
+    ensure!(
+        (ref_name == "HEAD" || ref_name.starts_with("refs/")) && is_valid_branch_name(ref_name),
+        "invalid ref name: '{}'",
+        ref_name
+    );
+    ensure!(
+        target.starts_with("refs/"),
+        "Refusing to point {} outside of refs/",
+        ref_name
+    );
+    ensure!(
+        is_valid_branch_name(target),
+        "Refusing to set '{}' to invalid ref '{}'",
+        ref_name,
+        target
+    );
+    write_ref(repo, ref_name, &format!("ref: {}", target))
+}
+
+// the branch HEAD names when it has no commits yet, as in a freshly made repo, without its
+// `refs/heads/` prefix
+pub(crate) fn unborn_branch(repo: &Repo) -> Result<Option<String>> {
+    let branch = symref_target(repo, "HEAD")?;
+    if branch == "HEAD" || read_ref(repo, &branch)?.is_some() {
+        return Ok(None);
+    }
+    Ok(Some(
+        branch
+            .strip_prefix("refs/heads/")
+            .unwrap_or(&branch)
+            .to_string(),
+    ))
+}
+
+// the ref that `refname` finally points at after following any symbolic refs
+pub(crate) fn symref_target(repo: &Repo, refname: &str) -> Result<String> {
+    follow_symref(repo, refname, 0)
+}
+
+fn follow_symref(repo: &Repo, refname: &str, depth: usize) -> Result<String> {
+    ensure!(
+        depth < MAX_SYMREF_DEPTH,
+        "too many levels of symbolic refs at {}",
+        refname
+    );
+    let ref_path = repo.path(refname);
+    if !ref_path.is_file() {
+        return Ok(refname.to_string());
+    }
+    let contents =
+        std::fs::read_to_string(&ref_path).with_context(|| format!("reading ref {}", refname))?;
+    match contents.trim_end().strip_prefix("ref: ") {
+        Some(target) => follow_symref(repo, target, depth + 1),
+        None => Ok(refname.to_string()),
+    }
+}
+
+// `value` is either a sha or, for a symbolic ref, `ref: <target>`
+pub(crate) fn write_ref(repo: &Repo, refname: &str, value: &str) -> Result<()> {
+    let ref_path = repo.path(refname);
+    if let Some(parent) = ref_path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("creating directories for ref {}", refname))?;
+    }
+    replace_file(&ref_path, format!("{}\n", value).as_bytes())
+        .with_context(|| format!("updating ref {}", refname))
+}
+
+// notes `refname` moving from `old` to `new` in logs/<refname>, as `old new ident\tmessage`; `ident`
+// is who moved it and when, and a ref that's just been created has no `old`, which is logged as
+// all zeros
+pub(crate) fn append_reflog(
+    repo: &Repo,
+    refname: &str,
+    old: Option<&str>,
+    new: &str,
+    ident: &str,
+    message: &str,
+) -> Result<()> {
+    let log_path = repo.path(&format!("logs/{}", refname));
+    if !log_path.is_file() && !logs_updates(repo, refname)? {
+        return Ok(());
+    }
+    if let Some(parent) = log_path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("creating directories for reflog {}", refname))?;
+    }
+    let mut line = format!("{} {} {}", old.unwrap_or(NULL_SHA), new, ident);
+    // every entry is one line, so a message running over several gets joined up
+    let message = message.split_whitespace().collect::<Vec<_>>().join(" ");
+    if !message.is_empty() {
+        line.push('\t');
+        line.push_str(&message);
+    }
+    line.push('\n');
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log_path)
+        .and_then(|mut log| log.write_all(line.as_bytes()))
+        .with_context(|| format!("writing reflog for {}", refname))
+}
+
+// each move of `refname`, oldest first, as the sha it moved to and the message it was logged with
+pub(crate) fn read_reflog(repo: &Repo, refname: &str) -> Result<Vec<(String, String)>> {
+    let log_path = repo.path(&format!("logs/{}", refname));
+    if !log_path.is_file() {
+        return Ok(vec![]);
+    }
+    let log = std::fs::read_to_string(&log_path)
+        .with_context(|| format!("reading reflog for {}", refname))?;
+    let mut entries = vec![];
+    for line in log.lines() {
+        let (update, message) = line.split_once('\t').unwrap_or((line, ""));
+        match update.split(' ').nth(1) {
+            Some(new) if is_plausibly_obj_sha(new) => {
+                entries.push((new.to_ascii_lowercase(), message.to_string()))
+            }
+            _ => bail!("malformed reflog entry for {}: {}", refname, line),
+        }
+    }
+    Ok(entries)
+}
+
+// core.logAllRefUpdates is a boolean or `always`; left unset, it's on unless the repo is bare, and
+// even then only branches, remote-tracking refs, notes and HEAD get logs
+fn logs_updates(repo: &Repo, refname: &str) -> Result<bool> {
+    let logged_by_default = refname == "HEAD"
+        || ["refs/heads/", "refs/remotes/", "refs/notes/"]
+            .iter()
+            .any(|prefix| refname.starts_with(prefix));
+    match repo.config.get::<bool>("core", "logallrefupdates") {
+        Ok(Some(enabled)) => Ok(enabled && logged_by_default),
+        Ok(None) => {
+            Ok(logged_by_default && !repo.config.get::<bool>("core", "bare")?.unwrap_or(false))
+        }
+        Err(e) => match repo.config.get::<String>("core", "logallrefupdates")? {
+            Some(value) if value.eq_ignore_ascii_case("always") => Ok(true),
+            _ => Err(e),
+        },
+    }
+}
+
+// like the index, write `<file>.lock` and move it into place so readers never see half a file
+fn replace_file(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut lock_name = path.to_path_buf().into_os_string();
+    lock_name.push(".lock");
+    let lock_path = PathBuf::from(lock_name);
+    let mut lockfile = OpenOptions::new()
+        .create_new(true)
+        .write(true)
+        .open(&lock_path)
+        .with_context(|| format!("Unable to create {}", lock_path.to_string_lossy()))?;
+    let written = lockfile
+        .write_all(contents)
+        .and_then(|_| lockfile.sync_all())
+        .with_context(|| format!("writing {}", lock_path.to_string_lossy()));
+    if written.is_err() {
+        let _ = std::fs::remove_file(&lock_path);
+        return written;
+    }
+    std::fs::rename(&lock_path, path)
+        .with_context(|| format!("moving {} into place", lock_path.to_string_lossy()))
+}
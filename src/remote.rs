@@ -0,0 +1,234 @@
+use std::io::{Read, Write};
+
+use anyhow::{bail, ensure, Context, Result};
+use reqwest::blocking::Client;
+
+// servers tailor their responses to git clients, so look like one
+const USER_AGENT: &str = "git/git-starter-rust";
+
+// what a server's `info/refs` says it has, in the order it listed them
+pub(crate) struct Advertisement {
+    pub(crate) refs: Vec<(String, String)>,
+    pub(crate) capabilities: Vec<String>,
+}
+
+impl Advertisement {
+    // the branch the server's HEAD points at, if it says
+    pub(crate) fn head_branch(&self) -> Option<&str> {
+        self.capabilities
+            .iter()
+            .find_map(|cap| cap.strip_prefix("symref=HEAD:"))
+    }
+
+    fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|cap| cap == capability)
+    }
+}
+
+pub(crate) struct Remote {
+    client: Client,
+    url: String,
+}
+
+impl Remote {
+    pub(crate) fn new(url: &str) -> Result<Self> {
+        let client = Client::builder()
+            .user_agent(USER_AGENT)
+            .build()
+            .context("setting up http client")?;
+        Ok(Remote {
+            client,
+            url: url.trim_end_matches('/').to_string(),
+        })
+    }
+
+    // the first step of the smart HTTP protocol, asking which refs there are to fetch
+    pub(crate) fn discover_refs(&self) -> Result<Advertisement> {
+        let url = format!("{}/info/refs?service=git-upload-pack", self.url);
+        let resp = self
+            .client
+            .get(&url)
+            .send()
+            .and_then(|resp| resp.error_for_status())
+            .with_context(|| format!("unable to access '{}'", self.url))?;
+        let content_type = resp
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|ct| ct.to_str().ok())
+            .unwrap_or_default();
+        // dumb HTTP servers just serve the file, which isn't enough to fetch from
+        ensure!(
+            content_type == "application/x-git-upload-pack-advertisement",
+            "{}/info/refs not valid: is this a git repository?",
+            self.url
+        );
+        let mut resp = resp;
+
+        let service = read_pkt_line(&mut resp)?.context("missing service announcement")?;
+        ensure!(
+            service == b"# service=git-upload-pack\n",
+            "unexpected service announcement: {}",
+            String::from_utf8_lossy(&service)
+        );
+        ensure!(
+            read_pkt_line(&mut resp)?.is_none(),
+            "expected flush after service announcement"
+        );
+
+        let mut refs = vec![];
+        let mut capabilities = vec![];
+        while let Some(line) = read_pkt_line(&mut resp)? {
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches('\n');
+            // the first ref carries the server's capabilities after a NUL
+            let line = match line.split_once('\0') {
+                Some((line, caps)) => {
+                    capabilities = caps.split(' ').map(String::from).collect();
+                    line
+                }
+                None => line,
+            };
+            let (sha, name) = line
+                .split_once(' ')
+                .with_context(|| format!("bad ref advertisement: {}", line))?;
+            ensure!(
+                crate::is_plausibly_obj_sha(sha),
+                "bad ref advertisement: {}",
+                line
+            );
+            // an empty repository advertises just its capabilities
+            if name != "capabilities^{}" {
+                refs.push((name.to_string(), sha.to_string()));
+            }
+        }
+        Ok(Advertisement { refs, capabilities })
+    }
+
+    // asks for everything reachable from `wants`, or with a `depth` just that many commits of
+    // history, writing the packfile the server sends to `out`; returns the commits whose parents
+    // were left out
+    pub(crate) fn fetch_pack<W: Write>(
+        &self,
+        adv: &Advertisement,
+        wants: &[String],
+        depth: Option<u32>,
+        mut out: W,
+    ) -> Result<Vec<String>> {
+        let side_band = adv.supports("side-band-64k");
+        let mut caps = vec![];
+        if depth.is_some() {
+            ensure!(
+                adv.supports("shallow"),
+                "Server does not support shallow clients"
+            );
+            caps.push("shallow");
+        }
+        if side_band {
+            caps.push("side-band-64k");
+        }
+        if adv.supports("ofs-delta") {
+            caps.push("ofs-delta");
+        }
+        let agent = format!("agent={}", USER_AGENT);
+        caps.push(&agent);
+
+        let mut req = vec![];
+        for (i, sha) in wants.iter().enumerate() {
+            if i == 0 {
+                write_pkt_line(&mut req, format!("want {} {}\n", sha, caps.join(" ")))?;
+            } else {
+                write_pkt_line(&mut req, format!("want {}\n", sha))?;
+            }
+        }
+        if let Some(depth) = depth {
+            write_pkt_line(&mut req, format!("deepen {}\n", depth))?;
+        }
+        req.extend_from_slice(b"0000");
+        write_pkt_line(&mut req, "done\n")?;
+
+        let url = format!("{}/git-upload-pack", self.url);
+        let mut resp = self
+            .client
+            .post(&url)
+            .header(
+                reqwest::header::CONTENT_TYPE,
+                "application/x-git-upload-pack-request",
+            )
+            .header(
+                reqwest::header::ACCEPT,
+                "application/x-git-upload-pack-result",
+            )
+            .body(req)
+            .send()
+            .and_then(|resp| resp.error_for_status())
+            .with_context(|| format!("unable to fetch from '{}'", self.url))?;
+
+        // a shallow fetch first hears where history was cut off
+        let mut shallow = vec![];
+        if depth.is_some() {
+            while let Some(line) = read_pkt_line(&mut resp)? {
+                let line = String::from_utf8_lossy(&line);
+                match line.trim_end().split_once(' ') {
+                    Some(("shallow", sha)) if crate::is_plausibly_obj_sha(sha) => {
+                        shallow.push(sha.to_string())
+                    }
+                    // only a repo that was shallow already could be unshallowed
+                    Some(("unshallow", _)) => {}
+                    _ => bail!("unexpected shallow update: {}", line.trim_end()),
+                }
+            }
+        }
+
+        // having nothing to start from, there's no negotiation and the server just says NAK
+        let ack = read_pkt_line(&mut resp)?.context("missing NAK before packfile")?;
+        ensure!(
+            ack == b"NAK\n",
+            "unexpected reply before packfile: {}",
+            String::from_utf8_lossy(&ack)
+        );
+
+        if !side_band {
+            std::io::copy(&mut resp, &mut out).context("receiving packfile")?;
+            return Ok(shallow);
+        }
+        // band 1 is the pack itself, 2 progress messages and 3 a fatal error from the server
+        while let Some(pkt) = read_pkt_line(&mut resp)? {
+            match pkt.split_first() {
+                Some((1, data)) => out.write_all(data).context("writing packfile")?,
+                Some((2, _)) => {}
+                Some((3, msg)) => bail!("remote error: {}", String::from_utf8_lossy(msg).trim()),
+                _ => bail!("bad side-band packet from server"),
+            }
+        }
+        Ok(shallow)
+    }
+}
+
+// pkt-lines are prefixed with their length, including the prefix, as 4 hex digits; `None` is a
+// flush-pkt (`0000`)
+fn read_pkt_line<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    reader
+        .read_exact(&mut len)
+        .context("reading pkt-line length")?;
+    let len = std::str::from_utf8(&len)
+        .ok()
+        .and_then(|len| usize::from_str_radix(len, 16).ok())
+        .context("bad pkt-line length")?;
+    if len == 0 {
+        return Ok(None);
+    }
+    ensure!(len > 4, "bad pkt-line length {}", len);
+
+    let mut data = vec![0u8; len - 4];
+    reader.read_exact(&mut data).context("reading pkt-line")?;
+    Ok(Some(data))
+}
+
+fn write_pkt_line<D: AsRef<[u8]>>(out: &mut Vec<u8>, data: D) -> Result<()> {
+    let data = data.as_ref();
+    ensure!(data.len() <= 65516, "pkt-line too long");
+    write!(out, "{:04x}", data.len() + 4)?;
+    out.extend_from_slice(data);
+    Ok(())
+}
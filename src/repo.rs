@@ -0,0 +1,270 @@
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, ensure, Context, Result};
+use flate2::Compression;
+
+use crate::config::Config;
+use crate::pack::PackCache;
+
+#[derive(Clone)]
+pub(crate) struct Repo {
+    pub(crate) git_dir: PathBuf,
+    // where everything but HEAD and the index lives, which is the git dir itself except in a
+    // linked worktree
+    common_dir: PathBuf,
+    objects_dir: PathBuf,
+    // other object dirs to look for objects in that aren't in this one, which are never written to
+    alternates: Vec<PathBuf>,
+    pub(crate) config: Config,
+    // replace objects that are already in the db rather than trusting them, the way to repair
+    // one that's got corrupted
+    pub(crate) rewrite_objects: bool,
+    pub(crate) packs: PackCache,
+}
+
+impl Repo {
+    pub(crate) fn new<P: Into<PathBuf>>(git_dir: P) -> Self {
+        let git_dir = git_dir.into();
+        Repo {
+            common_dir: git_dir.clone(),
+            objects_dir: git_dir.join("objects"),
+            alternates: vec![],
+            git_dir,
+            config: Config::default(),
+            rewrite_objects: false,
+            packs: PackCache::default(),
+        }
+    }
+
+    // like `new`, but for a repo that already exists, so its config is there to read along with
+    // the user's global one
+    pub(crate) fn open<P: Into<PathBuf>>(git_dir: P) -> Result<Self> {
+        let mut repo = Repo::new(git_dir);
+        let commondir = repo.git_dir.join("commondir");
+        if commondir.is_file() {
+            let contents = std::fs::read_to_string(&commondir).context("reading commondir")?;
+            repo.common_dir = repo
+                .git_dir
+                .join(contents.trim_end_matches(&['\n', '\r'][..]));
+        }
+        // GIT_OBJECT_DIRECTORY is relative to where we were run, which is still the cwd here
+        repo.objects_dir = match std::env::var_os("GIT_OBJECT_DIRECTORY") {
+            Some(dir) => std::env::current_dir().context("reading cwd")?.join(dir),
+            None => repo.path("objects"),
+        };
+        let mut seen = vec![normalize(&repo.objects_dir)];
+        read_alternates(&repo.objects_dir, 0, &mut seen);
+        repo.alternates = seen.split_off(1);
+        repo.config = Config::global()?;
+        repo.config.extend(Config::read(repo.path("config"))?);
+        Ok(repo)
+    }
+
+    // an explicit git dir (from --git-dir or GIT_DIR) is used as is with `cur_dir` as the top of
+    // the worktree, otherwise look for a .git in `cur_dir` or above like git does; returns the
+    // repo along with the top of its worktree
+    pub(crate) fn find(git_dir: Option<&Path>, cur_dir: &Path) -> Result<(Repo, PathBuf)> {
+        if let Some(git_dir) = git_dir {
+            let repo = Repo::open(read_gitfile(&cur_dir.join(git_dir))?)?;
+            ensure!(
+                repo.path("HEAD").is_file() && repo.objects_dir().is_dir(),
+                "not a git repository: '{}'",
+                git_dir.to_string_lossy()
+            );
+            return Ok((repo, cur_dir.to_path_buf()));
+        }
+
+        match cur_dir.ancestors().find(|d| d.join(".git").exists()) {
+            Some(top) => Ok((
+                Repo::open(read_gitfile(&top.join(".git"))?)?,
+                top.to_path_buf(),
+            )),
+            None => bail!("not a git repository (or any of the parent directories): .git"),
+        }
+    }
+
+    pub(crate) fn path<P: AsRef<Path>>(&self, rel_path: P) -> PathBuf {
+        let rel_path = rel_path.as_ref();
+        if is_per_worktree(rel_path) {
+            self.git_dir.join(rel_path)
+        } else {
+            self.common_dir.join(rel_path)
+        }
+    }
+
+    // where new objects go
+    pub(crate) fn objects_dir(&self) -> PathBuf {
+        self.objects_dir.clone()
+    }
+
+    // every dir an object might be found in, the repo's own first
+    pub(crate) fn object_dirs(&self) -> impl Iterator<Item = &Path> {
+        std::iter::once(self.objects_dir.as_path())
+            .chain(self.alternates.iter().map(PathBuf::as_path))
+    }
+
+    pub(crate) fn alternates(&self) -> &[PathBuf] {
+        &self.alternates
+    }
+
+    // whether the loose object `obj_sha` needn't be written, since one of the object dirs has it
+    pub(crate) fn has_object_file(&self, obj_sha: &str) -> bool {
+        let (obj_dirname, obj_filename) = obj_sha.split_at(2);
+        !self.rewrite_objects
+            && self
+                .object_dirs()
+                .any(|dir| dir.join(obj_dirname).join(obj_filename).exists())
+    }
+
+    // commits in a shallow clone whose parents were never fetched, where history has to stop
+    pub(crate) fn shallow_commits(&self) -> Result<HashSet<String>> {
+        let path = self.path("shallow");
+        if !path.is_file() {
+            return Ok(HashSet::new());
+        }
+        let contents = std::fs::read_to_string(&path).context("reading shallow file")?;
+        Ok(contents.lines().map(String::from).collect())
+    }
+
+    // zlib level for loose objects, where core.loosecompression overrides core.compression and
+    // -1 means zlib's own default
+    pub(crate) fn loose_compression(&self) -> Result<Compression> {
+        let level = match self.config.get::<i64>("core", "loosecompression")? {
+            Some(level) => Some(level),
+            None => self.config.get::<i64>("core", "compression")?,
+        };
+        match level {
+            None | Some(-1) => Ok(Compression::default()),
+            Some(n @ 0..=9) => Ok(Compression::new(n as u32)),
+            Some(n) => bail!("bad zlib compression level {}", n),
+        }
+    }
+}
+
+// git's limit on alternates listing alternates of their own
+const MAX_ALTERNATES_DEPTH: usize = 5;
+
+// an object dir's `info/alternates` lists other object dirs, one per line either absolute or
+// relative to it, and each of those can have alternates too; `seen` gets every one that exists in
+// the order git would look in them, like git complaining about the rest without giving up
+fn read_alternates(objects_dir: &Path, depth: usize, seen: &mut Vec<PathBuf>) {
+    let contents = match std::fs::read_to_string(objects_dir.join("info").join("alternates")) {
+        Ok(contents) => contents,
+        Err(_) => return,
+    };
+    if depth > MAX_ALTERNATES_DEPTH {
+        eprintln!(
+            "error: {}: ignoring alternate object stores, nesting too deep",
+            objects_dir.to_string_lossy()
+        );
+        return;
+    }
+    for line in contents.lines() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let dir = normalize(&objects_dir.join(line));
+        if !dir.is_dir() {
+            eprintln!(
+                "error: object directory {} does not exist; check .git/objects/info/alternates",
+                dir.to_string_lossy()
+            );
+            continue;
+        }
+        if !seen.contains(&dir) {
+            seen.push(dir.clone());
+            read_alternates(&dir, depth + 1, seen);
+        }
+    }
+}
+
+// `path` with any `.` and `..` taken out, without looking at what's on disk
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+// a submodule or linked worktree has a `.git` file rather than a directory, which says where its
+// git dir is with a `gitdir: ` line, relative to the file itself or absolute
+fn read_gitfile(path: &Path) -> Result<PathBuf> {
+    if !path.is_file() {
+        return Ok(path.to_path_buf());
+    }
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("reading {}", path.to_string_lossy()))?;
+    let target = match contents.strip_prefix("gitdir: ") {
+        Some(target) => target.trim_end_matches(&['\n', '\r'][..]),
+        None => bail!("invalid gitfile format: {}", path.to_string_lossy()),
+    };
+    let git_dir = path.parent().unwrap_or(path).join(target);
+    ensure!(
+        git_dir.is_dir(),
+        "not a git repository: {}",
+        git_dir.to_string_lossy()
+    );
+    Ok(git_dir)
+}
+
+// what each linked worktree keeps for itself: its index, and HEAD and the other all-caps refs
+// like ORIG_HEAD along with HEAD's reflog
+fn is_per_worktree(rel_path: &Path) -> bool {
+    match rel_path.to_str() {
+        Some("index") | Some("logs/HEAD") => true,
+        Some(name) => name.bytes().all(|b| b.is_ascii_uppercase() || b == b'_'),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::scratch_dir;
+
+    #[test]
+    fn find_follows_gitfile() {
+        let dir = scratch_dir("gitfile");
+        let real = dir.join("real.git");
+        std::fs::create_dir_all(real.join("objects")).unwrap();
+        std::fs::write(real.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        let work = dir.join("work");
+        std::fs::create_dir_all(work.join("sub")).unwrap();
+
+        // relative to the .git file, then absolute
+        for target in [
+            "../real.git".to_string(),
+            real.to_string_lossy().into_owned(),
+        ] {
+            std::fs::write(work.join(".git"), format!("gitdir: {}\n", target)).unwrap();
+            let (repo, top) = Repo::find(None, &work.join("sub")).unwrap();
+            assert_eq!(top, work);
+            assert_eq!(
+                repo.git_dir.canonicalize().unwrap(),
+                real.canonicalize().unwrap()
+            );
+            assert_eq!(
+                repo.objects_dir().canonicalize().unwrap(),
+                real.join("objects").canonicalize().unwrap()
+            );
+        }
+
+        std::fs::write(work.join(".git"), "not a gitfile\n").unwrap();
+        let err = Repo::find(None, &work)
+            .err()
+            .expect("a bad gitfile to be refused");
+        assert!(
+            err.to_string().starts_with("invalid gitfile format"),
+            "{}",
+            err
+        );
+    }
+}
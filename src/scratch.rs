@@ -0,0 +1,30 @@
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+// an empty directory of the test's own under the system temp dir, removed again when dropped
+// unless the test is panicking, so what a failing test left behind is there to look into
+pub(crate) struct ScratchDir(PathBuf);
+
+pub(crate) fn scratch_dir(name: &str) -> ScratchDir {
+    let dir =
+        std::env::temp_dir().join(format!("git-starter-rust-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    ScratchDir(dir)
+}
+
+impl Deref for ScratchDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        if !std::thread::panicking() {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+}